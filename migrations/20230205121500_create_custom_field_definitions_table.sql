@@ -0,0 +1,10 @@
+-- Create Custom Field Definitions table
+CREATE TABLE custom_field_definitions(
+    name TEXT NOT NULL,
+    PRIMARY KEY (name),
+    field_type TEXT NOT NULL
+        CHECK (field_type IN ('text', 'number', 'date', 'boolean', 'enum')),
+    required BOOLEAN NOT NULL DEFAULT FALSE,
+    options TEXT[] NOT NULL DEFAULT '{}',
+    created_at timestamptz NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Store validated custom field values alongside each subscriber
+ALTER TABLE subscriptions ADD COLUMN custom_fields JSONB NOT NULL DEFAULT '{}';
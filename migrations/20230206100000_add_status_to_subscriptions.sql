@@ -0,0 +1,5 @@
+-- Track whether a subscriber has confirmed their email address.
+-- Subscribers that predate the confirmation flow are treated as confirmed.
+ALTER TABLE subscriptions ADD COLUMN status TEXT NULL;
+UPDATE subscriptions SET status = 'confirmed' WHERE status IS NULL;
+ALTER TABLE subscriptions ALTER COLUMN status SET NOT NULL;
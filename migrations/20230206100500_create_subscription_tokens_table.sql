@@ -0,0 +1,6 @@
+-- Create Subscription Tokens table
+CREATE TABLE subscription_tokens(
+    subscription_token TEXT NOT NULL,
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    PRIMARY KEY (subscription_token)
+);
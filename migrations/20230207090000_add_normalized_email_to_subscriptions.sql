@@ -0,0 +1,33 @@
+-- Uniqueness moves from the address as typed to its normalized form.
+-- Existing rows are backfilled with the canonical form the application
+-- computes: NFC, lowercase, without a trailing dot on the domain. IDNA
+-- encoding and provider alias folding need the application's rules, so
+-- `zero2prod migrate` applies them once the migrations have run.
+ALTER TABLE subscriptions ADD COLUMN normalized_email TEXT NULL;
+UPDATE subscriptions
+SET normalized_email =
+    lower(normalize(substring(email FROM '^(.*)@[^@]*$'), NFC))
+    || '@'
+    || rtrim(lower(normalize(substring(email FROM '@([^@]*)$'), NFC)), '.')
+WHERE normalized_email IS NULL;
+
+-- Addresses that only differed in case now collide. Keep one row per
+-- address, preferring a confirmed one and then the oldest, and move the
+-- confirmation tokens of the others over to it.
+CREATE TEMPORARY TABLE duplicate_subscriptions AS
+SELECT id, first_value(id) OVER (
+    PARTITION BY normalized_email
+    ORDER BY status = 'confirmed' DESC, subscribed_at, id
+) AS kept_id
+FROM subscriptions;
+DELETE FROM duplicate_subscriptions WHERE id = kept_id;
+UPDATE subscription_tokens t
+SET subscriber_id = d.kept_id
+FROM duplicate_subscriptions d
+WHERE t.subscriber_id = d.id;
+DELETE FROM subscriptions s USING duplicate_subscriptions d WHERE s.id = d.id;
+DROP TABLE duplicate_subscriptions;
+
+ALTER TABLE subscriptions ALTER COLUMN normalized_email SET NOT NULL;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_normalized_email_key UNIQUE (normalized_email);
+ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_email_key;
@@ -0,0 +1,7 @@
+-- Token buckets shared by every application instance
+CREATE TABLE rate_limit_buckets(
+    key TEXT NOT NULL,
+    PRIMARY KEY (key),
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at timestamptz NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Let confirmation tokens expire and remind subscribers who never confirmed.
+ALTER TABLE subscription_tokens ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
+ALTER TABLE subscriptions ADD COLUMN reminder_sent_at timestamptz NULL;
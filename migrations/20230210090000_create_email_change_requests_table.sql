@@ -0,0 +1,9 @@
+-- Address changes waiting for the new address to be verified
+CREATE TABLE email_change_requests(
+    token TEXT NOT NULL,
+    PRIMARY KEY (token),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    new_email TEXT NOT NULL,
+    new_normalized_email TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
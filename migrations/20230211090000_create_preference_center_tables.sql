@@ -0,0 +1,36 @@
+-- Lists subscribers can join or leave from the preference center
+CREATE TABLE mailing_lists(
+    name TEXT NOT NULL,
+    PRIMARY KEY (name),
+    description TEXT NOT NULL,
+    -- New subscribers are put on these lists
+    subscribe_by_default BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+INSERT INTO mailing_lists (name, description)
+VALUES ('newsletter', 'Our regular newsletter.');
+
+CREATE TABLE list_memberships(
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    list_name TEXT NOT NULL REFERENCES mailing_lists (name) ON DELETE CASCADE,
+    PRIMARY KEY (subscriber_id, list_name)
+);
+-- Existing subscribers are on the only list there was
+INSERT INTO list_memberships (subscriber_id, list_name)
+SELECT id, 'newsletter' FROM subscriptions;
+
+ALTER TABLE subscriptions
+    ADD COLUMN frequency TEXT NOT NULL DEFAULT 'immediate'
+    CHECK (frequency IN ('immediate', 'weekly', 'monthly'));
+
+-- Consent and audit history: what happened to each subscription, and when
+CREATE TABLE subscription_events(
+    id BIGSERIAL,
+    PRIMARY KEY (id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    occurred_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX subscription_events_subscriber_id_idx
+    ON subscription_events (subscriber_id, occurred_at);
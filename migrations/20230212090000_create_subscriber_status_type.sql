@@ -0,0 +1,11 @@
+-- Subscriber statuses, mirrored by `SubscriberStatus` in the domain layer
+CREATE TYPE subscriber_status AS ENUM (
+    'pending_confirmation',
+    'confirmed',
+    'unsubscribed',
+    'bounced',
+    'complained',
+    'erased'
+);
+ALTER TABLE subscriptions
+    ALTER COLUMN status TYPE subscriber_status USING status::subscriber_status;
@@ -0,0 +1,13 @@
+-- Custom fields belong to a mailing list; existing ones were defined for the
+-- only list there was
+ALTER TABLE custom_field_definitions
+    ADD COLUMN list_name TEXT NOT NULL DEFAULT 'newsletter'
+    REFERENCES mailing_lists (name) ON DELETE CASCADE;
+ALTER TABLE custom_field_definitions ALTER COLUMN list_name DROP DEFAULT;
+ALTER TABLE custom_field_definitions DROP CONSTRAINT custom_field_definitions_pkey;
+ALTER TABLE custom_field_definitions ADD PRIMARY KEY (list_name, name);
+
+-- Values are now stored per list: {"<list>": {"<field>": <value>}}
+UPDATE subscriptions
+SET custom_fields = jsonb_build_object('newsletter', custom_fields)
+WHERE custom_fields <> '{}';
@@ -0,0 +1,2 @@
+-- Lets the background worker find idle buckets without a full scan
+CREATE INDEX rate_limit_buckets_updated_at_idx ON rate_limit_buckets (updated_at);
@@ -0,0 +1,8 @@
+-- Signup form tokens that have been submitted, kept until they expire so
+-- that they cannot be submitted again
+CREATE TABLE used_form_tokens(
+    nonce TEXT NOT NULL,
+    PRIMARY KEY (nonce),
+    expires_at timestamptz NOT NULL
+);
+CREATE INDEX used_form_tokens_expires_at_idx ON used_form_tokens (expires_at);
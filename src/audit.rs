@@ -0,0 +1,51 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+/// Something that happened to a subscription, recorded in its consent and
+/// audit history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionEvent {
+    /// The subscriber asked to join, or to join again.
+    Subscribed,
+    /// The subscriber confirmed their address: they consented.
+    Confirmed,
+    EmailChanged,
+    PreferencesUpdated,
+    /// Any other lifecycle change, such as a bounce or an erasure.
+    StatusChanged,
+}
+
+impl SubscriptionEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Subscribed => "subscribed",
+            Self::Confirmed => "confirmed",
+            Self::EmailChanged => "email_changed",
+            Self::PreferencesUpdated => "preferences_updated",
+            Self::StatusChanged => "status_changed",
+        }
+    }
+}
+
+/// Appends an event to a subscriber's history, as part of the transaction
+/// that made the change.
+#[tracing::instrument(name = "Record a subscription event", skip(transaction, details))]
+pub async fn record_event(
+    transaction: &mut PgConnection,
+    subscriber_id: Uuid,
+    event: SubscriptionEvent,
+    details: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_events (subscriber_id, kind, details)
+        VALUES ($1, $2, $3)
+        "#,
+        subscriber_id,
+        event.as_str(),
+        details,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
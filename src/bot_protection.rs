@@ -0,0 +1,466 @@
+use crate::database::ConnectionPool;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Server-side defences against scripted signups.
+///
+/// Before submitting the signup form, clients fetch a [`Challenge`]. It holds
+/// a signed form token recording when the form was rendered and, optionally,
+/// a proof-of-work puzzle. A submission is rejected if:
+///
+/// - the honeypot field, hidden from humans, has a value;
+/// - the form token is missing, forged, too recent (nobody types that fast)
+///   or too old;
+/// - the proof of work is missing or wrong;
+/// - the form token, and so its proof of work, has already been used.
+pub struct BotProtection {
+    enabled: bool,
+    /// Where used form tokens are remembered; `None` if disabled.
+    used_tokens: Option<Arc<dyn UsedFormTokenStore>>,
+    secret: Secret<String>,
+    honeypot_field: String,
+    min_form_age: Duration,
+    max_form_age: Duration,
+    proof_of_work_difficulty: u8,
+}
+
+/// What a client needs to submit the signup form.
+#[derive(Debug, Serialize)]
+pub struct Challenge {
+    /// Must be sent back, unchanged, as the `form_token` field.
+    pub form_token: String,
+    /// A field that must be present in the form but left empty.
+    pub honeypot_field: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof_of_work: Option<ProofOfWorkChallenge>,
+}
+
+/// Find a `pow_nonce` such that the SHA-256 digest of
+/// `{form_token}:{pow_nonce}` starts with `difficulty` zero bits.
+#[derive(Debug, Serialize)]
+pub struct ProofOfWorkChallenge {
+    pub difficulty: u8,
+}
+
+/// The fields of a submission that bot protection looks at.
+pub struct BotCheck<'a> {
+    pub form_token: Option<&'a str>,
+    pub pow_nonce: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BotCheckError {
+    #[error("The form token is missing.")]
+    MissingFormToken,
+    #[error("The form token is invalid.")]
+    InvalidFormToken,
+    #[error("The form was submitted too quickly.")]
+    TooFast,
+    #[error("The form has expired.")]
+    Expired,
+    #[error("The proof of work is missing or invalid.")]
+    InvalidProofOfWork,
+    #[error("The form has already been submitted.")]
+    AlreadyUsed,
+}
+
+/// A form token that passed [`BotProtection::verify`]. It only counts once
+/// [`BotProtection::consume`] has recorded it as used.
+#[derive(Debug, PartialEq, Eq)]
+pub struct VerifiedFormToken {
+    /// The random part of the token.
+    nonce: String,
+    /// When the token expires anyway, as a Unix timestamp in seconds.
+    expires_at: i64,
+}
+
+/// Remembers the form tokens that have been used until they expire.
+#[async_trait::async_trait]
+pub trait UsedFormTokenStore: Send + Sync {
+    /// Records the token with `nonce` as used until `expires_at`, a Unix
+    /// timestamp. Returns `false` if it already was.
+    async fn record(&self, nonce: &str, expires_at: i64) -> Result<bool, anyhow::Error>;
+
+    /// Forgets the tokens that expired before `now`, returning how many
+    /// there were.
+    async fn forget_expired(&self, now: i64) -> Result<u64, anyhow::Error>;
+}
+
+/// Keeps used form tokens in memory, for tests.
+#[derive(Default)]
+pub struct InMemoryUsedFormTokens {
+    tokens: Mutex<HashMap<String, i64>>,
+}
+
+#[async_trait::async_trait]
+impl UsedFormTokenStore for InMemoryUsedFormTokens {
+    async fn record(&self, nonce: &str, expires_at: i64) -> Result<bool, anyhow::Error> {
+        let mut tokens = self.tokens.lock().unwrap();
+        Ok(tokens.insert(nonce.to_string(), expires_at).is_none())
+    }
+
+    async fn forget_expired(&self, now: i64) -> Result<u64, anyhow::Error> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let before = tokens.len();
+        tokens.retain(|_, expires_at| *expires_at >= now);
+        Ok((before - tokens.len()) as u64)
+    }
+}
+
+/// Keeps used form tokens in Postgres, so that a token used with one
+/// instance is refused by the others.
+pub struct PostgresUsedFormTokens {
+    pool: ConnectionPool,
+}
+
+impl PostgresUsedFormTokens {
+    pub fn new(pool: ConnectionPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl UsedFormTokenStore for PostgresUsedFormTokens {
+    #[tracing::instrument(name = "Recording a used form token", skip(self, nonce))]
+    async fn record(&self, nonce: &str, expires_at: i64) -> Result<bool, anyhow::Error> {
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO used_form_tokens (nonce, expires_at)
+            VALUES ($1, to_timestamp($2))
+            ON CONFLICT (nonce) DO NOTHING
+            "#,
+            nonce,
+            expires_at as f64,
+        )
+        .execute(&self.pool.get())
+        .await?;
+        Ok(inserted.rows_affected() == 1)
+    }
+
+    #[tracing::instrument(name = "Forgetting expired form tokens", skip(self))]
+    async fn forget_expired(&self, now: i64) -> Result<u64, anyhow::Error> {
+        let deleted = sqlx::query!(
+            "DELETE FROM used_form_tokens WHERE expires_at < to_timestamp($1)",
+            now as f64,
+        )
+        .execute(&self.pool.get())
+        .await?;
+        Ok(deleted.rows_affected())
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+impl BotProtection {
+    pub fn new(
+        secret: Secret<String>,
+        honeypot_field: String,
+        min_form_age: Duration,
+        max_form_age: Duration,
+        proof_of_work_difficulty: u8,
+        used_tokens: Arc<dyn UsedFormTokenStore>,
+    ) -> Self {
+        Self {
+            enabled: true,
+            used_tokens: Some(used_tokens),
+            secret,
+            honeypot_field,
+            min_form_age,
+            max_form_age,
+            proof_of_work_difficulty,
+        }
+    }
+
+    /// Accepts every submission.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            used_tokens: None,
+            secret: Secret::new(String::new()),
+            honeypot_field: String::new(),
+            min_form_age: Duration::ZERO,
+            max_form_age: Duration::ZERO,
+            proof_of_work_difficulty: 0,
+        }
+    }
+
+    /// The name of the honeypot field, if bot protection is enabled.
+    pub fn honeypot_field(&self) -> Option<&str> {
+        if self.enabled {
+            Some(&self.honeypot_field)
+        } else {
+            None
+        }
+    }
+
+    pub fn issue_challenge(&self, now: i64) -> Challenge {
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = format!("{}.{}", now, hex::encode(nonce));
+        let signature = hex::encode(self.mac(&payload).finalize().into_bytes());
+        Challenge {
+            form_token: format!("{}.{}", payload, signature),
+            honeypot_field: self.honeypot_field.clone(),
+            proof_of_work: (self.proof_of_work_difficulty > 0).then_some(ProofOfWorkChallenge {
+                difficulty: self.proof_of_work_difficulty,
+            }),
+        }
+    }
+
+    /// Checks the form token and proof of work of a submission made at
+    /// `now`, a Unix timestamp in seconds. Returns the form token to
+    /// [`consume`](BotProtection::consume), or `None` if bot protection is
+    /// disabled.
+    pub fn verify(
+        &self,
+        check: BotCheck<'_>,
+        now: i64,
+    ) -> Result<Option<VerifiedFormToken>, BotCheckError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let form_token = check.form_token.ok_or(BotCheckError::MissingFormToken)?;
+        let (issued_at, nonce) = self.verify_form_token(form_token)?;
+
+        let age = now - issued_at;
+        if age < self.min_form_age.as_secs() as i64 {
+            return Err(BotCheckError::TooFast);
+        }
+        if age > self.max_form_age.as_secs() as i64 {
+            return Err(BotCheckError::Expired);
+        }
+
+        if self.proof_of_work_difficulty > 0 {
+            let nonce = check.pow_nonce.ok_or(BotCheckError::InvalidProofOfWork)?;
+            if !has_leading_zero_bits(form_token, nonce, self.proof_of_work_difficulty) {
+                return Err(BotCheckError::InvalidProofOfWork);
+            }
+        }
+        Ok(Some(VerifiedFormToken {
+            nonce: nonce.to_string(),
+            expires_at: issued_at + self.max_form_age.as_secs() as i64,
+        }))
+    }
+
+    /// Records a verified form token as used. Returns `false` if it already
+    /// was: the submission must then be rejected with
+    /// [`BotCheckError::AlreadyUsed`].
+    pub async fn consume(&self, form_token: &VerifiedFormToken) -> Result<bool, anyhow::Error> {
+        match &self.used_tokens {
+            Some(used_tokens) => {
+                used_tokens
+                    .record(&form_token.nonce, form_token.expires_at)
+                    .await
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Returns when the token was issued and its random part, if we signed
+    /// it.
+    fn verify_form_token<'a>(&self, form_token: &'a str) -> Result<(i64, &'a str), BotCheckError> {
+        let (payload, signature) = form_token
+            .rsplit_once('.')
+            .ok_or(BotCheckError::InvalidFormToken)?;
+        let signature = hex::decode(signature).map_err(|_| BotCheckError::InvalidFormToken)?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| BotCheckError::InvalidFormToken)?;
+        payload
+            .split_once('.')
+            .and_then(|(issued_at, nonce)| Some((issued_at.parse().ok()?, nonce)))
+            .ok_or(BotCheckError::InvalidFormToken)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length.");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn has_leading_zero_bits(form_token: &str, nonce: &str, bits: u8) -> bool {
+    let digest = Sha256::digest(format!("{}:{}", form_token, nonce).as_bytes());
+    let mut remaining = u32::from(bits);
+    for byte in digest {
+        if remaining == 0 {
+            break;
+        }
+        let needed = remaining.min(8);
+        if byte.leading_zeros() < needed {
+            return false;
+        }
+        remaining -= needed;
+    }
+    remaining == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bot_protection::{
+        has_leading_zero_bits, BotCheck, BotCheckError, BotProtection, InMemoryUsedFormTokens,
+        UsedFormTokenStore,
+    };
+    use claims::{assert_err, assert_ok, assert_some};
+    use secrecy::Secret;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const NOW: i64 = 1_675_000_000;
+
+    fn protection(difficulty: u8) -> BotProtection {
+        BotProtection::new(
+            Secret::new("a-secret".into()),
+            "website".into(),
+            Duration::from_secs(3),
+            Duration::from_secs(3600),
+            difficulty,
+            Arc::new(InMemoryUsedFormTokens::default()),
+        )
+    }
+
+    fn check(form_token: &str) -> BotCheck<'_> {
+        BotCheck {
+            form_token: Some(form_token),
+            pow_nonce: None,
+        }
+    }
+
+    #[test]
+    fn a_token_is_accepted_within_its_window() {
+        let protection = protection(0);
+        let challenge = protection.issue_challenge(NOW);
+        let token = challenge.form_token.as_str();
+
+        assert_eq!(
+            protection.verify(check(token), NOW + 1),
+            Err(BotCheckError::TooFast)
+        );
+        assert_ok!(protection.verify(check(token), NOW + 3));
+        assert_ok!(protection.verify(check(token), NOW + 3600));
+        assert_eq!(
+            protection.verify(check(token), NOW + 3601),
+            Err(BotCheckError::Expired)
+        );
+    }
+
+    #[test]
+    fn forged_or_missing_tokens_are_rejected() {
+        let protection = protection(0);
+        let token = protection.issue_challenge(NOW).form_token;
+        // Pretend the form was rendered earlier than it was.
+        let (_, rest) = token.split_once('.').unwrap();
+        let backdated = format!("{}.{}", NOW - 60, rest);
+        let other_key = BotProtection::new(
+            Secret::new("another-secret".into()),
+            "website".into(),
+            Duration::ZERO,
+            Duration::from_secs(3600),
+            0,
+            Arc::new(InMemoryUsedFormTokens::default()),
+        )
+        .issue_challenge(NOW)
+        .form_token;
+
+        for token in [backdated.as_str(), other_key.as_str(), "garbage"] {
+            assert_eq!(
+                protection.verify(check(token), NOW + 10),
+                Err(BotCheckError::InvalidFormToken)
+            );
+        }
+        assert_eq!(
+            protection.verify(
+                BotCheck {
+                    form_token: None,
+                    pow_nonce: None
+                },
+                NOW + 10
+            ),
+            Err(BotCheckError::MissingFormToken)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_form_token_can_only_be_used_once() {
+        let protection = protection(0);
+        let token = protection.issue_challenge(NOW).form_token;
+
+        let first = assert_some!(assert_ok!(protection.verify(check(&token), NOW + 10)));
+        assert!(protection.consume(&first).await.unwrap());
+        let second = assert_some!(assert_ok!(protection.verify(check(&token), NOW + 20)));
+        assert!(!protection.consume(&second).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn used_tokens_are_forgotten_once_expired() {
+        let used_tokens = InMemoryUsedFormTokens::default();
+        assert!(used_tokens.record("a", NOW).await.unwrap());
+        assert!(used_tokens.record("b", NOW + 60).await.unwrap());
+
+        assert_eq!(used_tokens.forget_expired(NOW + 1).await.unwrap(), 1);
+        assert!(!used_tokens.record("b", NOW + 60).await.unwrap());
+    }
+
+    #[test]
+    fn a_solved_proof_of_work_is_accepted() {
+        let protection = protection(8);
+        let challenge = protection.issue_challenge(NOW);
+        let token = challenge.form_token.as_str();
+        assert_eq!(challenge.proof_of_work.unwrap().difficulty, 8);
+
+        let solution = (0u64..)
+            .map(|n| n.to_string())
+            .find(|nonce| has_leading_zero_bits(token, nonce, 8))
+            .unwrap();
+        let wrong = (0u64..)
+            .map(|n| n.to_string())
+            .find(|nonce| !has_leading_zero_bits(token, nonce, 8))
+            .unwrap();
+
+        assert_ok!(protection.verify(
+            BotCheck {
+                form_token: Some(token),
+                pow_nonce: Some(&solution)
+            },
+            NOW + 10
+        ));
+        assert_err!(protection.verify(
+            BotCheck {
+                form_token: Some(token),
+                pow_nonce: Some(&wrong)
+            },
+            NOW + 10
+        ));
+        assert_err!(protection.verify(check(token), NOW + 10));
+    }
+
+    #[test]
+    fn leading_zero_bits_are_counted_across_bytes() {
+        let nonce = (0u64..)
+            .map(|n| n.to_string())
+            .find(|nonce| has_leading_zero_bits("abc", nonce, 12))
+            .unwrap();
+        assert!(has_leading_zero_bits("abc", &nonce, 9));
+        assert!(has_leading_zero_bits("abc", &nonce, 0));
+    }
+
+    #[test]
+    fn a_disabled_protection_accepts_anything() {
+        let protection = BotProtection::disabled();
+        assert_eq!(protection.honeypot_field(), None);
+        assert_ok!(protection.verify(
+            BotCheck {
+                form_token: None,
+                pow_nonce: None
+            },
+            NOW
+        ));
+    }
+}
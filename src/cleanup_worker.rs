@@ -0,0 +1,347 @@
+use crate::bot_protection::{PostgresUsedFormTokens, UsedFormTokenStore};
+use crate::configuration::Settings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::metrics;
+use crate::routes::{
+    generate_subscription_token, html_preferences_footer, plain_preferences_footer,
+};
+use crate::shutdown::Shutdown;
+use crate::signed_links::LinkSigner;
+use crate::startup::AppState;
+use crate::subscriber_repository::SubscriberRepository;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// How long subscribers get to confirm their address.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingSubscriptionPolicy {
+    /// Confirmation links stop working after this long.
+    pub token_ttl: Duration,
+    /// Subscribers that have not confirmed after this long get one reminder,
+    /// with a fresh link.
+    pub remind_after: Duration,
+    /// Subscribers that have not confirmed after this long are deleted.
+    pub purge_after: Duration,
+}
+
+/// What a single cleanup pass did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    pub reminders_sent: u64,
+    pub reminders_failed: u64,
+    pub subscribers_purged: u64,
+    /// Confirmation tokens only.
+    pub tokens_expired: u64,
+    pub email_change_requests_expired: u64,
+}
+
+/// The background job that reminds and purges unconfirmed subscribers,
+/// sharing the services of the application it runs in.
+pub struct CleanupWorker {
+    state: AppState,
+    interval: Duration,
+}
+
+impl CleanupWorker {
+    pub fn new(configuration: &Settings, state: AppState) -> Self {
+        Self {
+            state,
+            interval: configuration.pending_subscriptions.cleanup_interval(),
+        }
+    }
+
+    /// Runs a cleanup pass every interval until `shutdown` is requested. A
+    /// pass that is under way when it is requested runs to completion.
+    ///
+    /// Each pass also evicts the rate limit buckets that have filled up
+    /// again and forgets the used signup form tokens that have expired.
+    pub async fn run_until_stopped(self, mut shutdown: Shutdown) {
+        let Self { state, interval } = self;
+        let used_form_tokens = PostgresUsedFormTokens::new(state.pool.clone());
+        while !shutdown.is_requested() {
+            let now = Utc::now();
+            let services = state.services.get();
+            match cleanup_once(
+                state.subscribers.as_ref(),
+                &services.email_client,
+                &state.base_url.0,
+                &state.link_signer,
+                &state.pending_policy,
+                now,
+            )
+            .await
+            {
+                Ok(report) => tracing::info!(
+                    reminders_sent = report.reminders_sent,
+                    reminders_failed = report.reminders_failed,
+                    subscribers_purged = report.subscribers_purged,
+                    tokens_expired = report.tokens_expired,
+                    email_change_requests_expired = report.email_change_requests_expired,
+                    "Cleaned up unconfirmed subscriptions"
+                ),
+                Err(e) => tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to clean up unconfirmed subscriptions"
+                ),
+            }
+            match services.rate_limiter.evict_full_buckets().await {
+                Ok(evicted) => tracing::info!(
+                    rate_limit_buckets_evicted = evicted,
+                    "Evicted idle rate limit buckets"
+                ),
+                Err(e) => tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to evict idle rate limit buckets"
+                ),
+            }
+            match used_form_tokens.forget_expired(now.timestamp()).await {
+                Ok(forgotten) => tracing::info!(
+                    form_tokens_forgotten = forgotten,
+                    "Forgot expired form tokens"
+                ),
+                Err(e) => tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to forget expired form tokens"
+                ),
+            }
+            tokio::select! {
+                () = tokio::time::sleep(interval) => {}
+                () = shutdown.requested() => {}
+            }
+        }
+        tracing::info!("Stopped the cleanup worker");
+    }
+}
+
+/// Sends due reminders, then deletes subscribers that never confirmed and
+/// expired tokens, as of `now`. Email change verification links expire
+/// along with confirmation links.
+///
+/// Several instances can run this at the same time: each pending subscriber
+/// is reminded at most once. The reminder is recorded before it is sent: if
+/// sending fails, we do not try again.
+#[tracing::instrument(name = "Cleaning up unconfirmed subscriptions", skip_all)]
+pub async fn cleanup_once(
+    subscribers: &dyn SubscriberRepository,
+    email_client: &EmailClient,
+    base_url: &str,
+    link_signer: &LinkSigner,
+    policy: &PendingSubscriptionPolicy,
+    now: DateTime<Utc>,
+) -> Result<CleanupReport, anyhow::Error> {
+    let mut report = CleanupReport::default();
+    let purge_cutoff = now - chrono::Duration::from_std(policy.purge_after)?;
+    let remind_cutoff = now - chrono::Duration::from_std(policy.remind_after)?;
+    let token_cutoff = now - chrono::Duration::from_std(policy.token_ttl)?;
+
+    loop {
+        let token = generate_subscription_token();
+        let subscriber = match subscribers
+            .claim_reminder(remind_cutoff, purge_cutoff, &token)
+            .await?
+        {
+            Some(subscriber) => subscriber,
+            None => break,
+        };
+        let recipient = match SubscriberEmail::parse(subscriber.email().to_string()) {
+            Ok(recipient) => recipient,
+            Err(e) => {
+                tracing::warn!(error.message = %e, "Skipping a reminder to an invalid address");
+                report.reminders_failed += 1;
+                metrics::REMINDERS_FAILED.inc();
+                continue;
+            }
+        };
+        let preferences_link = link_signer.preferences_link(base_url, subscriber.id());
+        match send_reminder_email(email_client, recipient, base_url, &token, &preferences_link)
+            .await
+        {
+            Ok(()) => {
+                report.reminders_sent += 1;
+                metrics::REMINDERS_SENT.inc();
+            }
+            Err(e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to send a confirmation reminder"
+                );
+                report.reminders_failed += 1;
+                metrics::REMINDERS_FAILED.inc();
+            }
+        }
+    }
+
+    report.subscribers_purged = subscribers.purge_unconfirmed(purge_cutoff).await?;
+    metrics::UNCONFIRMED_SUBSCRIBERS_PURGED.inc_by(report.subscribers_purged);
+    report.tokens_expired = subscribers.expire_confirmation_tokens(token_cutoff).await?;
+    metrics::CONFIRMATION_TOKENS_EXPIRED.inc_by(report.tokens_expired);
+    report.email_change_requests_expired = subscribers.expire_email_changes(token_cutoff).await?;
+    metrics::EMAIL_CHANGE_REQUESTS_EXPIRED.inc_by(report.email_change_requests_expired);
+    Ok(report)
+}
+
+#[tracing::instrument(
+    name = "Send a confirmation reminder",
+    skip(
+        email_client,
+        recipient,
+        base_url,
+        subscription_token,
+        preferences_link
+    )
+)]
+async fn send_reminder_email(
+    email_client: &EmailClient,
+    recipient: SubscriberEmail,
+    base_url: &str,
+    subscription_token: &str,
+    preferences_link: &str,
+) -> Result<(), reqwest::Error> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token
+    );
+    let plain_body = format!(
+        "You signed up for our newsletter but have not confirmed your address yet.\n\
+        Visit {} to confirm your subscription.{}",
+        confirmation_link,
+        plain_preferences_footer(preferences_link)
+    );
+    let html_body = format!(
+        "You signed up for our newsletter but have not confirmed your address yet.<br />\
+        Click <a href=\"{}\">here</a> to confirm your subscription.{}",
+        confirmation_link,
+        html_preferences_footer(preferences_link)
+    );
+    email_client
+        .send_email(
+            recipient,
+            "Please confirm your subscription",
+            &html_body,
+            &plain_body,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cleanup_worker::{cleanup_once, CleanupReport, PendingSubscriptionPolicy};
+    use crate::domain::{CustomFields, NewSubscriber, SubscriberEmail, SubscriberName};
+    use crate::email_client::EmailClient;
+    use crate::signed_links::LinkSigner;
+    use crate::subscriber_repository::{InMemorySubscriberRepository, SubscriberRepository};
+    use chrono::{DateTime, Utc};
+    use claims::{assert_none, assert_some};
+    use secrecy::Secret;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const HOUR: u64 = 60 * 60;
+
+    fn policy(token_ttl_hours: u64) -> PendingSubscriptionPolicy {
+        PendingSubscriptionPolicy {
+            token_ttl: Duration::from_secs(token_ttl_hours * HOUR),
+            remind_after: Duration::from_secs(24 * HOUR),
+            purge_after: Duration::from_secs(7 * 24 * HOUR),
+        }
+    }
+
+    async fn email_server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(path("/api/1.0/messages/send"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    async fn run(
+        subscribers: &InMemorySubscriberRepository,
+        email_server: &MockServer,
+        policy: PendingSubscriptionPolicy,
+        now: DateTime<Utc>,
+    ) -> CleanupReport {
+        let email_client = EmailClient::new(
+            email_server.uri(),
+            SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+            Secret::new("api-key".into()),
+            Duration::from_millis(200),
+        );
+        let link_signer = LinkSigner::new(Secret::new("hmac-secret".into()));
+        cleanup_once(
+            subscribers,
+            &email_client,
+            "http://127.0.0.1",
+            &link_signer,
+            &policy,
+            now,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn pending_subscriber(subscribers: &InMemorySubscriberRepository) -> Uuid {
+        let new_subscriber = NewSubscriber {
+            email: SubscriberEmail::parse("ursula_le_guin@gmail.com".into()).unwrap(),
+            name: SubscriberName::parse("le guin".into()).unwrap(),
+            custom_fields: CustomFields::default(),
+        };
+        let token = Uuid::new_v4().to_string();
+        subscribers
+            .insert(&new_subscriber, &token)
+            .await
+            .unwrap()
+            .unwrap()
+            .id()
+    }
+
+    #[tokio::test]
+    async fn pending_subscribers_are_reminded_once_then_purged_with_their_history() {
+        let subscribers = InMemorySubscriberRepository::default();
+        let email_server = email_server().await;
+        let subscriber_id = pending_subscriber(&subscribers).await;
+        let a_day_later = Utc::now() + chrono::Duration::hours(25);
+
+        let reminded = run(&subscribers, &email_server, policy(48), a_day_later).await;
+        let again = run(&subscribers, &email_server, policy(48), a_day_later).await;
+
+        assert_eq!(reminded.reminders_sent, 1);
+        assert_eq!(again.reminders_sent, 0);
+        assert_eq!(email_server.received_requests().await.unwrap().len(), 1);
+
+        let a_week_later = Utc::now() + chrono::Duration::days(8);
+        let purged = run(&subscribers, &email_server, policy(48), a_week_later).await;
+
+        assert_eq!(purged.subscribers_purged, 1);
+        assert_none!(subscribers.find_by_id(subscriber_id).await.unwrap());
+        assert!(subscribers.events(subscriber_id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn each_kind_of_expired_token_is_counted_on_its_own() {
+        let subscribers = InMemorySubscriberRepository::default();
+        let email_server = email_server().await;
+        let subscriber_id = pending_subscriber(&subscribers).await;
+        let new_email = SubscriberEmail::parse("le_guin@example.com".into()).unwrap();
+        subscribers
+            .insert_email_change(subscriber_id, &new_email, "change-token")
+            .await
+            .unwrap();
+        let two_hours_later = Utc::now() + chrono::Duration::hours(2);
+
+        let report = run(&subscribers, &email_server, policy(1), two_hours_later).await;
+
+        assert_eq!(report.tokens_expired, 1);
+        assert_eq!(report.email_change_requests_expired, 1);
+        assert_eq!(report.subscribers_purged, 0);
+        assert_some!(subscribers.find_by_id(subscriber_id).await.unwrap());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::authentication::create_user;
+use crate::cleanup_worker::CleanupWorker;
+use crate::configuration::{
+    get_configuration, get_configuration_from, ConfigurationError, Settings,
+};
+use crate::domain::SubscriberEmail;
+use crate::shutdown;
+use crate::startup::{get_connection_pool, AppState, Application};
+use crate::subscriber_repository::normalize_stored_emails;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use secrecy::Secret;
+use sqlx::migrate::Migrate;
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(name = "zero2prod", about = "Newsletter delivery service")]
+pub struct Cli {
+    /// Read the configuration from this directory instead of `APP_CONFIG_DIR`
+    /// or `./configuration`.
+    #[arg(long, global = true, value_name = "DIRECTORY")]
+    pub config_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Serve the API and run the background jobs. The default.
+    Serve,
+    /// Apply pending database migrations.
+    Migrate {
+        /// Only list the pending migrations.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Create a user who can call the admin endpoints. The password is read
+    /// from standard input, so that it stays out of the shell history.
+    CreateAdmin { username: String },
+    /// Send a test email through the configured email API.
+    SendTestEmail { address: String },
+    /// Check that the configuration is valid, without starting anything.
+    CheckConfig,
+    /// Print the effective configuration, with secrets redacted, and where
+    /// each value came from.
+    ShowConfig,
+    /// Run the background jobs without serving the API.
+    Worker,
+}
+
+impl Cli {
+    /// Loads the configuration from `--config-dir`, if given.
+    pub fn configuration(&self) -> Result<Settings, ConfigurationError> {
+        load_configuration(self.config_dir.as_deref())
+    }
+
+    pub async fn run(self, configuration: Settings) -> Result<(), anyhow::Error> {
+        match self.command.unwrap_or(Command::Serve) {
+            Command::Serve => serve(configuration, self.config_dir).await,
+            Command::Migrate { dry_run } => migrate(&configuration, dry_run).await,
+            Command::CreateAdmin { username } => create_admin(&configuration, &username).await,
+            Command::SendTestEmail { address } => send_test_email(&configuration, address).await,
+            Command::CheckConfig => check_config(&configuration),
+            Command::ShowConfig => {
+                show_config(&configuration);
+                Ok(())
+            }
+            Command::Worker => worker(&configuration).await,
+        }
+    }
+}
+
+fn load_configuration(config_dir: Option<&Path>) -> Result<Settings, ConfigurationError> {
+    match config_dir {
+        Some(directory) => get_configuration_from(directory),
+        None => get_configuration(),
+    }
+}
+
+async fn serve(configuration: Settings, config_dir: Option<PathBuf>) -> Result<(), anyhow::Error> {
+    let mut application = Application::build(configuration).await?;
+    application.spawn_cleanup_worker();
+    application.spawn_configuration_reloader(move || load_configuration(config_dir.as_deref()));
+    application.run_until_stopped(shutdown::signal()).await?;
+    Ok(())
+}
+
+async fn connect(configuration: &Settings) -> Result<PgPool, anyhow::Error> {
+    configuration
+        .database
+        .pool
+        .options()
+        .connect_with(configuration.database.with_db())
+        .await
+        .context("Failed to connect to Postgres.")
+}
+
+async fn migrate(configuration: &Settings, dry_run: bool) -> Result<(), anyhow::Error> {
+    let pool = connect(configuration).await?;
+    let migrator = sqlx::migrate!("./migrations");
+    let mut connection = pool.acquire().await?;
+    connection.ensure_migrations_table().await?;
+    let applied: HashSet<i64> = connection
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+    drop(connection);
+    let pending: Vec<_> = migrator
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect();
+    if pending.is_empty() {
+        println!("The database is up to date.");
+    } else {
+        for migration in &pending {
+            println!("{} {}", migration.version, migration.description);
+        }
+        if dry_run {
+            println!("{} pending migration(s).", pending.len());
+            return Ok(());
+        }
+        migrator
+            .run(&pool)
+            .await
+            .context("Failed to apply migrations.")?;
+        println!("Applied {} migration(s).", pending.len());
+    }
+    if dry_run {
+        return Ok(());
+    }
+    // Migrations can only approximate the normalization rules, which may also
+    // have changed since the addresses were stored.
+    let normalized = normalize_stored_emails(&pool, configuration.email_address.normalization())
+        .await
+        .context("Failed to normalize the stored email addresses.")?;
+    if normalized > 0 {
+        println!("Normalized {} stored email address(es).", normalized);
+    }
+    Ok(())
+}
+
+async fn create_admin(configuration: &Settings, username: &str) -> Result<(), anyhow::Error> {
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .context("Failed to read the password.")?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_string();
+    if password.is_empty() {
+        anyhow::bail!("The password must not be empty.");
+    }
+    let pool = connect(configuration).await?;
+    let user_id = create_user(username, Secret::new(password), &pool).await?;
+    println!("Created admin '{}' with id {}.", username, user_id);
+    Ok(())
+}
+
+async fn send_test_email(configuration: &Settings, address: String) -> Result<(), anyhow::Error> {
+    let recipient = SubscriberEmail::parse(address).context("Invalid recipient address.")?;
+    let email_client = configuration
+        .email_client
+        .client()
+        .context("Invalid sender email address.")?;
+    let address = recipient.as_ref().to_string();
+    let text = "This is a test email: the email API is configured correctly.";
+    email_client
+        .send_email(recipient, "Test email", text, text)
+        .await
+        .context("Failed to send the test email.")?;
+    println!("Sent a test email to {}.", address);
+    Ok(())
+}
+
+fn check_config(configuration: &Settings) -> Result<(), anyhow::Error> {
+    // Setting up the services checks everything that can be checked without
+    // connecting to anything.
+    AppState::new(configuration, get_connection_pool(&configuration.database))?;
+    println!("The configuration is valid.");
+    Ok(())
+}
+
+fn show_config(configuration: &Settings) {
+    let entries = configuration.describe();
+    let width = entries
+        .iter()
+        .map(|entry| entry.key.len())
+        .max()
+        .unwrap_or(0);
+    for entry in entries {
+        println!(
+            "{:width$} = {}  # {}",
+            entry.key,
+            entry.value,
+            entry.source,
+            width = width
+        );
+    }
+}
+
+async fn worker(configuration: &Settings) -> Result<(), anyhow::Error> {
+    let state = AppState::new(configuration, get_connection_pool(&configuration.database))?;
+    let (trigger, shutdown) = shutdown::channel();
+    let worker =
+        tokio::spawn(CleanupWorker::new(configuration, state.clone()).run_until_stopped(shutdown));
+    shutdown::signal().await;
+    trigger.trigger();
+    worker.await?;
+    state.pool.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{Cli, Command};
+    use clap::{CommandFactory, Parser};
+
+    fn parse(args: &[&str]) -> Option<Command> {
+        Cli::try_parse_from(args).unwrap().command
+    }
+
+    #[test]
+    fn the_arguments_are_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn subcommands_are_parsed() {
+        assert_eq!(parse(&["zero2prod"]), None);
+        assert_eq!(
+            parse(&["zero2prod", "migrate", "--dry-run"]),
+            Some(Command::Migrate { dry_run: true })
+        );
+        assert_eq!(
+            parse(&["zero2prod", "send-test-email", "ursula@example.com"]),
+            Some(Command::SendTestEmail {
+                address: "ursula@example.com".into()
+            })
+        );
+    }
+
+    #[test]
+    fn the_configuration_directory_can_be_given_before_or_after_the_subcommand() {
+        for args in [
+            &[
+                "zero2prod",
+                "--config-dir",
+                "/etc/zero2prod",
+                "check-config",
+            ],
+            &[
+                "zero2prod",
+                "check-config",
+                "--config-dir",
+                "/etc/zero2prod",
+            ],
+        ] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert_eq!(cli.config_dir, Some("/etc/zero2prod".into()));
+            assert_eq!(cli.command, Some(Command::CheckConfig));
+        }
+    }
+
+    #[test]
+    fn send_test_email_requires_an_address() {
+        assert!(Cli::try_parse_from(["zero2prod", "send-test-email"]).is_err());
+    }
+}
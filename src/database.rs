@@ -0,0 +1,116 @@
+use crate::configuration::{DatabaseSettings, PoolSettings};
+use crate::secrets::SecretSource;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::postgres::PgConnectOptions;
+use sqlx::PgPool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How long a replaced pool stays open, so that whoever picked it up just
+/// before it was replaced can finish with it.
+const RETIRED_POOL_GRACE: Duration = Duration::from_secs(30);
+
+/// The application's connection pool.
+///
+/// Connections are opened with the current database password: when it
+/// changes, because its file was rotated or a reload brought a new one, the
+/// pool is replaced by one that uses it. A rotation does not end the
+/// connections that are already open, so the old pool keeps serving whoever
+/// holds it for a while before it is closed.
+#[derive(Clone)]
+pub struct ConnectionPool(Arc<Inner>);
+
+struct Inner {
+    /// The pool, with the password its connections are opened with.
+    current: RwLock<(Secret<String>, PgPool)>,
+    /// `None` for a pool that is never replaced.
+    rotation: Option<Rotation>,
+}
+
+/// What it takes to build a replacement pool.
+struct Rotation {
+    pool: PoolSettings,
+    connect_options: PgConnectOptions,
+    password: RwLock<SecretSource>,
+}
+
+impl ConnectionPool {
+    /// Connects lazily: nothing is opened until the pool is first used.
+    pub fn new(settings: &DatabaseSettings) -> Self {
+        let connect_options = settings.with_db();
+        let pool = settings
+            .pool
+            .options()
+            .connect_lazy_with(connect_options.clone());
+        Self(Arc::new(Inner {
+            current: RwLock::new((settings.password.clone(), pool)),
+            rotation: Some(Rotation {
+                pool: settings.pool.clone(),
+                connect_options,
+                password: RwLock::new(settings.password_source()),
+            }),
+        }))
+    }
+
+    /// The pool to run queries on, replaced first if the password has
+    /// changed since it was built.
+    pub fn get(&self) -> PgPool {
+        let rotation = match &self.0.rotation {
+            Some(rotation) => rotation,
+            None => return self.0.current.read().unwrap().1.clone(),
+        };
+        let password = rotation.password.read().unwrap().current();
+        {
+            let current = self.0.current.read().unwrap();
+            if current.0.expose_secret() == password.expose_secret() {
+                return current.1.clone();
+            }
+        }
+
+        let mut current = self.0.current.write().unwrap();
+        // Someone else may have replaced it while we were waiting.
+        if current.0.expose_secret() != password.expose_secret() {
+            let pool = rotation.pool.options().connect_lazy_with(
+                rotation
+                    .connect_options
+                    .clone()
+                    .password(password.expose_secret()),
+            );
+            let (_, retired) = std::mem::replace(&mut *current, (password, pool));
+            tracing::info!("Replaced the connection pool to use a new database password");
+            retire(retired);
+        }
+        current.1.clone()
+    }
+
+    /// Opens connections with `password` from now on. Does nothing for a
+    /// pool built from a [`PgPool`].
+    pub fn set_password(&self, password: SecretSource) {
+        if let Some(rotation) = &self.0.rotation {
+            *rotation.password.write().unwrap() = password;
+        }
+    }
+
+    /// Waits for the connections in use to be returned, then closes them all.
+    pub async fn close(&self) {
+        let pool = self.0.current.read().unwrap().1.clone();
+        pool.close().await;
+    }
+}
+
+/// A pool that always opens connections the way `pool` does.
+impl From<PgPool> for ConnectionPool {
+    fn from(pool: PgPool) -> Self {
+        Self(Arc::new(Inner {
+            current: RwLock::new((Secret::new(String::new()), pool)),
+            rotation: None,
+        }))
+    }
+}
+
+fn retire(pool: PgPool) {
+    tokio::spawn(async move {
+        tokio::time::sleep(RETIRED_POOL_GRACE).await;
+        pool.close().await;
+    });
+}
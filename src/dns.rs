@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Answers whether a domain can receive email.
+#[async_trait::async_trait]
+pub trait MailDomainResolver: Send + Sync {
+    /// Returns `Ok(false)` if `domain` definitely cannot receive email, and an
+    /// error if we could not find out (e.g. the lookup timed out).
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error>;
+}
+
+/// Resolves domains using DNS: a domain accepts mail if it has MX records or,
+/// failing that, A/AAAA records (RFC 5321, section 5.1).
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsResolver {
+    /// Uses the system's resolver configuration (`/etc/resolv.conf`), or
+    /// public resolvers if it cannot be read.
+    pub fn new() -> Self {
+        let (config, mut options) = match trust_dns_resolver::system_conf::read_system_conf() {
+            Ok(system_conf) => system_conf,
+            Err(e) => {
+                tracing::warn!(
+                    error.message = %e,
+                    "Failed to read the system DNS configuration, using the defaults"
+                );
+                (ResolverConfig::default(), ResolverOpts::default())
+            }
+        };
+        // A subscriber is waiting on the answer.
+        options.timeout = Duration::from_secs(2);
+        options.attempts = 1;
+        let resolver =
+            TokioAsyncResolver::tokio(config, options).expect("Failed to build the DNS resolver.");
+        Self { resolver }
+    }
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `e` means the name exists but has no records of the requested
+/// type (`Some(true)`), does not exist at all (`Some(false)`), or neither.
+fn name_exists(e: &ResolveError) -> Option<bool> {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+            Some(*response_code != ResponseCode::NXDomain)
+        }
+        _ => None,
+    }
+}
+
+#[async_trait::async_trait]
+impl MailDomainResolver for DnsResolver {
+    #[tracing::instrument(name = "Resolving mail servers", skip(self))]
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        // The trailing dot stops the search domains from being appended.
+        let fqdn = format!("{}.", domain);
+        match self.resolver.mx_lookup(fqdn.as_str()).await {
+            // A single MX record pointing at `.` is a "null MX" (RFC 7505):
+            // the domain explicitly does not accept mail.
+            Ok(mx) => return Ok(mx.iter().any(|record| !record.exchange().is_root())),
+            Err(e) => match name_exists(&e) {
+                Some(true) => {}
+                Some(false) => return Ok(false),
+                None => return Err(e.into()),
+            },
+        }
+        match self.resolver.lookup_ip(fqdn.as_str()).await {
+            Ok(_) => Ok(true),
+            Err(e) => match name_exists(&e) {
+                Some(_) => Ok(false),
+                None => Err(e.into()),
+            },
+        }
+    }
+}
+
+/// Remembers the answers of another resolver for `ttl`. Failed lookups are
+/// not cached.
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+/// Past this many entries, expired ones are evicted on insertion.
+const CACHE_SOFT_LIMIT: usize = 10_000;
+
+impl<R: MailDomainResolver> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: MailDomainResolver> MailDomainResolver for CachingResolver<R> {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        if let Some((accepts, expires_at)) = self.cache.lock().unwrap().get(domain) {
+            if *expires_at > Instant::now() {
+                return Ok(*accepts);
+            }
+        }
+        let accepts = self.inner.accepts_mail(domain).await?;
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_SOFT_LIMIT {
+            cache.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        cache.insert(domain.to_string(), (accepts, now + self.ttl));
+        Ok(accepts)
+    }
+}
+
+/// A resolver that knows a fixed set of mail domains, for tests.
+#[derive(Default)]
+pub struct InMemoryResolver {
+    domains: HashSet<String>,
+    lookups: Mutex<usize>,
+}
+
+impl InMemoryResolver {
+    pub fn new<I, S>(domains: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            domains: domains.into_iter().map(Into::into).collect(),
+            lookups: Mutex::new(0),
+        }
+    }
+
+    /// How many lookups have been made so far.
+    pub fn lookups(&self) -> usize {
+        *self.lookups.lock().unwrap()
+    }
+}
+
+#[async_trait::async_trait]
+impl MailDomainResolver for InMemoryResolver {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        *self.lookups.lock().unwrap() += 1;
+        Ok(self.domains.contains(domain))
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: MailDomainResolver + ?Sized> MailDomainResolver for std::sync::Arc<R> {
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        (**self).accepts_mail(domain).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::{CachingResolver, InMemoryResolver, MailDomainResolver};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn answers_are_cached_until_they_expire() {
+        let inner = Arc::new(InMemoryResolver::new(["example.com"]));
+        let resolver = CachingResolver::new(inner.clone(), Duration::from_millis(50));
+
+        assert!(resolver.accepts_mail("example.com").await.unwrap());
+        assert!(!resolver.accepts_mail("gmial.com").await.unwrap());
+        assert!(resolver.accepts_mail("example.com").await.unwrap());
+        assert!(!resolver.accepts_mail("gmial.com").await.unwrap());
+        assert_eq!(inner.lookups(), 2);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(resolver.accepts_mail("example.com").await.unwrap());
+        assert_eq!(inner.lookups(), 3);
+    }
+}
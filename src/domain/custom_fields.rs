@@ -0,0 +1,453 @@
+use chrono::NaiveDate;
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+
+/// Field names that are already taken by the built-in subscription fields.
+const RESERVED_NAMES: [&str; 2] = ["email", "name"];
+/// The maximum length of a text value, in characters.
+const MAX_TEXT_LENGTH: usize = 1024;
+
+/// Why a submitted custom field value was rejected.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CustomFieldError {
+    #[error("This is not a known field.")]
+    Unknown,
+    #[error("This field is required.")]
+    Missing,
+    #[error("The value must be at most {max} characters long.")]
+    TooLong { max: usize },
+    #[error("The value is not a valid {expected}.")]
+    InvalidValue { expected: &'static str },
+}
+
+/// The kind of value an admin-defined custom field accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CustomFieldType {
+    Text,
+    Number,
+    Date,
+    Boolean,
+    /// One of a fixed set of allowed values.
+    Enum(Vec<String>),
+}
+
+impl CustomFieldType {
+    /// Builds a field type from its stored representation: the type name plus,
+    /// for `enum` fields, the list of allowed values.
+    pub fn parse(field_type: &str, options: Vec<String>) -> Result<Self, String> {
+        let field_type = match field_type {
+            "text" => Self::Text,
+            "number" => Self::Number,
+            "date" => Self::Date,
+            "boolean" => Self::Boolean,
+            "enum" => {
+                if options.is_empty() || options.iter().any(|o| o.trim().is_empty()) {
+                    return Err("An enum field needs at least one non-empty option.".into());
+                }
+                return Ok(Self::Enum(options));
+            }
+            other => return Err(format!("{} is not a supported custom field type.", other)),
+        };
+        if options.is_empty() {
+            Ok(field_type)
+        } else {
+            Err("Only enum fields accept options.".into())
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Number => "number",
+            Self::Date => "date",
+            Self::Boolean => "boolean",
+            Self::Enum(_) => "enum",
+        }
+    }
+
+    pub fn options(&self) -> &[String] {
+        match self {
+            Self::Enum(options) => options,
+            _ => &[],
+        }
+    }
+}
+
+/// A field that subscribers to a mailing list fill in.
+#[derive(Debug, Clone)]
+pub struct CustomFieldDefinition {
+    list: String,
+    name: String,
+    field_type: CustomFieldType,
+    required: bool,
+}
+
+impl CustomFieldDefinition {
+    /// Returns a definition if `name` is a lowercase identifier that does not
+    /// collide with one of the built-in subscription fields.
+    pub fn parse(
+        list: String,
+        name: String,
+        field_type: CustomFieldType,
+        required: bool,
+    ) -> Result<Self, String> {
+        let mut chars = name.chars();
+        let is_identifier = matches!(chars.next(), Some(c) if c.is_ascii_lowercase())
+            && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !is_identifier || name.len() > 64 {
+            return Err(format!("{} is not a valid custom field name.", name));
+        }
+        if RESERVED_NAMES.contains(&name.as_str()) {
+            return Err(format!("{} is a reserved field name.", name));
+        }
+        Ok(Self {
+            list,
+            name,
+            field_type,
+            required,
+        })
+    }
+
+    /// The mailing list the field belongs to.
+    pub fn list(&self) -> &str {
+        &self.list
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn field_type(&self) -> &CustomFieldType {
+        &self.field_type
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Converts a raw submitted value into the JSON value we store.
+    fn parse_value(&self, raw: &str) -> Result<Value, CustomFieldError> {
+        let invalid = || CustomFieldError::InvalidValue {
+            expected: match self.field_type {
+                CustomFieldType::Number => "number",
+                CustomFieldType::Date => "date (YYYY-MM-DD)",
+                CustomFieldType::Boolean => "boolean",
+                _ => "option",
+            },
+        };
+        match &self.field_type {
+            CustomFieldType::Text => {
+                if raw.chars().count() > MAX_TEXT_LENGTH {
+                    Err(CustomFieldError::TooLong {
+                        max: MAX_TEXT_LENGTH,
+                    })
+                } else {
+                    Ok(Value::String(raw.to_string()))
+                }
+            }
+            CustomFieldType::Number => {
+                if let Ok(n) = raw.parse::<i64>() {
+                    Ok(Value::Number(n.into()))
+                } else {
+                    raw.parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .map(Value::Number)
+                        .ok_or_else(invalid)
+                }
+            }
+            CustomFieldType::Date => NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+                .map(|d| Value::String(d.format("%Y-%m-%d").to_string()))
+                .map_err(|_| invalid()),
+            CustomFieldType::Boolean => match raw.to_lowercase().as_str() {
+                // HTML checkboxes submit `on` when ticked.
+                "true" | "on" | "1" => Ok(Value::Bool(true)),
+                "false" | "off" | "0" => Ok(Value::Bool(false)),
+                _ => Err(invalid()),
+            },
+            CustomFieldType::Enum(options) => {
+                if options.iter().any(|o| o == raw) {
+                    Ok(Value::String(raw.to_string()))
+                } else {
+                    Err(invalid())
+                }
+            }
+        }
+    }
+}
+
+/// Custom field values that have been validated against their definitions,
+/// grouped by mailing list.
+#[derive(Debug, Default, Clone)]
+pub struct CustomFields(Map<String, Value>);
+
+impl CustomFields {
+    /// Returns the typed values for `raw` if every submitted field is defined,
+    /// every value matches its field type and no required field is missing.
+    /// Empty values are treated as absent.
+    ///
+    /// `definitions` may come from several lists: a value is stored for every
+    /// list that defines a field with its name.
+    ///
+    /// Otherwise every offending field is returned along with its error.
+    pub fn parse(
+        raw: HashMap<String, String>,
+        definitions: &[CustomFieldDefinition],
+    ) -> Result<Self, Vec<(String, CustomFieldError)>> {
+        let mut errors: Vec<_> = raw
+            .keys()
+            .filter(|k| !definitions.iter().any(|d| d.name() == k.as_str()))
+            .map(|k| (k.clone(), CustomFieldError::Unknown))
+            .collect();
+        errors.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut values = Map::new();
+        let mut invalid = Vec::new();
+        for definition in definitions {
+            let outcome = match raw.get(definition.name()).map(|v| v.trim()) {
+                Some(value) if !value.is_empty() => definition.parse_value(value).map(Some),
+                _ if definition.required() => Err(CustomFieldError::Missing),
+                _ => Ok(None),
+            };
+            match outcome {
+                Ok(Some(value)) => {
+                    let list = values
+                        .entry(definition.list())
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    if let Value::Object(fields) = list {
+                        fields.insert(definition.name().to_string(), value);
+                    }
+                }
+                Ok(None) => {}
+                // Lists that share a field report it once.
+                Err(e) if !invalid.contains(&definition.name()) => {
+                    invalid.push(definition.name());
+                    errors.push((definition.name().to_string(), e));
+                }
+                Err(_) => {}
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Self(values))
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Values as stored by [`CustomFields::as_json`]. They were validated
+    /// when they were written, so anything else is ignored.
+    pub fn restore(stored: Value) -> Self {
+        match stored {
+            Value::Object(lists) => Self(
+                lists
+                    .into_iter()
+                    .filter(|(_, fields)| fields.is_object())
+                    .collect(),
+            ),
+            _ => Self::default(),
+        }
+    }
+
+    /// The value of `list`'s field `name`.
+    pub fn get(&self, list: &str, name: &str) -> Option<&Value> {
+        self.0.get(list).and_then(|fields| fields.get(name))
+    }
+
+    pub fn as_json(&self) -> Value {
+        Value::Object(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{CustomFieldDefinition, CustomFieldError, CustomFieldType, CustomFields};
+    use claims::{assert_err, assert_ok};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn definition(
+        name: &str,
+        field_type: CustomFieldType,
+        required: bool,
+    ) -> CustomFieldDefinition {
+        CustomFieldDefinition::parse("newsletter".into(), name.into(), field_type, required)
+            .unwrap()
+    }
+
+    fn raw(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn names_must_be_lowercase_identifiers() {
+        for name in ["", "Company", "1st", "job title", "company-size"] {
+            assert_err!(CustomFieldDefinition::parse(
+                "newsletter".into(),
+                name.into(),
+                CustomFieldType::Text,
+                false
+            ));
+        }
+        assert_ok!(CustomFieldDefinition::parse(
+            "newsletter".into(),
+            "company_size".into(),
+            CustomFieldType::Text,
+            false
+        ));
+    }
+
+    #[test]
+    fn built_in_field_names_are_reserved() {
+        assert_err!(CustomFieldDefinition::parse(
+            "newsletter".into(),
+            "email".into(),
+            CustomFieldType::Text,
+            false
+        ));
+    }
+
+    #[test]
+    fn enum_fields_require_options() {
+        assert_err!(CustomFieldType::parse("enum", vec![]));
+        assert_err!(CustomFieldType::parse("text", vec!["a".into()]));
+        assert_err!(CustomFieldType::parse("colour", vec![]));
+    }
+
+    #[test]
+    fn values_are_converted_to_their_field_type() {
+        let definitions = vec![
+            definition("company", CustomFieldType::Text, false),
+            definition("employees", CustomFieldType::Number, false),
+            definition("founded", CustomFieldType::Date, false),
+            definition("customer", CustomFieldType::Boolean, false),
+            definition(
+                "role",
+                CustomFieldType::Enum(vec!["engineer".into(), "manager".into()]),
+                false,
+            ),
+        ];
+        let fields = CustomFields::parse(
+            raw(&[
+                ("company", "Acme"),
+                ("employees", "12"),
+                ("founded", "1999-12-31"),
+                ("customer", "on"),
+                ("role", "manager"),
+            ]),
+            &definitions,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fields.as_json(),
+            json!({"newsletter": {
+                "company": "Acme",
+                "employees": 12,
+                "founded": "1999-12-31",
+                "customer": true,
+                "role": "manager",
+            }})
+        );
+    }
+
+    #[test]
+    fn values_that_do_not_match_their_type_are_rejected() {
+        let definitions = vec![
+            definition("employees", CustomFieldType::Number, false),
+            definition("founded", CustomFieldType::Date, false),
+            definition("customer", CustomFieldType::Boolean, false),
+            definition(
+                "role",
+                CustomFieldType::Enum(vec!["engineer".into()]),
+                false,
+            ),
+        ];
+        for (name, value) in [
+            ("employees", "a dozen"),
+            ("founded", "31/12/1999"),
+            ("customer", "maybe"),
+            ("role", "ceo"),
+        ] {
+            assert_err!(CustomFields::parse(raw(&[(name, value)]), &definitions));
+        }
+    }
+
+    #[test]
+    fn missing_required_fields_are_rejected() {
+        let definitions = vec![definition("company", CustomFieldType::Text, true)];
+        assert_err!(CustomFields::parse(raw(&[]), &definitions));
+        assert_eq!(
+            assert_err!(CustomFields::parse(raw(&[("company", " ")]), &definitions)),
+            vec![("company".to_string(), CustomFieldError::Missing)]
+        );
+    }
+
+    #[test]
+    fn every_offending_field_is_reported() {
+        let definitions = vec![
+            definition("company", CustomFieldType::Text, true),
+            definition("employees", CustomFieldType::Number, false),
+        ];
+        let errors = assert_err!(CustomFields::parse(
+            raw(&[("employees", "many"), ("colour", "blue")]),
+            &definitions
+        ));
+        assert_eq!(
+            errors,
+            vec![
+                ("colour".to_string(), CustomFieldError::Unknown),
+                ("company".to_string(), CustomFieldError::Missing),
+                (
+                    "employees".to_string(),
+                    CustomFieldError::InvalidValue { expected: "number" }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_optional_fields_are_omitted() {
+        let definitions = vec![definition("company", CustomFieldType::Text, false)];
+        let fields = CustomFields::parse(raw(&[("company", "")]), &definitions).unwrap();
+        assert!(fields.get("newsletter", "company").is_none());
+    }
+
+    #[test]
+    fn values_are_stored_for_every_list_defining_the_field() {
+        let list_definition = |list: &str, name: &str, required| {
+            CustomFieldDefinition::parse(list.into(), name.into(), CustomFieldType::Text, required)
+                .unwrap()
+        };
+        let definitions = vec![
+            list_definition("newsletter", "company", true),
+            list_definition("events", "company", true),
+            list_definition("events", "city", false),
+        ];
+
+        let fields = CustomFields::parse(raw(&[("company", "Acme")]), &definitions).unwrap();
+        assert_eq!(
+            fields.as_json(),
+            json!({"newsletter": {"company": "Acme"}, "events": {"company": "Acme"}})
+        );
+        assert_eq!(
+            assert_err!(CustomFields::parse(raw(&[]), &definitions)),
+            vec![("company".to_string(), CustomFieldError::Missing)]
+        );
+    }
+
+    #[test]
+    fn restored_values_are_looked_up_by_list() {
+        let fields = CustomFields::restore(json!({"events": {"city": "Portland"}}));
+        assert_eq!(fields.get("events", "city"), Some(&json!("Portland")));
+        assert_eq!(fields.get("newsletter", "city"), None);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert_err!(CustomFields::parse(raw(&[("company", "Acme")]), &[]));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::dns::MailDomainResolver;
+use crate::domain::subscriber_email::{EmailNormalization, SubscriberEmail, SubscriberEmailError};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// Providers common enough that a near miss is more likely a typo than a
+/// real domain.
+const WELL_KNOWN_DOMAINS: [&str; 20] = [
+    "aol.com",
+    "comcast.net",
+    "fastmail.com",
+    "gmail.com",
+    "gmx.com",
+    "gmx.de",
+    "googlemail.com",
+    "hotmail.co.uk",
+    "hotmail.com",
+    "icloud.com",
+    "live.com",
+    "mail.com",
+    "me.com",
+    "msn.com",
+    "outlook.com",
+    "proton.me",
+    "protonmail.com",
+    "yahoo.co.uk",
+    "yahoo.com",
+    "yandex.ru",
+];
+
+/// Decides which email addresses we accept subscriptions from.
+///
+/// On top of syntactic validation, addresses are rejected if their domain is
+/// on the blocklist (throwaway providers) or their local part is a role such
+/// as `noreply` or `postmaster`. Addresses or domains on the allowlist bypass
+/// both checks.
+///
+/// With a resolver configured, [`EmailPolicy::verify_deliverable`] also
+/// rejects domains that cannot receive email.
+pub struct EmailPolicy {
+    normalization: EmailNormalization,
+    blocklist: Option<Blocklist>,
+    role_prefixes: Vec<String>,
+    allowlist: HashSet<String>,
+    resolver: Option<Arc<dyn MailDomainResolver>>,
+}
+
+/// A set of domains read from a file with one domain per line. Blank lines
+/// and lines starting with `#` are ignored.
+///
+/// The file is re-read whenever its modification time changes, so the list
+/// can be updated without restarting the application.
+struct Blocklist {
+    path: PathBuf,
+    state: RwLock<BlocklistState>,
+}
+
+#[derive(Default)]
+struct BlocklistState {
+    modified: Option<SystemTime>,
+    domains: HashSet<String>,
+}
+
+impl EmailPolicy {
+    pub fn new(
+        normalization: EmailNormalization,
+        blocklist_path: Option<PathBuf>,
+        role_prefixes: Vec<String>,
+        allowlist: Vec<String>,
+    ) -> Self {
+        let blocklist = blocklist_path.map(|path| {
+            let blocklist = Blocklist {
+                path,
+                state: RwLock::new(BlocklistState::default()),
+            };
+            blocklist.reload_if_modified();
+            blocklist
+        });
+        Self {
+            normalization,
+            blocklist,
+            role_prefixes: role_prefixes
+                .into_iter()
+                .map(|p| p.to_lowercase())
+                .collect(),
+            allowlist: allowlist.into_iter().map(|a| a.to_lowercase()).collect(),
+            resolver: None,
+        }
+    }
+
+    /// Checks that the domain of accepted addresses can receive email.
+    pub fn with_resolver(mut self, resolver: Arc<dyn MailDomainResolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Parses `s` with [`SubscriberEmail::parse_with`] and checks the result
+    /// against the policy.
+    pub fn parse(&self, s: String) -> Result<SubscriberEmail, SubscriberEmailError> {
+        let email = SubscriberEmail::parse_with(s, self.normalization)?;
+        let (local, domain) = split(&email);
+        if self.is_allowlisted(&email) {
+            return Ok(email);
+        }
+
+        if let Some(blocklist) = &self.blocklist {
+            if blocklist.contains(domain) {
+                return Err(SubscriberEmailError::DisposableDomain(domain.to_string()));
+            }
+        }
+        if let Some(prefix) = self.role_prefixes.iter().find(|p| is_role(local, p)) {
+            return Err(SubscriberEmailError::RoleAccount(prefix.clone()));
+        }
+        Ok(email)
+    }
+
+    /// Looks up the domain of `email`, unless no resolver is configured or the
+    /// address is allowlisted.
+    ///
+    /// If the lookup itself fails we give the address the benefit of the
+    /// doubt: a flaky DNS server should not stop people from subscribing.
+    pub async fn verify_deliverable(
+        &self,
+        email: &SubscriberEmail,
+    ) -> Result<(), SubscriberEmailError> {
+        let resolver = match &self.resolver {
+            Some(resolver) if !self.is_allowlisted(email) => resolver,
+            _ => return Ok(()),
+        };
+        let (_, domain) = split(email);
+        match resolver.accepts_mail(domain).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(SubscriberEmailError::Undeliverable {
+                domain: domain.to_string(),
+                suggestion: suggest_domain(domain).map(String::from),
+            }),
+            Err(e) => {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    domain,
+                    "Failed to verify that a domain accepts email"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn is_allowlisted(&self, email: &SubscriberEmail) -> bool {
+        let (_, domain) = split(email);
+        self.allowlist.contains(email.normalized()) || self.allowlist.contains(domain)
+    }
+}
+
+/// Splits the normalized form of `email` into its local part and domain.
+fn split(email: &SubscriberEmail) -> (&str, &str) {
+    email
+        .normalized()
+        .rsplit_once('@')
+        .expect("A normalized email always contains an `@`.")
+}
+
+/// The well-known domain closest to `domain`, if `domain` looks like a typo
+/// of it: at most one edit away, or two for longer domains.
+fn suggest_domain(domain: &str) -> Option<&'static str> {
+    let max_distance = if domain.len() >= 10 { 2 } else { 1 };
+    WELL_KNOWN_DOMAINS
+        .iter()
+        .map(|known| (*known, strsim::damerau_levenshtein(domain, known)))
+        .filter(|(_, distance)| (1..=max_distance).contains(distance))
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(known, _)| known)
+}
+
+/// `noreply`, `noreply-alerts` and `noreply+news` all match the `noreply`
+/// prefix; `noreplyjane` does not.
+fn is_role(local: &str, prefix: &str) -> bool {
+    match local.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with(['.', '-', '_', '+']),
+        None => false,
+    }
+}
+
+impl Blocklist {
+    /// Returns `true` if `domain`, or any domain it is a subdomain of, is on
+    /// the list.
+    fn contains(&self, domain: &str) -> bool {
+        self.reload_if_modified();
+        let state = self.state.read().unwrap();
+        let mut candidate = domain;
+        loop {
+            if state.domains.contains(candidate) {
+                return true;
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// If the file cannot be read, the previously loaded list stays in use.
+    fn reload_if_modified(&self) {
+        let modified = match std::fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                tracing::warn!(
+                    error.message = %e,
+                    path = %self.path.display(),
+                    "Failed to read the email domain blocklist"
+                );
+                return;
+            }
+        };
+        if self.state.read().unwrap().modified == Some(modified) {
+            return;
+        }
+
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                let domains: HashSet<String> = contents
+                    .lines()
+                    .map(|line| line.trim().trim_end_matches('.').to_lowercase())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .collect();
+                tracing::info!(
+                    path = %self.path.display(),
+                    domains = domains.len(),
+                    "Loaded the email domain blocklist"
+                );
+                *self.state.write().unwrap() = BlocklistState {
+                    modified: Some(modified),
+                    domains,
+                };
+            }
+            Err(e) => tracing::warn!(
+                error.message = %e,
+                path = %self.path.display(),
+                "Failed to read the email domain blocklist"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::InMemoryResolver;
+    use crate::domain::{EmailNormalization, EmailPolicy, SubscriberEmailError};
+    use claims::{assert_err, assert_ok};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn blocklist_file(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn policy(blocklist: &str, allowlist: &[&str]) -> EmailPolicy {
+        EmailPolicy::new(
+            EmailNormalization::default(),
+            Some(blocklist_file(blocklist)),
+            vec!["noreply".into(), "postmaster".into()],
+            allowlist.iter().map(|a| a.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn blocklisted_domains_and_their_subdomains_are_rejected() {
+        let policy = policy("# throwaway providers\nmailinator.com\n\n", &[]);
+
+        for email in ["ursula@Mailinator.com", "ursula@eu.mailinator.com"] {
+            assert_eq!(
+                assert_err!(policy.parse(email.into())),
+                SubscriberEmailError::DisposableDomain(
+                    email.split_once('@').unwrap().1.to_lowercase()
+                )
+            );
+        }
+        assert_ok!(policy.parse("ursula@notmailinator.com".into()));
+    }
+
+    #[test]
+    fn role_accounts_are_rejected() {
+        let policy = policy("", &[]);
+
+        for email in [
+            "noreply@example.com",
+            "NoReply-alerts@example.com",
+            "postmaster+news@example.com",
+        ] {
+            assert_err!(policy.parse(email.into()));
+        }
+        assert_ok!(policy.parse("noreplyjane@example.com".into()));
+    }
+
+    #[test]
+    fn the_allowlist_overrides_both_rules() {
+        let policy = policy("mailinator.com", &["mailinator.com", "noreply@example.com"]);
+
+        assert_ok!(policy.parse("ursula@mailinator.com".into()));
+        assert_ok!(policy.parse("noreply@example.com".into()));
+        assert_err!(policy.parse("noreply@example.org".into()));
+    }
+
+    #[test]
+    fn changes_to_the_blocklist_file_are_picked_up() {
+        let path = blocklist_file("");
+        let policy = EmailPolicy::new(
+            EmailNormalization::default(),
+            Some(path.clone()),
+            vec![],
+            vec![],
+        );
+        assert_ok!(policy.parse("ursula@mailinator.com".into()));
+
+        // Make sure the modification time changes even on coarse filesystems
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        std::fs::write(&path, "mailinator.com\n").unwrap();
+
+        assert_err!(policy.parse("ursula@mailinator.com".into()));
+    }
+
+    #[test]
+    fn a_missing_blocklist_file_does_not_reject_anything() {
+        let policy = EmailPolicy::new(
+            EmailNormalization::default(),
+            Some(PathBuf::from("/does/not/exist.txt")),
+            vec![],
+            vec![],
+        );
+        assert_ok!(policy.parse("ursula@mailinator.com".into()));
+    }
+
+    fn resolving_policy() -> EmailPolicy {
+        policy("", &["allowed.example"]).with_resolver(Arc::new(InMemoryResolver::new([
+            "gmail.com",
+            "example.com",
+        ])))
+    }
+
+    #[tokio::test]
+    async fn domains_without_mail_servers_are_undeliverable() {
+        let policy = resolving_policy();
+
+        let email = policy.parse("ursula@example.com".into()).unwrap();
+        assert_ok!(policy.verify_deliverable(&email).await);
+
+        let email = policy.parse("ursula@nowhere.example".into()).unwrap();
+        assert_eq!(
+            assert_err!(policy.verify_deliverable(&email).await),
+            SubscriberEmailError::Undeliverable {
+                domain: "nowhere.example".into(),
+                suggestion: None
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn misspelled_providers_come_with_a_suggestion() {
+        let policy = resolving_policy();
+
+        for typo in ["gmial.com", "gmail.con", "gnail.com"] {
+            let email = policy.parse(format!("ursula@{}", typo)).unwrap();
+            let error = assert_err!(policy.verify_deliverable(&email).await);
+            assert_eq!(
+                error,
+                SubscriberEmailError::Undeliverable {
+                    domain: typo.into(),
+                    suggestion: Some("gmail.com".into())
+                }
+            );
+            assert!(error.to_string().ends_with("Did you mean gmail.com?"));
+        }
+    }
+
+    #[tokio::test]
+    async fn allowlisted_domains_are_not_looked_up() {
+        let policy = resolving_policy();
+        let email = policy.parse("ursula@allowed.example".into()).unwrap();
+        assert_ok!(policy.verify_deliverable(&email).await);
+    }
+}
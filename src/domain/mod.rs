@@ -1,7 +1,17 @@
+mod custom_fields;
+mod email_policy;
 mod new_subscriber;
+mod personalization;
+mod sending_frequency;
+mod subscriber;
 mod subscriber_email;
 mod subscriber_name;
 
+pub use custom_fields::{CustomFieldDefinition, CustomFieldError, CustomFieldType, CustomFields};
+pub use email_policy::EmailPolicy;
 pub use new_subscriber::NewSubscriber;
-pub use subscriber_email::SubscriberEmail;
-pub use subscriber_name::SubscriberName;
+pub use personalization::personalize;
+pub use sending_frequency::SendingFrequency;
+pub use subscriber::{InvalidTransition, Subscriber, SubscriberStatus};
+pub use subscriber_email::{EmailNormalization, SubscriberEmail, SubscriberEmailError};
+pub use subscriber_name::{SubscriberName, SubscriberNameError};
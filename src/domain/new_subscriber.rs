@@ -1,7 +1,9 @@
+use crate::domain::custom_fields::CustomFields;
 use crate::domain::subscriber_email::SubscriberEmail;
 use crate::domain::subscriber_name::SubscriberName;
 
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    pub custom_fields: CustomFields,
 }
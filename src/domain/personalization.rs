@@ -0,0 +1,81 @@
+use crate::domain::Subscriber;
+use serde_json::Value;
+
+/// Fills in the `{{ placeholder }}`s of an email sent to `list`.
+///
+/// `{{ name }}` and `{{ email }}` are the subscriber's details; any other
+/// placeholder is the value of the list's custom field with that name, or
+/// nothing if they did not fill it in. A `{{` without a matching `}}` is left
+/// as it is.
+pub fn personalize(template: &str, subscriber: &Subscriber, list: &str) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        rendered.push_str(&rest[..start]);
+        match rest[start + 2..end].trim() {
+            "name" => rendered.push_str(subscriber.name()),
+            "email" => rendered.push_str(subscriber.email()),
+            field => match subscriber.custom_fields().get(list, field) {
+                Some(Value::String(value)) => rendered.push_str(value),
+                Some(Value::Null) | None => {}
+                Some(value) => rendered.push_str(&value.to_string()),
+            },
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::personalization::personalize;
+    use crate::domain::{CustomFields, Subscriber, SubscriberStatus};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn subscriber() -> Subscriber {
+        Subscriber::restore(
+            Uuid::new_v4(),
+            "ursula@example.com".into(),
+            "le guin".into(),
+            SubscriberStatus::Confirmed,
+            CustomFields::restore(json!({
+                "newsletter": {"company": "Acme", "employees": 12},
+                "events": {"city": "Portland"}
+            })),
+        )
+    }
+
+    #[test]
+    fn placeholders_are_replaced_with_the_subscriber_details() {
+        assert_eq!(
+            personalize(
+                "Hi {{name}} from {{ company }} ({{employees}} people), {{email}}",
+                &subscriber(),
+                "newsletter"
+            ),
+            "Hi le guin from Acme (12 people), ursula@example.com"
+        );
+    }
+
+    #[test]
+    fn fields_of_other_lists_are_not_used() {
+        assert_eq!(
+            personalize("See you in {{city}}!", &subscriber(), "newsletter"),
+            "See you in !"
+        );
+    }
+
+    #[test]
+    fn unterminated_placeholders_are_left_alone() {
+        assert_eq!(
+            personalize("{{name}} {{company", &subscriber(), "newsletter"),
+            "le guin {{company"
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use serde::Serialize;
+
+/// How often a subscriber wants to hear from us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SendingFrequency {
+    /// Every issue, as soon as it is sent.
+    Immediate,
+    /// A weekly digest.
+    Weekly,
+    /// A monthly digest.
+    Monthly,
+}
+
+impl SendingFrequency {
+    pub const ALL: [SendingFrequency; 3] = [Self::Immediate, Self::Weekly, Self::Monthly];
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|frequency| frequency.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "{} is not a supported frequency. Use `immediate`, `weekly` or `monthly`.",
+                    s
+                )
+            })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Immediate => "immediate",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+        }
+    }
+
+    /// A human-readable description, for the preference center.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Immediate => "Every issue",
+            Self::Weekly => "A weekly digest",
+            Self::Monthly => "A monthly digest",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SendingFrequency;
+    use claims::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn frequencies_round_trip_through_their_names() {
+        for frequency in SendingFrequency::ALL {
+            assert_ok_eq!(SendingFrequency::parse(frequency.as_str()), frequency);
+        }
+    }
+
+    #[test]
+    fn unknown_frequencies_are_rejected() {
+        assert_err!(SendingFrequency::parse("daily"));
+        assert_err!(SendingFrequency::parse("Weekly"));
+    }
+}
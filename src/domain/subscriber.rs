@@ -0,0 +1,257 @@
+use crate::domain::CustomFields;
+use uuid::Uuid;
+
+/// Where a subscriber is in their lifecycle.
+///
+/// The legal moves are:
+/// - pending → confirmed;
+/// - pending or confirmed → unsubscribed or bounced;
+/// - unsubscribed or bounced → pending, by signing up again;
+/// - anything but erased → complained (once) or erased.
+///
+/// Erasure is final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "subscriber_status", rename_all = "snake_case")]
+pub enum SubscriberStatus {
+    /// Signed up, but has not confirmed their address yet.
+    PendingConfirmation,
+    Confirmed,
+    /// Asked to stop receiving emails.
+    Unsubscribed,
+    /// Their address no longer accepts our emails.
+    Bounced,
+    /// Reported our emails as spam: we must never email them again.
+    Complained,
+    /// Asked for their personal data to be deleted.
+    Erased,
+}
+
+impl SubscriberStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PendingConfirmation => "pending_confirmation",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::Bounced => "bounced",
+            Self::Complained => "complained",
+            Self::Erased => "erased",
+        }
+    }
+
+    fn can_become(self, next: SubscriberStatus) -> bool {
+        use SubscriberStatus::*;
+        match (self, next) {
+            (PendingConfirmation, Confirmed) => true,
+            (PendingConfirmation | Confirmed, Unsubscribed | Bounced) => true,
+            (Unsubscribed | Bounced, PendingConfirmation) => true,
+            (Erased, _) => false,
+            (Complained, Complained) => false,
+            (_, Complained | Erased) => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for SubscriberStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("A subscriber cannot go from {from} to {to}.")]
+pub struct InvalidTransition {
+    pub from: SubscriberStatus,
+    pub to: SubscriberStatus,
+}
+
+/// A subscriber as stored. Their status can only change through the
+/// methods below, which refuse illegal moves.
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: SubscriberStatus,
+    custom_fields: CustomFields,
+}
+
+impl Subscriber {
+    /// Rebuilds a subscriber from storage, where their details were
+    /// validated when they were written. Only the subscriber repository
+    /// does this: handlers get their subscribers from it.
+    pub(crate) fn restore(
+        id: Uuid,
+        email: String,
+        name: String,
+        status: SubscriberStatus,
+        custom_fields: CustomFields,
+    ) -> Self {
+        Self {
+            id,
+            email,
+            name,
+            status,
+            custom_fields,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn status(&self) -> SubscriberStatus {
+        self.status
+    }
+
+    /// The custom field values they filled in, per mailing list.
+    pub fn custom_fields(&self) -> &CustomFields {
+        &self.custom_fields
+    }
+
+    /// Whether the subscriber can use their preference center.
+    pub fn can_manage_preferences(&self) -> bool {
+        matches!(
+            self.status,
+            SubscriberStatus::PendingConfirmation | SubscriberStatus::Confirmed
+        )
+    }
+
+    /// Whether the subscriber can move their subscription to a new address.
+    pub fn can_change_email(&self) -> bool {
+        self.status == SubscriberStatus::Confirmed
+    }
+
+    pub fn confirm(&mut self) -> Result<(), InvalidTransition> {
+        self.transition(SubscriberStatus::Confirmed)
+    }
+
+    /// Signing up again after leaving starts over with a confirmation.
+    pub fn resubscribe(&mut self) -> Result<(), InvalidTransition> {
+        self.transition(SubscriberStatus::PendingConfirmation)
+    }
+
+    pub fn unsubscribe(&mut self) -> Result<(), InvalidTransition> {
+        self.transition(SubscriberStatus::Unsubscribed)
+    }
+
+    pub fn record_bounce(&mut self) -> Result<(), InvalidTransition> {
+        self.transition(SubscriberStatus::Bounced)
+    }
+
+    pub fn record_complaint(&mut self) -> Result<(), InvalidTransition> {
+        self.transition(SubscriberStatus::Complained)
+    }
+
+    pub fn erase(&mut self) -> Result<(), InvalidTransition> {
+        self.transition(SubscriberStatus::Erased)
+    }
+
+    fn transition(&mut self, next: SubscriberStatus) -> Result<(), InvalidTransition> {
+        if !self.status.can_become(next) {
+            return Err(InvalidTransition {
+                from: self.status,
+                to: next,
+            });
+        }
+        self.status = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::subscriber::{InvalidTransition, Subscriber, SubscriberStatus};
+    use crate::domain::CustomFields;
+    use claims::{assert_err, assert_ok};
+    use uuid::Uuid;
+
+    fn subscriber(status: SubscriberStatus) -> Subscriber {
+        Subscriber::restore(
+            Uuid::new_v4(),
+            "ursula@example.com".into(),
+            "le guin".into(),
+            status,
+            CustomFields::default(),
+        )
+    }
+
+    #[test]
+    fn a_pending_subscriber_can_confirm_once() {
+        let mut subscriber = subscriber(SubscriberStatus::PendingConfirmation);
+        assert_ok!(subscriber.confirm());
+        assert_eq!(subscriber.status(), SubscriberStatus::Confirmed);
+        assert_eq!(
+            subscriber.confirm(),
+            Err(InvalidTransition {
+                from: SubscriberStatus::Confirmed,
+                to: SubscriberStatus::Confirmed
+            })
+        );
+    }
+
+    #[test]
+    fn leaving_subscribers_can_come_back_through_confirmation() {
+        for status in [SubscriberStatus::Unsubscribed, SubscriberStatus::Bounced] {
+            let mut subscriber = subscriber(status);
+            assert_err!(subscriber.confirm());
+            assert_ok!(subscriber.resubscribe());
+            assert_eq!(subscriber.status(), SubscriberStatus::PendingConfirmation);
+        }
+    }
+
+    #[test]
+    fn complaints_and_erasure_are_sticky() {
+        let mut complained = subscriber(SubscriberStatus::Complained);
+        assert_err!(complained.resubscribe());
+        assert_err!(complained.unsubscribe());
+        assert_err!(complained.record_complaint());
+        assert_ok!(complained.erase());
+
+        let mut erased = subscriber(SubscriberStatus::Erased);
+        assert_err!(erased.resubscribe());
+        assert_err!(erased.record_complaint());
+        assert_err!(erased.erase());
+        assert_eq!(erased.status(), SubscriberStatus::Erased);
+    }
+
+    #[test]
+    fn only_active_subscribers_can_leave() {
+        for status in [
+            SubscriberStatus::PendingConfirmation,
+            SubscriberStatus::Confirmed,
+        ] {
+            assert_ok!(subscriber(status).unsubscribe());
+            assert_ok!(subscriber(status).record_bounce());
+        }
+        for status in [
+            SubscriberStatus::Unsubscribed,
+            SubscriberStatus::Bounced,
+            SubscriberStatus::Complained,
+            SubscriberStatus::Erased,
+        ] {
+            assert_err!(subscriber(status).unsubscribe());
+            assert_err!(subscriber(status).record_bounce());
+        }
+    }
+
+    #[test]
+    fn permissions_follow_the_status() {
+        let pending = subscriber(SubscriberStatus::PendingConfirmation);
+        assert!(pending.can_manage_preferences());
+        assert!(!pending.can_change_email());
+        let confirmed = subscriber(SubscriberStatus::Confirmed);
+        assert!(confirmed.can_manage_preferences());
+        assert!(confirmed.can_change_email());
+        let unsubscribed = subscriber(SubscriberStatus::Unsubscribed);
+        assert!(!unsubscribed.can_manage_preferences());
+    }
+}
@@ -1,19 +1,139 @@
+use unicode_normalization::UnicodeNormalization;
 use validator::validate_email;
 
+/// Providers that deliver `local+tag@domain` to `local@domain`.
+const PLUS_TAG_PROVIDERS: [&str; 10] = [
+    "gmail.com",
+    "googlemail.com",
+    "outlook.com",
+    "hotmail.com",
+    "live.com",
+    "icloud.com",
+    "me.com",
+    "fastmail.com",
+    "protonmail.com",
+    "proton.me",
+];
+/// Providers that ignore dots in the local part.
+const DOT_INSENSITIVE_PROVIDERS: [&str; 2] = ["gmail.com", "googlemail.com"];
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SubscriberEmailError {
+    #[error("The email address must not be empty.")]
+    Empty,
+    #[error("{0} is not a valid email address.")]
+    Malformed(String),
+    #[error("Addresses at {0} are not accepted.")]
+    DisposableDomain(String),
+    #[error("Role accounts such as {0}@ are not accepted.")]
+    RoleAccount(String),
+    #[error(
+        "{domain} does not accept email.{}",
+        .suggestion.as_ref().map(|s| format!(" Did you mean {}?", s)).unwrap_or_default()
+    )]
+    Undeliverable {
+        domain: String,
+        /// A well-known domain the subscriber may have meant to type.
+        suggestion: Option<String>,
+    },
+}
+
+/// Optional rules applied on top of the canonical normalization.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EmailNormalization {
+    /// Fold provider-specific aliases, e.g. `u.r.s.u.l.a+news@gmail.com`
+    /// becomes `ursula@gmail.com`.
+    pub fold_provider_aliases: bool,
+}
+
 #[derive(Debug)]
-pub struct SubscriberEmail(String);
+pub struct SubscriberEmail {
+    /// The address as the subscriber typed it, which is what we send to.
+    display: String,
+    /// The canonical form, used to tell whether two addresses are the same.
+    normalized: String,
+}
 
 impl SubscriberEmail {
-    /// Returns an instance of `SubscriberName` if the input satisfies all
-    /// our validation constraints on subscriber names.
-    /// It panics otherwise.
-    pub fn parse(s: String) -> Result<SubscriberEmail, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
+    /// Returns an instance of `SubscriberEmail` if the input is a
+    /// syntactically valid email address.
+    pub fn parse(s: String) -> Result<SubscriberEmail, SubscriberEmailError> {
+        Self::parse_with(s, EmailNormalization::default())
+    }
+
+    /// Like [`SubscriberEmail::parse`], additionally applying `rules` to the
+    /// normalized form.
+    pub fn parse_with(
+        s: String,
+        rules: EmailNormalization,
+    ) -> Result<SubscriberEmail, SubscriberEmailError> {
+        if s.trim().is_empty() {
+            return Err(SubscriberEmailError::Empty);
+        }
+        if !is_valid_email(&s) {
+            return Err(SubscriberEmailError::Malformed(s));
+        }
+        match normalize(&s, rules) {
+            Some(normalized) => Ok(Self {
+                display: s,
+                normalized,
+            }),
+            None => Err(SubscriberEmailError::Malformed(s)),
+        }
+    }
+
+    /// The canonical form of the address: the local part in Unicode NFC and
+    /// lowercased, the domain lowercased and IDNA-encoded (punycode).
+    ///
+    /// Two addresses with the same normalized form reach the same mailbox.
+    pub fn normalized(&self) -> &str {
+        &self.normalized
+    }
+}
+
+/// `validate_email` only accepts ASCII local parts, but RFC 6531 also allows
+/// non-ASCII characters there (`josé@example.com`). We validate a copy of the
+/// address with an ASCII stand-in for each of them.
+fn is_valid_email(email: &str) -> bool {
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let mut stand_in = String::with_capacity(email.len());
+    for c in local.nfc() {
+        if c.is_ascii() {
+            stand_in.push(c);
+        } else if c.is_whitespace() || c.is_control() {
+            return false;
         } else {
-            Err(format!("{} is not a valid subscriber email.", s))
+            stand_in.push('a');
+        }
+    }
+    validate_email(format!("{}@{}", stand_in, domain))
+}
+
+/// Returns `None` if the domain cannot be IDNA-encoded.
+fn normalize(email: &str, rules: EmailNormalization) -> Option<String> {
+    let (local, domain) = email.rsplit_once('@')?;
+    let domain = idna::domain_to_ascii(&domain.to_lowercase()).ok()?;
+    let domain = domain.trim_end_matches('.');
+    // Local parts are case-sensitive on paper, but no provider we know of
+    // treats them that way.
+    let mut local: String = local.nfc().collect::<String>().to_lowercase();
+
+    let mut domain = domain.to_string();
+    if rules.fold_provider_aliases {
+        if PLUS_TAG_PROVIDERS.contains(&domain.as_str()) {
+            if let Some((untagged, _)) = local.split_once('+') {
+                local = untagged.to_string();
+            }
+        }
+        if DOT_INSENSITIVE_PROVIDERS.contains(&domain.as_str()) {
+            local.retain(|c| c != '.');
+            domain = "gmail.com".into();
         }
     }
+    Some(format!("{}@{}", local, domain))
 }
 
 impl AsRef<str> for SubscriberEmail {
@@ -21,13 +141,13 @@ impl AsRef<str> for SubscriberEmail {
         // The caller gets a shared reference to the inner string.
         // This gives the caller **read-only** access,
         // they have no way to compromise our invariants
-        &self.0
+        &self.display
     }
 }
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberEmail;
-    use claims::assert_err;
+    use crate::domain::{EmailNormalization, SubscriberEmail, SubscriberEmailError};
+    use claims::{assert_err, assert_ok};
     use fake::{faker::internet::en::SafeEmail, Fake};
     use rand::{rngs::StdRng, SeedableRng};
 
@@ -50,13 +170,19 @@ mod tests {
     #[test]
     fn empty_string_is_rejected() {
         let email = "".to_string();
-        assert_err!(SubscriberEmail::parse(email));
+        assert_eq!(
+            assert_err!(SubscriberEmail::parse(email)),
+            SubscriberEmailError::Empty
+        );
     }
 
     #[test]
     fn email_missing_at_symbol_is_rejected() {
         let email = "ursuladomain.com".to_string();
-        assert_err!(SubscriberEmail::parse(email));
+        assert_eq!(
+            assert_err!(SubscriberEmail::parse(email)),
+            SubscriberEmailError::Malformed("ursuladomain.com".into())
+        );
     }
 
     #[test]
@@ -64,4 +190,61 @@ mod tests {
         let email = "@domain.com".to_string();
         assert_err!(SubscriberEmail::parse(email));
     }
+
+    #[test]
+    fn the_display_form_is_preserved() {
+        let email = SubscriberEmail::parse("Ursula@Example.COM".into()).unwrap();
+        assert_eq!(email.as_ref(), "Ursula@Example.COM");
+    }
+
+    #[test]
+    fn addresses_differing_only_by_case_share_a_normalized_form() {
+        let a = SubscriberEmail::parse("Alice@Example.com".into()).unwrap();
+        let b = SubscriberEmail::parse("alice@example.com".into()).unwrap();
+        assert_eq!(a.normalized(), "alice@example.com");
+        assert_eq!(a.normalized(), b.normalized());
+    }
+
+    #[test]
+    fn internationalized_domains_are_punycode_encoded() {
+        let email = SubscriberEmail::parse("ursula@BÜCHER.example".into()).unwrap();
+        assert_eq!(email.normalized(), "ursula@xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn non_ascii_local_parts_are_accepted() {
+        assert_ok!(SubscriberEmail::parse("josé@example.com".into()));
+        assert_err!(SubscriberEmail::parse("jos\u{a0}é@example.com".into()));
+    }
+
+    #[test]
+    fn the_local_part_is_nfc_normalized() {
+        // `e` followed by a combining acute accent, rather than `é`
+        let decomposed = SubscriberEmail::parse("rene\u{301}@example.com".into()).unwrap();
+        let composed = SubscriberEmail::parse("ren\u{e9}@example.com".into()).unwrap();
+        assert_eq!(decomposed.normalized(), composed.normalized());
+    }
+
+    #[test]
+    fn provider_aliases_are_only_folded_when_enabled() {
+        let email = "U.r.s.u.l.a+news@googlemail.com".to_string();
+        let rules = EmailNormalization {
+            fold_provider_aliases: true,
+        };
+
+        let plain = SubscriberEmail::parse(email.clone()).unwrap();
+        let folded = SubscriberEmail::parse_with(email, rules).unwrap();
+
+        assert_eq!(plain.normalized(), "u.r.s.u.l.a+news@googlemail.com");
+        assert_eq!(folded.normalized(), "ursula@gmail.com");
+    }
+
+    #[test]
+    fn plus_tags_are_kept_for_unknown_providers() {
+        let rules = EmailNormalization {
+            fold_provider_aliases: true,
+        };
+        let email = SubscriberEmail::parse_with("ur.sula+news@example.com".into(), rules).unwrap();
+        assert_eq!(email.normalized(), "ur.sula+news@example.com");
+    }
 }
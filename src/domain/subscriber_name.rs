@@ -1,17 +1,35 @@
 use unicode_segmentation::UnicodeSegmentation; // provides `graphemes()` impl for `String` and `&str`
 
+/// The maximum length of a subscriber name, in graphemes.
+const MAX_LENGTH: usize = 256;
+const FORBIDDEN_CHARACTERS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SubscriberNameError {
+    #[error("The name must not be empty.")]
+    Empty,
+    #[error("The name must be at most {max} characters long.")]
+    TooLong { max: usize },
+    /// `position` is the zero-based index of the grapheme the offending
+    /// character is part of, so that it matches what the user sees.
+    #[error("The name must not contain `{character}` (found at position {position}).")]
+    ForbiddenCharacter { character: char, position: usize },
+}
+
 #[derive(Debug)]
 pub struct SubscriberName(String);
 
 impl SubscriberName {
     /// Returns an instance of `SubscriberName` if the input satisfies all
     /// our validation constraints on subscriber names.
-    /// It panics otherwise.
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
+    /// It returns the first constraint that was violated otherwise.
+    pub fn parse(s: String) -> Result<SubscriberName, SubscriberNameError> {
         // `.trim()` retuns a view over the input `s` without trailing
         // whitespace-like characters.
         // `.is_empty()` checks if the view contains any character.
-        let is_empty_or_whitespace = s.trim().is_empty();
+        if s.trim().is_empty() {
+            return Err(SubscriberNameError::Empty);
+        }
 
         // A grapheme is defined by the Unicode standard as a "user-perceived"
         // character: `å` is a single grapheme, but it is composed of two characters
@@ -20,18 +38,29 @@ impl SubscriberName {
         // `graphemes` returns an iterator over the graphemes in the input `s`.
         // `true` specifies that we want to use the extended grapheme definition set,
         // the recommended one.
-        let is_too_long = s.graphemes(true).count() > 256;
-
-        // Iterator over all characters in the input `s` to check if any of them
-        // matches one of the characters in the forbidden array.
-        let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contains_forbidden_characters = s.chars().any(|g| forbidden_characters.contains(&g));
+        if s.graphemes(true).count() > MAX_LENGTH {
+            return Err(SubscriberNameError::TooLong { max: MAX_LENGTH });
+        }
 
-        if is_empty_or_whitespace || is_too_long || contains_forbidden_characters {
-            Err(format!("{} is not a valid subscriber name.", s))
-        } else {
-            Ok(Self(s))
+        // Iterator over all graphemes in the input `s` to find the first one
+        // that contains one of the characters in the forbidden array.
+        if let Some((position, character)) =
+            s.graphemes(true)
+                .enumerate()
+                .find_map(|(position, grapheme)| {
+                    grapheme
+                        .chars()
+                        .find(|c| FORBIDDEN_CHARACTERS.contains(c))
+                        .map(|character| (position, character))
+                })
+        {
+            return Err(SubscriberNameError::ForbiddenCharacter {
+                character,
+                position,
+            });
         }
+
+        Ok(Self(s))
     }
 }
 
@@ -45,7 +74,7 @@ impl AsRef<str> for SubscriberName {
 }
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberName;
+    use crate::domain::{SubscriberName, SubscriberNameError};
     use claims::{assert_err, assert_ok};
 
     #[test]
@@ -57,13 +86,19 @@ mod tests {
     #[test]
     fn a_name_longer_than_256_graphemes_is_rejected() {
         let name = "a".repeat(257);
-        assert_err!(SubscriberName::parse(name));
+        assert_eq!(
+            assert_err!(SubscriberName::parse(name)),
+            SubscriberNameError::TooLong { max: 256 }
+        );
     }
 
     #[test]
     fn whitespace_only_names_are_rejected() {
         let name = " ".to_string();
-        assert_err!(SubscriberName::parse(name));
+        assert_eq!(
+            assert_err!(SubscriberName::parse(name)),
+            SubscriberNameError::Empty
+        );
     }
 
     #[test]
@@ -80,6 +115,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn the_position_of_the_first_forbidden_character_is_reported() {
+        // `é` is written as `e` followed by a combining accent: two
+        // characters, but a single grapheme.
+        let name = "Rene\u{301}e (Le Guin)".to_string();
+        assert_eq!(
+            assert_err!(SubscriberName::parse(name)),
+            SubscriberNameError::ForbiddenCharacter {
+                character: '(',
+                position: 6
+            }
+        );
+    }
+
     #[test]
     fn a_valid_name_is_parsed_successfully() {
         let name = "Ursula Le Guin".to_string();
@@ -1,19 +1,20 @@
 use crate::domain::SubscriberEmail;
+use crate::secrets::SecretSource;
 use reqwest::Client;
-use secrecy::{ExposeSecret, Secret};
+use secrecy::ExposeSecret;
 
 pub struct EmailClient {
     http_client: Client,
     base_url: String,
     sender: SubscriberEmail,
-    api_key: Secret<String>,
+    api_key: SecretSource,
 }
 
 impl EmailClient {
     pub fn new(
         base_url: String,
         sender: SubscriberEmail,
-        api_key: Secret<String>,
+        api_key: impl Into<SecretSource>,
         timeout: std::time::Duration,
     ) -> Self {
         let http_client = Client::builder().timeout(timeout).build().unwrap();
@@ -21,7 +22,7 @@ impl EmailClient {
             http_client,
             base_url,
             sender,
-            api_key,
+            api_key: api_key.into(),
         }
     }
 
@@ -33,8 +34,9 @@ impl EmailClient {
         text_content: &str,
     ) -> Result<(), reqwest::Error> {
         let url = format!("{}/api/1.0/messages/send", &self.base_url);
+        let api_key = self.api_key.current();
         let request_body = SendEmailRequest {
-            key: self.api_key.expose_secret(),
+            key: api_key.expose_secret(),
             message: &EmailMessage {
                 from_email: self.sender.as_ref(),
                 to: vec![EmailRecipient {
@@ -0,0 +1,36 @@
+use axum::http::StatusCode;
+use std::fmt;
+
+/// Writes `e` followed by every error in its `source()` chain.
+///
+/// Error types use this as their `Debug` implementation, so that logging an
+/// error with `{:?}` captures the whole chain.
+pub fn error_chain_fmt(e: &impl std::error::Error, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+/// Logs an error that is about to be turned into a response with `status`.
+///
+/// Handlers propagate errors up to their `IntoResponse` implementation and
+/// log them there, once, instead of at every layer they pass through.
+pub fn log_error(e: &impl std::error::Error, status: StatusCode) {
+    if status.is_server_error() {
+        tracing::error!(error.cause_chain = ?e, error.message = %e, "Request failed");
+    } else {
+        tracing::info!(error.cause_chain = ?e, error.message = %e, "Request rejected");
+    }
+}
+
+/// Whether a query failed because it would have duplicated a unique value.
+pub fn is_unique_violation(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("23505"),
+        _ => false,
+    }
+}
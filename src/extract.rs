@@ -0,0 +1,264 @@
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::{FromRequest, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    response::{IntoResponse, Response},
+    BoxError, Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::convert::Infallible;
+
+/// The format a client wants error responses rendered in.
+///
+/// Clients that send JSON, or that list `application/json` in their `Accept`
+/// header, get JSON; everybody else gets plain text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    Text,
+}
+
+impl ResponseFormat {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header_contains = |name, needle| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.contains(needle))
+                .unwrap_or(false)
+        };
+        if header_contains(header::CONTENT_TYPE, "application/json")
+            || header_contains(header::ACCEPT, "application/json")
+        {
+            Self::Json
+        } else {
+            Self::Text
+        }
+    }
+
+    /// Renders an error message with the given status code.
+    pub fn error(self, status: StatusCode, message: &str) -> Response {
+        match self {
+            Self::Json => (status, Json(serde_json::json!({ "error": message }))).into_response(),
+            Self::Text => (status, message.to_string()).into_response(),
+        }
+    }
+
+    /// Renders a 400 listing every field that failed validation.
+    ///
+    /// As JSON, this is the same shape as [`ResponseFormat::error`] with an
+    /// extra `fields` array; as text, one `field: message` line per error.
+    pub fn field_errors(self, errors: &[FieldError]) -> Response {
+        let message = "The submitted data is invalid.";
+        match self {
+            Self::Json => (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": message, "fields": errors })),
+            )
+                .into_response(),
+            Self::Text => {
+                let lines: Vec<_> = errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect();
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("{}\n{}", message, lines.join("\n")),
+                )
+                    .into_response()
+            }
+        }
+    }
+}
+
+/// A validation failure for a single submitted field.
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    /// A stable, machine-readable code such as `too_long`.
+    pub reason: &'static str,
+    /// A human-readable description of the problem.
+    pub message: String,
+    /// For `forbidden_character`, the position of the offending character, in graphemes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    /// For `undeliverable_domain`, the domain the subscriber probably meant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ResponseFormat
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(&parts.headers))
+    }
+}
+
+/// Deserializes the request body as either `application/json` or
+/// `application/x-www-form-urlencoded`, depending on its `Content-Type`.
+///
+/// Like any body extractor it MUST be the last argument of a handler.
+/// Rejections are rendered in the client's [`ResponseFormat`]; a body that
+/// cannot be deserialized into `T` is a 400, listing the offending field as a
+/// [`FieldError`] when there is one (e.g. a missing field).
+pub struct FormOrJson<T>(pub T);
+
+/// Why a body could not be deserialized.
+enum BodyError {
+    /// A field is missing or holds the wrong kind of value.
+    Field(FieldError),
+    /// The body is malformed, or the problem is not down to a single field.
+    Malformed(String),
+}
+
+impl BodyError {
+    fn new<E: std::fmt::Display>(error: serde_path_to_error::Error<E>, kind: &str) -> Self {
+        let path = error.path().to_string();
+        let message = error.inner().to_string();
+        // serde_json appends the position to its messages.
+        let message = match message.rfind(" at line ") {
+            Some(position) => message[..position].to_string(),
+            None => message,
+        };
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split('`').next());
+        let required = || "This field is required.".to_string();
+        let (field, reason, message) = match (missing, path.as_str()) {
+            (Some(field), ".") => (field.to_string(), "missing", required()),
+            (Some(field), path) => (format!("{}.{}", path, field), "missing", required()),
+            (None, ".") => {
+                return Self::Malformed(format!("Failed to deserialize the {}: {}", kind, message))
+            }
+            (None, path) => (path.to_string(), "invalid_value", message),
+        };
+        Self::Field(FieldError {
+            field,
+            reason,
+            message,
+            position: None,
+            suggestion: None,
+        })
+    }
+}
+
+fn deserialize_json<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BodyError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        if e.inner().classify() == serde_json::error::Category::Data {
+            BodyError::new(e, "JSON body")
+        } else {
+            BodyError::Malformed(format!("Failed to parse the JSON body: {}", e.inner()))
+        }
+    })?;
+    deserializer
+        .end()
+        .map_err(|e| BodyError::Malformed(format!("Failed to parse the JSON body: {}", e)))?;
+    Ok(value)
+}
+
+fn deserialize_form<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BodyError> {
+    let deserializer = serde_urlencoded::Deserializer::new(form_urlencoded::parse(bytes));
+    serde_path_to_error::deserialize(deserializer).map_err(|e| BodyError::new(e, "form body"))
+}
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for FormOrJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let format = ResponseFormat::from_headers(req.headers());
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        let deserialize = if content_type.starts_with("application/json") {
+            deserialize_json::<T>
+        } else if content_type.starts_with("application/x-www-form-urlencoded") {
+            deserialize_form::<T>
+        } else {
+            return Err(format.error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected request with `Content-Type: application/json` or \
+                `Content-Type: application/x-www-form-urlencoded`",
+            ));
+        };
+        let bytes = Bytes::from_request(req, state).await.map_err(|rejection| {
+            let status = match rejection.status() {
+                StatusCode::PAYLOAD_TOO_LARGE => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            format.error(status, &rejection.body_text())
+        })?;
+
+        deserialize(&bytes)
+            .map(FormOrJson)
+            .map_err(|error| match error {
+                BodyError::Field(error) => format.field_errors(&[error]),
+                BodyError::Malformed(message) => format.error(StatusCode::BAD_REQUEST, &message),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extract::{deserialize_form, deserialize_json, BodyError};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Data {
+        name: String,
+        #[serde(default)]
+        subscribed: bool,
+        #[serde(flatten)]
+        extra: HashMap<String, String>,
+    }
+
+    fn field_error(error: BodyError) -> (String, &'static str) {
+        match error {
+            BodyError::Field(e) => (e.field, e.reason),
+            BodyError::Malformed(message) => panic!("Not a field error: {}", message),
+        }
+    }
+
+    #[test]
+    fn missing_fields_are_reported_by_name() {
+        let json = deserialize_json::<Data>(br#"{"subscribed": true}"#).unwrap_err();
+        assert_eq!(field_error(json), ("name".to_string(), "missing"));
+        let form = deserialize_form::<Data>(b"subscribed=true").unwrap_err();
+        assert_eq!(field_error(form), ("name".to_string(), "missing"));
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_reported_by_field() {
+        let json = deserialize_json::<Data>(br#"{"name": 12}"#).unwrap_err();
+        assert_eq!(field_error(json), ("name".to_string(), "invalid_value"));
+    }
+
+    #[test]
+    fn malformed_bodies_are_not_field_errors() {
+        for body in [&b"{"[..], b"[]", br#"{"name": "a"} trailing"#] {
+            assert!(matches!(
+                deserialize_json::<Data>(body),
+                Err(BodyError::Malformed(_))
+            ));
+        }
+    }
+}
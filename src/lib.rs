@@ -1,6 +1,22 @@
+pub mod audit;
+pub mod authentication;
+pub mod bot_protection;
+pub mod cleanup_worker;
+pub mod cli;
 pub mod configuration;
+pub mod database;
+pub mod dns;
 pub mod domain;
 pub mod email_client;
+pub mod error;
+pub mod extract;
+pub mod metrics;
+pub mod rate_limit;
+pub mod reload;
 pub mod routes;
+pub mod secrets;
+pub mod shutdown;
+pub mod signed_links;
 pub mod startup;
+pub mod subscriber_repository;
 pub mod telemetry;
@@ -1,13 +1,22 @@
-use zero2prod_axum::configuration::get_configuration;
-use zero2prod_axum::startup::build;
-use zero2prod_axum::telemetry::{get_subscriber, init_subscriber};
+use anyhow::Context;
+use clap::Parser;
+use zero2prod_axum::cli::Cli;
+use zero2prod_axum::telemetry::{flush_telemetry, get_subscriber, init_subscriber};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let subscriber = get_subscriber("zero2prod_axum".into(), "info".into(), std::io::stdout);
+async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let configuration = cli
+        .configuration()
+        .context("Failed to read configuration.")?;
+    let subscriber = get_subscriber(
+        "zero2prod_axum".into(),
+        configuration.application.log_level.clone(),
+        std::io::stdout,
+    );
     init_subscriber(subscriber);
 
-    let configuration = get_configuration().expect("Failed to read configuration.");
-    build(configuration).await?;
-    Ok(())
+    let result = cli.run(configuration).await;
+    flush_telemetry();
+    result
 }
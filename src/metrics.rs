@@ -0,0 +1,58 @@
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, Encoder, IntCounter, TextEncoder};
+
+pub static REMINDERS_SENT: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "confirmation_reminders_sent_total",
+        "Reminders sent to subscribers who had not confirmed their address."
+    )
+    .unwrap()
+});
+
+pub static REMINDERS_FAILED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "confirmation_reminders_failed_total",
+        "Reminders that could not be sent."
+    )
+    .unwrap()
+});
+
+pub static UNCONFIRMED_SUBSCRIBERS_PURGED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "unconfirmed_subscribers_purged_total",
+        "Subscribers deleted because they never confirmed their address."
+    )
+    .unwrap()
+});
+
+pub static CONFIRMATION_TOKENS_EXPIRED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "confirmation_tokens_expired_total",
+        "Confirmation tokens deleted because they expired."
+    )
+    .unwrap()
+});
+
+pub static EMAIL_CHANGE_REQUESTS_EXPIRED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "email_change_requests_expired_total",
+        "Email change requests deleted because they were never verified in time."
+    )
+    .unwrap()
+});
+
+/// Every registered metric, in the Prometheus text exposition format.
+pub fn render() -> String {
+    // Make sure counters show up before they are first incremented.
+    Lazy::force(&REMINDERS_SENT);
+    Lazy::force(&REMINDERS_FAILED);
+    Lazy::force(&UNCONFIRMED_SUBSCRIBERS_PURGED);
+    Lazy::force(&CONFIRMATION_TOKENS_EXPIRED);
+    Lazy::force(&EMAIL_CHANGE_REQUESTS_EXPIRED);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Failed to encode metrics.");
+    String::from_utf8(buffer).expect("Metrics are valid UTF-8.")
+}
@@ -0,0 +1,433 @@
+use crate::database::ConnectionPool;
+use crate::extract::ResponseFormat;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket: up to `capacity` requests in a burst, then one more every
+/// `refill_interval`.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    pub capacity: u32,
+    pub refill_interval: Duration,
+}
+
+impl TokenBucket {
+    fn refill_per_second(&self) -> f64 {
+        1.0 / self.refill_interval.as_secs_f64()
+    }
+
+    /// How long an unused bucket takes to fill up from empty.
+    fn refill_time(&self) -> Duration {
+        self.refill_interval * self.capacity
+    }
+
+    /// How long until a bucket holding `tokens` has a whole token again, to
+    /// the millisecond.
+    fn retry_after(&self, tokens: f64) -> Duration {
+        let millis = (1.0 - tokens) * self.refill_interval.as_millis() as f64;
+        Duration::from_millis(millis.max(0.0).round() as u64)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Where bucket levels are kept.
+#[async_trait::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket identified by `key`, creating it full if
+    /// it does not exist yet.
+    async fn acquire(&self, key: &str, bucket: &TokenBucket) -> Result<Decision, anyhow::Error>;
+
+    /// Forgets the buckets that have not been used for `idle_for`, returning
+    /// how many there were.
+    async fn evict_idle(&self, idle_for: Duration) -> Result<u64, anyhow::Error>;
+}
+
+/// Keeps buckets in memory: limits apply per application instance.
+///
+/// Buckets are only removed by [`RateLimitStore::evict_idle`], which the
+/// background worker calls periodically.
+#[derive(Default)]
+pub struct InMemoryStore {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl InMemoryStore {
+    fn acquire_at(&self, key: &str, bucket: &TokenBucket, now: Instant) -> Decision {
+        let capacity = f64::from(bucket.capacity);
+        let refill = |(tokens, updated_at): (f64, Instant)| {
+            let elapsed = now.saturating_duration_since(updated_at).as_secs_f64();
+            (tokens + elapsed * bucket.refill_per_second()).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let tokens = buckets
+            .get(key)
+            .map(|state| refill(*state))
+            .unwrap_or(capacity);
+        if tokens < 1.0 {
+            return Decision::Limited {
+                retry_after: bucket.retry_after(tokens),
+            };
+        }
+        buckets.insert(key.to_string(), (tokens - 1.0, now));
+        Decision::Allowed
+    }
+
+    fn evict_idle_at(&self, idle_for: Duration, now: Instant) -> u64 {
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, (_, updated_at)| now.saturating_duration_since(*updated_at) < idle_for);
+        (before - buckets.len()) as u64
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for InMemoryStore {
+    async fn acquire(&self, key: &str, bucket: &TokenBucket) -> Result<Decision, anyhow::Error> {
+        Ok(self.acquire_at(key, bucket, Instant::now()))
+    }
+
+    async fn evict_idle(&self, idle_for: Duration) -> Result<u64, anyhow::Error> {
+        Ok(self.evict_idle_at(idle_for, Instant::now()))
+    }
+}
+
+/// Keeps buckets in Postgres, so that limits are shared by every instance.
+///
+/// Time is measured by the database clock, which all instances agree on.
+pub struct PostgresStore {
+    pool: ConnectionPool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: ConnectionPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for PostgresStore {
+    #[tracing::instrument(name = "Acquiring a rate limit token", skip(self, bucket))]
+    async fn acquire(&self, key: &str, bucket: &TokenBucket) -> Result<Decision, anyhow::Error> {
+        let capacity = f64::from(bucket.capacity);
+        // The update only happens if, once refilled, the bucket holds at
+        // least one token; the row lock makes concurrent requests queue up.
+        let acquired = sqlx::query!(
+            r#"
+            INSERT INTO rate_limit_buckets (key, tokens, updated_at)
+            VALUES ($1, $2::float8 - 1, now())
+            ON CONFLICT (key) DO UPDATE
+            SET
+                tokens = LEAST(
+                    $2,
+                    rate_limit_buckets.tokens
+                        + EXTRACT(EPOCH FROM now() - rate_limit_buckets.updated_at)::float8 * $3
+                ) - 1,
+                updated_at = now()
+            WHERE LEAST(
+                $2,
+                rate_limit_buckets.tokens
+                    + EXTRACT(EPOCH FROM now() - rate_limit_buckets.updated_at)::float8 * $3
+            ) >= 1
+            RETURNING tokens
+            "#,
+            key,
+            capacity,
+            bucket.refill_per_second(),
+        )
+        .fetch_optional(&self.pool.get())
+        .await?;
+        if acquired.is_some() {
+            return Ok(Decision::Allowed);
+        }
+
+        let current = sqlx::query!(
+            r#"
+            SELECT
+                tokens + EXTRACT(EPOCH FROM now() - updated_at)::float8 * $2 AS "tokens!"
+            FROM rate_limit_buckets
+            WHERE key = $1
+            "#,
+            key,
+            bucket.refill_per_second(),
+        )
+        .fetch_one(&self.pool.get())
+        .await?;
+        Ok(Decision::Limited {
+            retry_after: bucket.retry_after(current.tokens),
+        })
+    }
+
+    #[tracing::instrument(name = "Evicting idle rate limit buckets", skip(self))]
+    async fn evict_idle(&self, idle_for: Duration) -> Result<u64, anyhow::Error> {
+        let evicted = sqlx::query!(
+            "DELETE FROM rate_limit_buckets WHERE updated_at < now() - make_interval(secs => $1)",
+            idle_for.as_secs_f64(),
+        )
+        .execute(&self.pool.get())
+        .await?;
+        Ok(evicted.rows_affected())
+    }
+}
+
+/// A request was refused because a client went over its limit.
+#[derive(Debug, thiserror::Error)]
+#[error("Too many requests. Please try again later.")]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl RateLimited {
+    /// A 429 telling the client, in whole seconds, when to try again.
+    pub fn response(&self, format: ResponseFormat) -> Response {
+        let mut seconds = self.retry_after.as_secs();
+        if self.retry_after.subsec_nanos() > 0 {
+            seconds += 1;
+        }
+        let mut response = format.error(StatusCode::TOO_MANY_REQUESTS, &self.to_string());
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        response
+    }
+}
+
+/// Limits requests per client IP and per target email address.
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Option<Arc<dyn RateLimitStore>>,
+    per_ip: TokenBucket,
+    per_email: TokenBucket,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimiter {
+    pub fn new(
+        store: Arc<dyn RateLimitStore>,
+        per_ip: TokenBucket,
+        per_email: TokenBucket,
+        trust_forwarded_for: bool,
+    ) -> Self {
+        Self {
+            store: Some(store),
+            per_ip,
+            per_email,
+            trust_forwarded_for,
+        }
+    }
+
+    /// A limiter that lets every request through.
+    pub fn disabled() -> Self {
+        let unused = TokenBucket {
+            capacity: 0,
+            refill_interval: Duration::from_secs(1),
+        };
+        Self {
+            store: None,
+            per_ip: unused,
+            per_email: unused,
+            trust_forwarded_for: false,
+        }
+    }
+
+    /// Where requests are counted; `None` if the limiter is disabled.
+    pub fn store(&self) -> Option<Arc<dyn RateLimitStore>> {
+        self.store.clone()
+    }
+
+    /// Forgets the buckets that have filled up again: a full bucket is the
+    /// same as none at all.
+    pub async fn evict_full_buckets(&self) -> Result<u64, anyhow::Error> {
+        match &self.store {
+            Some(store) => {
+                let refill_time = self.per_ip.refill_time().max(self.per_email.refill_time());
+                store.evict_idle(refill_time).await
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// The address of the client that made the request.
+    ///
+    /// Behind a reverse proxy every request comes from the proxy, so with
+    /// `trust_forwarded_for` set we use the last address in
+    /// `X-Forwarded-For`, which the proxy appended. Earlier entries are set
+    /// by the client and cannot be trusted.
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        if self.trust_forwarded_for {
+            let forwarded = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok());
+            if let Some(ip) = forwarded {
+                return ip;
+            }
+        }
+        peer.ip()
+    }
+
+    pub async fn check_ip(&self, ip: IpAddr) -> Result<(), RateLimited> {
+        self.check(&format!("ip:{}", ip), &self.per_ip).await
+    }
+
+    /// `email` should be normalized, so that variants of an address share a
+    /// limit.
+    pub async fn check_email(&self, email: &str) -> Result<(), RateLimited> {
+        self.check(&format!("email:{}", email), &self.per_email)
+            .await
+    }
+
+    /// If the store fails, the request is let through: we would rather
+    /// accept a few extra requests than refuse every one of them.
+    async fn check(&self, key: &str, bucket: &TokenBucket) -> Result<(), RateLimited> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        match store.acquire(key, bucket).await {
+            Ok(Decision::Allowed) => Ok(()),
+            Ok(Decision::Limited { retry_after }) => Err(RateLimited { retry_after }),
+            Err(e) => {
+                tracing::warn!(error.cause_chain = ?e, "Failed to check a rate limit");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The [`RateLimiter`] along with the address of the client that made the
+/// request.
+pub struct ClientRateLimit {
+    limiter: RateLimiter,
+    client_ip: IpAddr,
+}
+
+impl ClientRateLimit {
+    pub fn new(limiter: RateLimiter, client_ip: IpAddr) -> Self {
+        Self { limiter, client_ip }
+    }
+
+    pub async fn check_ip(&self) -> Result<(), RateLimited> {
+        self.limiter.check_ip(self.client_ip).await
+    }
+
+    pub async fn check_email(&self, email: &str) -> Result<(), RateLimited> {
+        self.limiter.check_email(email).await
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientRateLimit
+where
+    RateLimiter: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let limiter = RateLimiter::from_ref(state);
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let client_ip = limiter.client_ip(peer, &parts.headers);
+        Ok(Self { limiter, client_ip })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::{Decision, InMemoryStore, RateLimiter, TokenBucket};
+    use axum::http::HeaderMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn bucket() -> TokenBucket {
+        TokenBucket {
+            capacity: 2,
+            refill_interval: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_up_to_its_capacity() {
+        let store = InMemoryStore::default();
+        let now = Instant::now();
+
+        assert_eq!(store.acquire_at("a", &bucket(), now), Decision::Allowed);
+        assert_eq!(store.acquire_at("a", &bucket(), now), Decision::Allowed);
+        assert_eq!(
+            store.acquire_at("a", &bucket(), now),
+            Decision::Limited {
+                retry_after: Duration::from_secs(10)
+            }
+        );
+        // Buckets are independent
+        assert_eq!(store.acquire_at("b", &bucket(), now), Decision::Allowed);
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time() {
+        let store = InMemoryStore::default();
+        let start = Instant::now();
+        store.acquire_at("a", &bucket(), start);
+        store.acquire_at("a", &bucket(), start);
+
+        assert_eq!(
+            store.acquire_at("a", &bucket(), start + Duration::from_secs(4)),
+            Decision::Limited {
+                retry_after: Duration::from_secs(6)
+            }
+        );
+        assert_eq!(
+            store.acquire_at("a", &bucket(), start + Duration::from_secs(10)),
+            Decision::Allowed
+        );
+    }
+
+    #[test]
+    fn idle_buckets_are_evicted() {
+        let store = InMemoryStore::default();
+        let start = Instant::now();
+        store.acquire_at("a", &bucket(), start);
+        store.acquire_at("b", &bucket(), start + Duration::from_secs(15));
+
+        let evicted = store.evict_idle_at(bucket().refill_time(), start + Duration::from_secs(20));
+
+        assert_eq!(evicted, 1);
+        let buckets = store.buckets.lock().unwrap();
+        assert!(buckets.contains_key("b") && !buckets.contains_key("a"));
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_used_when_trusted() {
+        let peer = "10.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        let store = Arc::new(InMemoryStore::default());
+
+        let trusting = RateLimiter::new(store.clone(), bucket(), bucket(), true);
+        let distrustful = RateLimiter::new(store, bucket(), bucket(), false);
+
+        assert_eq!(
+            trusting.client_ip(peer, &headers),
+            "2.2.2.2".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(distrustful.client_ip(peer, &headers), peer.ip());
+    }
+}
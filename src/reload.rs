@@ -0,0 +1,290 @@
+use crate::configuration::{ConfigurationError, Settings};
+use crate::domain::SubscriberEmailError;
+use crate::error::error_chain_fmt;
+use crate::shutdown::Shutdown;
+use crate::startup::{AppState, ReloadableServices};
+use crate::telemetry::set_log_filter;
+use secrecy::ExposeSecret;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A value that can be replaced while the application runs. Readers take a
+/// snapshot with [`Reloadable::get`], which stays valid for as long as they
+/// hold on to it.
+pub struct Reloadable<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Every clone sees the new value.
+    pub fn replace(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ReloadError {
+    #[error("Failed to load the configuration.")]
+    Load(#[from] ConfigurationError),
+    #[error("Changing {} requires a restart.", .0.join(", "))]
+    RestartRequired(Vec<String>),
+    #[error("The configured sender email address is invalid.")]
+    InvalidSenderEmail(#[source] SubscriberEmailError),
+    #[error("Failed to change the log filter.")]
+    LogFilter(#[source] anyhow::Error),
+}
+
+impl std::fmt::Debug for ReloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Whether a change to `key` can be applied without a restart.
+fn is_reloadable(key: &str) -> bool {
+    key == "application.log_level"
+        || is_database_password(key)
+        || key.starts_with("email_client.")
+        || (key.starts_with("rate_limit.") && key != "rate_limit.storage")
+}
+
+fn is_database_password(key: &str) -> bool {
+    key == "database.password" || key == "database.password_file"
+}
+
+/// Applies configuration changes to a running application.
+pub struct Reloader {
+    current: Settings,
+    state: AppState,
+}
+
+impl Reloader {
+    pub fn new(current: Settings, state: AppState) -> Self {
+        Self { current, state }
+    }
+
+    /// Swaps in the services, the log filter and the database password from
+    /// `new`, returning the keys that changed. Nothing is applied if a
+    /// setting that needs a restart has changed, or if anything fails.
+    pub fn apply(&mut self, new: Settings) -> Result<Vec<String>, ReloadError> {
+        let changed = changed_keys(&self.current, &new);
+        let restart_required: Vec<String> = changed
+            .iter()
+            .filter(|key| !is_reloadable(key))
+            .cloned()
+            .collect();
+        if !restart_required.is_empty() {
+            return Err(ReloadError::RestartRequired(restart_required));
+        }
+
+        let store = self
+            .state
+            .services
+            .get()
+            .rate_limiter
+            .store()
+            .unwrap_or_else(|| new.rate_limit.store(self.state.pool.clone()));
+        let services =
+            ReloadableServices::new(&new, store).map_err(ReloadError::InvalidSenderEmail)?;
+        if new.application.log_level != self.current.application.log_level {
+            set_log_filter(&new.application.log_level).map_err(ReloadError::LogFilter)?;
+        }
+
+        if changed.iter().any(|key| is_database_password(key)) {
+            self.state.pool.set_password(new.database.password_source());
+        }
+        self.state.services.replace(services);
+        self.current = new;
+        Ok(changed)
+    }
+}
+
+/// The keys whose value differs between `old` and `new`, sorted.
+fn changed_keys(old: &Settings, new: &Settings) -> Vec<String> {
+    let old_values: HashMap<String, serde_json::Value> = old
+        .describe()
+        .into_iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect();
+    let mut changed: Vec<String> = new
+        .describe()
+        .into_iter()
+        .filter(|entry| old_values.get(&entry.key) != Some(&entry.value))
+        .map(|entry| entry.key)
+        .collect();
+    // `describe` redacts secrets, so they have to be compared separately.
+    let secrets = [
+        (
+            "application.hmac_secret",
+            &old.application.hmac_secret,
+            &new.application.hmac_secret,
+        ),
+        (
+            "bot_protection.hmac_secret",
+            &old.bot_protection.hmac_secret,
+            &new.bot_protection.hmac_secret,
+        ),
+        (
+            "database.password",
+            &old.database.password,
+            &new.database.password,
+        ),
+        (
+            "email_client.api_key",
+            &old.email_client.api_key,
+            &new.email_client.api_key,
+        ),
+    ];
+    for (key, old, new) in secrets {
+        if old.expose_secret() != new.expose_secret() {
+            changed.push(key.to_string());
+        }
+    }
+    changed.sort();
+    changed
+}
+
+/// Reloads the configuration with `load` on every SIGHUP, until `shutdown`
+/// is requested. A reload that fails is logged and leaves the running
+/// configuration as it was.
+pub async fn reload_on_hangup(
+    mut reloader: Reloader,
+    load: impl Fn() -> Result<Settings, ConfigurationError>,
+    mut shutdown: Shutdown,
+) {
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!(error.message = %e, "Failed to listen for SIGHUP");
+            return;
+        }
+    };
+    loop {
+        #[cfg(unix)]
+        let received = hangup.recv();
+        #[cfg(not(unix))]
+        let received = std::future::pending::<Option<()>>();
+        tokio::select! {
+            received = received => {
+                if received.is_none() {
+                    return;
+                }
+            }
+            () = shutdown.requested() => return,
+        }
+
+        tracing::info!("Received SIGHUP: reloading the configuration");
+        match load()
+            .map_err(ReloadError::from)
+            .and_then(|settings| reloader.apply(settings))
+        {
+            Ok(changed) => tracing::info!(changed = ?changed, "Reloaded the configuration"),
+            Err(e) => tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Kept the running configuration"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::local_configuration;
+    use crate::reload::{ReloadError, Reloadable, Reloader};
+    use crate::startup::{get_connection_pool, AppState};
+
+    fn reloader() -> Reloader {
+        let configuration = local_configuration();
+        let state =
+            AppState::new(&configuration, get_connection_pool(&configuration.database)).unwrap();
+        Reloader::new(configuration, state)
+    }
+
+    #[test]
+    fn every_clone_sees_a_replaced_value() {
+        let value = Reloadable::new(1);
+        let clone = value.clone();
+        let snapshot = value.get();
+
+        clone.replace(2);
+
+        assert_eq!(*value.get(), 2);
+        assert_eq!(*snapshot, 1);
+    }
+
+    #[tokio::test]
+    async fn reloadable_settings_are_swapped_in() {
+        let mut reloader = reloader();
+        let state = reloader.state.clone();
+        let mut configuration = local_configuration();
+        configuration.email_client.timeout_milliseconds = 500;
+        configuration.rate_limit.per_ip.capacity += 1;
+
+        let changed = reloader.apply(configuration).unwrap();
+
+        assert_eq!(
+            changed,
+            vec![
+                "email_client.timeout_milliseconds",
+                "rate_limit.per_ip.capacity"
+            ]
+        );
+        let entry = |key: &str| {
+            state
+                .services
+                .get()
+                .configuration
+                .iter()
+                .find(|entry| entry.key == key)
+                .unwrap()
+                .value
+                .clone()
+        };
+        assert_eq!(entry("email_client.timeout_milliseconds"), 500);
+    }
+
+    #[tokio::test]
+    async fn a_new_database_password_does_not_need_a_restart() {
+        let mut reloader = reloader();
+        let mut configuration = local_configuration();
+        configuration.database.password = secrecy::Secret::new("rotated".into());
+
+        let changed = reloader.apply(configuration).unwrap();
+
+        assert_eq!(changed, vec!["database.password"]);
+    }
+
+    #[tokio::test]
+    async fn a_reload_that_needs_a_restart_is_refused() {
+        let mut reloader = reloader();
+        let mut configuration = local_configuration();
+        configuration.email_client.timeout_milliseconds = 500;
+        configuration.application.port += 1;
+        configuration.database.host = "elsewhere".into();
+
+        match reloader.apply(configuration) {
+            Err(ReloadError::RestartRequired(keys)) => {
+                assert_eq!(keys, vec!["application.port", "database.host"])
+            }
+            other => panic!("Unexpected outcome: {:?}", other),
+        }
+        assert_ne!(
+            reloader.current.email_client.timeout_milliseconds, 500,
+            "A refused reload was partly applied."
+        );
+    }
+}
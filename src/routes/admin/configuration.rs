@@ -0,0 +1,14 @@
+use crate::authentication::AdminUser;
+use crate::configuration::ConfigurationEntry;
+use axum::extract::State;
+use axum::Json;
+use std::sync::Arc;
+
+/// The configuration the application was started with, with secrets
+/// redacted and the source of each value.
+pub async fn get_effective_configuration(
+    _admin: AdminUser,
+    State(configuration): State<Arc<Vec<ConfigurationEntry>>>,
+) -> Json<Arc<Vec<ConfigurationEntry>>> {
+    Json(configuration)
+}
@@ -0,0 +1,169 @@
+use crate::authentication::AdminUser;
+use crate::domain::{CustomFieldDefinition, CustomFieldType};
+use crate::error::{error_chain_fmt, log_error};
+use crate::extract::ResponseFormat;
+use crate::subscriber_repository::SubscriberRepository;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(thiserror::Error)]
+pub enum CustomFieldAdminError {
+    #[error("{0}")]
+    Validation(String),
+    #[error("A custom field named {0} already exists.")]
+    Conflict(String),
+    #[error("There is no custom field named {0}.")]
+    NotFound(String),
+    #[error("There is no mailing list named {0}.")]
+    UnknownList(String),
+    #[error("{context}")]
+    Repository {
+        context: &'static str,
+        source: anyhow::Error,
+    },
+}
+
+impl std::fmt::Debug for CustomFieldAdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for CustomFieldAdminError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Validation(_) => StatusCode::BAD_REQUEST,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::NotFound(_) | Self::UnknownList(_) => StatusCode::NOT_FOUND,
+            Self::Repository { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        log_error(&self, status);
+        let message = match &self {
+            Self::Repository { .. } => "Something went wrong.".to_string(),
+            other => other.to_string(),
+        };
+        ResponseFormat::Json.error(status, &message)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CustomFieldData {
+    name: String,
+    field_type: String,
+    #[serde(default)]
+    required: bool,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+impl CustomFieldData {
+    fn parse(self, list: String) -> Result<CustomFieldDefinition, String> {
+        let field_type = CustomFieldType::parse(&self.field_type, self.options)?;
+        CustomFieldDefinition::parse(list, self.name, field_type, self.required)
+    }
+}
+
+impl From<&CustomFieldDefinition> for CustomFieldData {
+    fn from(value: &CustomFieldDefinition) -> Self {
+        Self {
+            name: value.name().to_string(),
+            field_type: value.field_type().as_str().to_string(),
+            required: value.required(),
+            options: value.field_type().options().to_vec(),
+        }
+    }
+}
+
+#[tracing::instrument(name = "Listing custom fields", skip(repository, admin), fields(user_id = %admin.user_id))]
+pub async fn list_custom_fields(
+    admin: AdminUser,
+    State(repository): State<Arc<dyn SubscriberRepository>>,
+    Path(list): Path<String>,
+) -> Result<Json<Vec<CustomFieldData>>, CustomFieldAdminError> {
+    ensure_list_exists(repository.as_ref(), &list).await?;
+    let definitions = repository
+        .custom_field_definitions_of(&list)
+        .await
+        .map_err(|source| CustomFieldAdminError::Repository {
+            context: "Failed to fetch custom field definitions.",
+            source,
+        })?;
+    Ok(Json(definitions.iter().map(Into::into).collect()))
+}
+
+#[tracing::instrument(
+    name = "Defining a custom field",
+    skip(repository, admin, data),
+    fields(user_id = %admin.user_id, field_name = %data.name)
+)]
+pub async fn create_custom_field(
+    admin: AdminUser,
+    State(repository): State<Arc<dyn SubscriberRepository>>,
+    Path(list): Path<String>,
+    Json(data): Json<CustomFieldData>,
+) -> Result<StatusCode, CustomFieldAdminError> {
+    ensure_list_exists(repository.as_ref(), &list).await?;
+    let definition = data
+        .parse(list)
+        .map_err(CustomFieldAdminError::Validation)?;
+    let inserted = repository
+        .insert_custom_field(&definition)
+        .await
+        .map_err(|source| CustomFieldAdminError::Repository {
+            context: "Failed to insert custom field definition in the database.",
+            source,
+        })?;
+    if inserted {
+        Ok(StatusCode::CREATED)
+    } else {
+        Err(CustomFieldAdminError::Conflict(
+            definition.name().to_string(),
+        ))
+    }
+}
+
+#[tracing::instrument(name = "Removing a custom field", skip(repository, admin), fields(user_id = %admin.user_id))]
+pub async fn delete_custom_field(
+    admin: AdminUser,
+    State(repository): State<Arc<dyn SubscriberRepository>>,
+    Path((list, name)): Path<(String, String)>,
+) -> Result<StatusCode, CustomFieldAdminError> {
+    ensure_list_exists(repository.as_ref(), &list).await?;
+    let deleted = repository
+        .delete_custom_field(&list, &name)
+        .await
+        .map_err(|source| CustomFieldAdminError::Repository {
+            context: "Failed to delete custom field definition from the database.",
+            source,
+        })?;
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(CustomFieldAdminError::NotFound(name))
+    }
+}
+
+async fn ensure_list_exists(
+    repository: &dyn SubscriberRepository,
+    list: &str,
+) -> Result<(), CustomFieldAdminError> {
+    let exists =
+        repository
+            .list_exists(list)
+            .await
+            .map_err(|source| CustomFieldAdminError::Repository {
+                context: "Failed to look up the mailing list.",
+                source,
+            })?;
+    if exists {
+        Ok(())
+    } else {
+        Err(CustomFieldAdminError::UnknownList(list.to_string()))
+    }
+}
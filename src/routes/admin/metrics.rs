@@ -0,0 +1,12 @@
+use crate::authentication::AdminUser;
+use crate::metrics;
+use axum::http::header;
+use axum::response::IntoResponse;
+
+/// Application metrics, for Prometheus to scrape.
+pub async fn get_metrics(_admin: AdminUser) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
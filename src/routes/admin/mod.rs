@@ -0,0 +1,7 @@
+mod configuration;
+mod custom_fields;
+mod metrics;
+
+pub use configuration::*;
+pub use custom_fields::*;
+pub use metrics::*;
@@ -1,5 +1,15 @@
+mod admin;
 mod health_check;
 mod subscriptions;
+mod subscriptions_challenge;
+mod subscriptions_confirm;
+mod subscriptions_email_change;
+mod subscriptions_preferences;
 
+pub use admin::*;
 pub use health_check::*;
 pub use subscriptions::*;
+pub use subscriptions_challenge::*;
+pub use subscriptions_confirm::*;
+pub use subscriptions_email_change::*;
+pub use subscriptions_preferences::*;
@@ -1,79 +1,499 @@
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::bot_protection::{BotCheck, BotCheckError, BotProtection};
+use crate::domain::{
+    CustomFieldDefinition, CustomFieldError, CustomFields, EmailPolicy, NewSubscriber,
+    SubscriberEmailError, SubscriberName, SubscriberNameError,
+};
+use crate::email_client::EmailClient;
+use crate::error::{error_chain_fmt, log_error};
+use crate::extract::{FieldError, FormOrJson, ResponseFormat};
+use crate::rate_limit::{ClientRateLimit, RateLimited};
+use crate::signed_links::LinkSigner;
+use crate::startup::ApplicationBaseUrl;
+use crate::subscriber_repository::SubscriberRepository;
 use axum::{
-    extract::{Form, State},
+    extract::State,
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use chrono::Utc;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Deserialize;
-use sqlx::PgPool;
-use std::sync::Arc;
-use uuid::Uuid;
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error("The submitted subscription details are invalid.")]
+    Validation(ResponseFormat, Vec<FieldError>),
+    #[error("Too many subscription requests.")]
+    RateLimited(ResponseFormat, #[source] RateLimited),
+    #[error("The submission failed the bot check.")]
+    BotCheck(ResponseFormat, #[source] BotCheckError),
+    #[error("Failed to send a confirmation email.")]
+    SendEmail(#[source] reqwest::Error),
+    #[error("{context}")]
+    Repository {
+        context: &'static str,
+        source: anyhow::Error,
+    },
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for SubscribeError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Validation(..) | Self::BotCheck(..) => StatusCode::BAD_REQUEST,
+            Self::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            Self::SendEmail(_) | Self::Repository { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        log_error(&self, status);
+        match self {
+            Self::Validation(format, errors) => format.field_errors(&errors),
+            Self::RateLimited(format, e) => e.response(format),
+            Self::BotCheck(format, e) => format.error(status, &e.to_string()),
+            _ => status.into_response(),
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    /// Issued by `GET /subscriptions/challenge`, see [`BotProtection`].
+    #[serde(default)]
+    form_token: Option<String>,
+    #[serde(default)]
+    pow_nonce: Option<String>,
+    /// Any other submitted field is treated as an admin-defined custom field.
+    #[serde(flatten)]
+    custom_fields: HashMap<String, RawFieldValue>,
+}
+
+/// A custom field value as submitted: always text in a form, but possibly a
+/// number or a boolean in a JSON body.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawFieldValue {
+    Text(String),
+    Number(serde_json::Number),
+    Boolean(bool),
+}
+
+impl From<RawFieldValue> for String {
+    fn from(value: RawFieldValue) -> Self {
+        match value {
+            RawFieldValue::Text(s) => s,
+            RawFieldValue::Number(n) => n.to_string(),
+            RawFieldValue::Boolean(b) => b.to_string(),
+        }
+    }
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+impl FormData {
+    /// Validates every field, returning all failures rather than the first.
+    async fn parse(
+        self,
+        definitions: &[CustomFieldDefinition],
+        email_policy: &EmailPolicy,
+    ) -> Result<NewSubscriber, Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let name = SubscriberName::parse(self.name)
+            .map_err(|e| errors.push(name_error(e)))
+            .ok();
+        let email = match email_policy.parse(self.email) {
+            Ok(email) => email_policy
+                .verify_deliverable(&email)
+                .await
+                .map(|()| email),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| errors.push(email_error("email", e)))
+        .ok();
+        let custom_fields = self
+            .custom_fields
+            .into_iter()
+            .map(|(name, value)| (name, value.into()))
+            .collect();
+        let custom_fields = CustomFields::parse(custom_fields, definitions)
+            .map_err(|e| {
+                errors.extend(
+                    e.into_iter()
+                        .map(|(field, error)| custom_field_error(field, error)),
+                )
+            })
+            .ok();
 
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+        match (name, email, custom_fields) {
+            (Some(name), Some(email), Some(custom_fields)) => Ok(NewSubscriber {
+                email,
+                name,
+                custom_fields,
+            }),
+            _ => Err(errors),
+        }
     }
 }
 
-// Form type is an extractor that works on the body of the request and
-// therefore MUST be the last argument of an axum request handler function. The
-// error output here is less than helpful.
+pub(crate) fn name_error(error: SubscriberNameError) -> FieldError {
+    let (reason, position) = match error {
+        SubscriberNameError::Empty => ("empty", None),
+        SubscriberNameError::TooLong { .. } => ("too_long", None),
+        SubscriberNameError::ForbiddenCharacter { position, .. } => {
+            ("forbidden_character", Some(position))
+        }
+    };
+    FieldError {
+        field: "name".into(),
+        reason,
+        message: error.to_string(),
+        position,
+        suggestion: None,
+    }
+}
+
+pub(crate) fn email_error(field: &str, error: SubscriberEmailError) -> FieldError {
+    let (reason, suggestion) = match &error {
+        SubscriberEmailError::Empty => ("empty", None),
+        SubscriberEmailError::Malformed(_) => ("malformed_email", None),
+        SubscriberEmailError::DisposableDomain(_) => ("disposable_domain", None),
+        SubscriberEmailError::RoleAccount(_) => ("role_account", None),
+        SubscriberEmailError::Undeliverable { suggestion, .. } => {
+            ("undeliverable_domain", suggestion.clone())
+        }
+    };
+    FieldError {
+        field: field.into(),
+        reason,
+        message: error.to_string(),
+        position: None,
+        suggestion,
+    }
+}
+
+fn custom_field_error(field: String, error: CustomFieldError) -> FieldError {
+    let reason = match error {
+        CustomFieldError::Unknown => "unknown_field",
+        CustomFieldError::Missing => "missing",
+        CustomFieldError::TooLong { .. } => "too_long",
+        CustomFieldError::InvalidValue { .. } => "invalid_value",
+    };
+    FieldError {
+        field,
+        reason,
+        message: error.to_string(),
+        position: None,
+        suggestion: None,
+    }
+}
+
+// `FormOrJson` works on the body of the request and therefore MUST be the last
+// argument of an axum request handler function. URL-encoded forms and JSON
+// bodies are both accepted and go through the same validation.
+//
+// The response is the same whether or not the address was already on the
+// list, so the endpoint cannot be used to find out who is subscribed.
+//
+// Requests are rate limited per client IP and, once the address is known to
+// be valid, per email address: each one may send an email.
+//
+// Submissions that fill in the honeypot field get the usual response, so
+// bots cannot tell that they were caught, but are otherwise ignored.
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(connection_pool, form),
+    skip(
+        subscribers,
+        email_client,
+        base_url,
+        email_policy,
+        bot_protection,
+        link_signer,
+        rate_limit,
+        format,
+        form
+    ),
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name
     )
 )]
 pub async fn subscribe(
-    State(connection_pool): State<Arc<PgPool>>,
-    Form(form): Form<FormData>,
-) -> impl IntoResponse {
-    let new_subscriber = match form.try_into() {
-        Ok(form) => form,
-        Err(_) => return StatusCode::BAD_REQUEST,
-    };
-    match insert_subscriber(&connection_pool, &new_subscriber).await {
-        Ok(_) => StatusCode::OK,
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    State(subscribers): State<Arc<dyn SubscriberRepository>>,
+    State(email_client): State<Arc<EmailClient>>,
+    State(base_url): State<ApplicationBaseUrl>,
+    State(email_policy): State<Arc<EmailPolicy>>,
+    State(bot_protection): State<Arc<BotProtection>>,
+    State(link_signer): State<LinkSigner>,
+    rate_limit: ClientRateLimit,
+    format: ResponseFormat,
+    FormOrJson(mut form): FormOrJson<FormData>,
+) -> Result<StatusCode, SubscribeError> {
+    rate_limit
+        .check_ip()
+        .await
+        .map_err(|e| SubscribeError::RateLimited(format, e))?;
+    if let Some(honeypot) = bot_protection.honeypot_field() {
+        let value = form.custom_fields.remove(honeypot).map(String::from);
+        if !value.unwrap_or_default().is_empty() {
+            tracing::info!("Ignoring a submission with a filled-in honeypot field");
+            return Ok(StatusCode::OK);
+        }
+    }
+    let form_token = bot_protection
+        .verify(
+            BotCheck {
+                form_token: form.form_token.as_deref(),
+                pow_nonce: form.pow_nonce.as_deref(),
+            },
+            Utc::now().timestamp(),
+        )
+        .map_err(|e| SubscribeError::BotCheck(format, e))?;
+    let definitions = subscribers
+        .custom_field_definitions()
+        .await
+        .map_err(|source| SubscribeError::Repository {
+            context: "Failed to fetch custom field definitions.",
+            source,
+        })?;
+    let new_subscriber = form
+        .parse(&definitions, &email_policy)
+        .await
+        .map_err(|errors| SubscribeError::Validation(format, errors))?;
+    rate_limit
+        .check_email(new_subscriber.email.normalized())
+        .await
+        .map_err(|e| SubscribeError::RateLimited(format, e))?;
+    // Only a valid submission uses up its form token, so that one with a
+    // typo can be corrected and sent again.
+    if let Some(form_token) = form_token {
+        let first_use = bot_protection
+            .consume(&form_token)
+            .await
+            .map_err(|source| SubscribeError::Repository {
+                context: "Failed to record the use of a form token.",
+                source,
+            })?;
+        if !first_use {
+            return Err(SubscribeError::BotCheck(format, BotCheckError::AlreadyUsed));
+        }
     }
+    let subscription_token = generate_subscription_token();
+    let subscriber_id = match subscribers
+        .insert(&new_subscriber, &subscription_token)
+        .await
+        .map_err(|source| SubscribeError::Repository {
+            context: "Failed to store a new subscriber.",
+            source,
+        })? {
+        Some(subscriber) => subscriber.id(),
+        // Already confirmed: there is nothing left to do.
+        None => return Ok(StatusCode::OK),
+    };
+    send_confirmation_email(
+        &email_client,
+        new_subscriber,
+        &base_url.0,
+        &subscription_token,
+        &link_signer.preferences_link(&base_url.0, subscriber_id),
+    )
+    .await
+    .map_err(SubscribeError::SendEmail)?;
+    Ok(StatusCode::OK)
 }
 
 #[tracing::instrument(
-    name = "Saving new subscriber details in the database",
-    skip(new_subscriber, pool)
+    name = "Send a confirmation email to a new subscriber",
+    skip(
+        email_client,
+        new_subscriber,
+        base_url,
+        subscription_token,
+        preferences_link
+    )
 )]
-pub async fn insert_subscriber(
-    pool: &PgPool,
-    new_subscriber: &NewSubscriber,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"
-        INSERT INTO subscriptions (id, email, name, subscribed_at)
-        VALUES ($1, $2, $3, $4)
-        "#,
-        Uuid::new_v4(),
-        new_subscriber.email.as_ref(),
-        new_subscriber.name.as_ref(),
-        Utc::now()
+pub async fn send_confirmation_email(
+    email_client: &EmailClient,
+    new_subscriber: NewSubscriber,
+    base_url: &str,
+    subscription_token: &str,
+    preferences_link: &str,
+) -> Result<(), reqwest::Error> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token
+    );
+    let plain_body = format!(
+        "Welcome to our newsletter!\nVisit {} to confirm your subscription.{}",
+        confirmation_link,
+        plain_preferences_footer(preferences_link)
+    );
+    let html_body = format!(
+        "Welcome to our newsletter!<br />\
+        Click <a href=\"{}\">here</a> to confirm your subscription.{}",
+        confirmation_link,
+        html_preferences_footer(preferences_link)
+    );
+    email_client
+        .send_email(new_subscriber.email, "Welcome!", &html_body, &plain_body)
+        .await
+}
+
+/// Appended to every email we send a subscriber, after any other link.
+pub fn plain_preferences_footer(preferences_link: &str) -> String {
+    format!("\n\nManage your subscription at {}", preferences_link)
+}
+
+pub fn html_preferences_footer(preferences_link: &str) -> String {
+    format!(
+        "<br /><br /><a href=\"{}\">Manage your subscription</a>",
+        preferences_link
     )
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(())
+}
+
+/// Generate a random 25-characters-long case-sensitive subscription token.
+pub fn generate_subscription_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bot_protection::BotProtection;
+    use crate::domain::{EmailPolicy, SubscriberEmail, SubscriberStatus};
+    use crate::email_client::EmailClient;
+    use crate::extract::{FormOrJson, ResponseFormat};
+    use crate::rate_limit::{ClientRateLimit, RateLimiter};
+    use crate::routes::{subscribe, FormData, SubscribeError};
+    use crate::signed_links::LinkSigner;
+    use crate::startup::ApplicationBaseUrl;
+    use crate::subscriber_repository::{InMemorySubscriberRepository, SubscriberRepository};
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use secrecy::Secret;
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use wiremock::matchers::any;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn post(
+        subscribers: Arc<InMemorySubscriberRepository>,
+        email_server: &MockServer,
+        name: &str,
+        email: &str,
+    ) -> Result<StatusCode, SubscribeError> {
+        let email_client = EmailClient::new(
+            email_server.uri(),
+            SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+            Secret::new("api-key".into()),
+            std::time::Duration::from_millis(200),
+        );
+        subscribe(
+            State(subscribers),
+            State(Arc::new(email_client)),
+            State(ApplicationBaseUrl("http://127.0.0.1".into())),
+            State(Arc::new(EmailPolicy::new(
+                Default::default(),
+                None,
+                Vec::new(),
+                Vec::new(),
+            ))),
+            State(Arc::new(BotProtection::disabled())),
+            State(LinkSigner::new(Secret::new("secret".into()))),
+            ClientRateLimit::new(RateLimiter::disabled(), IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ResponseFormat::Json,
+            FormOrJson(FormData {
+                email: email.into(),
+                name: name.into(),
+                form_token: None,
+                pow_nonce: None,
+                custom_fields: HashMap::new(),
+            }),
+        )
+        .await
+    }
+
+    async fn email_server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn a_valid_subscription_is_stored_as_pending() {
+        let subscribers = Arc::new(InMemorySubscriberRepository::default());
+        let email_server = email_server().await;
+
+        let status = post(
+            subscribers.clone(),
+            &email_server,
+            "le guin",
+            "ursula_le_guin@gmail.com",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        let email = SubscriberEmail::parse("ursula_le_guin@gmail.com".into()).unwrap();
+        let subscriber = subscribers.find_by_email(&email).await.unwrap().unwrap();
+        assert_eq!(subscriber.name(), "le guin");
+        assert_eq!(subscriber.status(), SubscriberStatus::PendingConfirmation);
+        assert_eq!(email_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn confirmed_subscribers_get_no_email() {
+        let subscribers = Arc::new(InMemorySubscriberRepository::default());
+        let email_server = email_server().await;
+        post(
+            subscribers.clone(),
+            &email_server,
+            "le guin",
+            "ursula@gmail.com",
+        )
+        .await
+        .unwrap();
+        let email = SubscriberEmail::parse("ursula@gmail.com".into()).unwrap();
+        let mut subscriber = subscribers.find_by_email(&email).await.unwrap().unwrap();
+        subscriber.confirm().unwrap();
+        subscribers.update_status(&subscriber).await.unwrap();
+
+        let status = post(
+            subscribers.clone(),
+            &email_server,
+            "le guin",
+            "ursula@gmail.com",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(email_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_subscriptions_are_not_stored() {
+        let subscribers = Arc::new(InMemorySubscriberRepository::default());
+        let email_server = email_server().await;
+
+        let result = post(subscribers.clone(), &email_server, "", "not-an-email").await;
+
+        match result {
+            Err(SubscribeError::Validation(_, errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+        let all = subscribers.list(&Default::default()).await.unwrap();
+        assert!(all.is_empty());
+    }
 }
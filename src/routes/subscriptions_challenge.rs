@@ -0,0 +1,10 @@
+use crate::bot_protection::{BotProtection, Challenge};
+use axum::{extract::State, Json};
+use chrono::Utc;
+use std::sync::Arc;
+
+/// Hands out what a client needs to submit the signup form: it should be
+/// fetched when the form is rendered, not when it is submitted.
+pub async fn challenge(State(bot_protection): State<Arc<BotProtection>>) -> Json<Challenge> {
+    Json(bot_protection.issue_challenge(Utc::now().timestamp()))
+}
@@ -0,0 +1,106 @@
+use crate::cleanup_worker::PendingSubscriptionPolicy;
+use crate::domain::InvalidTransition;
+use crate::error::{error_chain_fmt, log_error};
+use crate::extract::ResponseFormat;
+use crate::rate_limit::{ClientRateLimit, RateLimited};
+use crate::subscriber_repository::{ConfirmOutcome, SubscriberRepository};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct Parameters {
+    subscription_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error("The confirmation link has expired. Please subscribe again.")]
+    ExpiredToken,
+    #[error("The subscription can no longer be confirmed.")]
+    InvalidTransition(#[source] InvalidTransition),
+    #[error("Too many confirmation attempts.")]
+    RateLimited(#[source] RateLimited),
+    #[error("{context}")]
+    Repository {
+        context: &'static str,
+        source: anyhow::Error,
+    },
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for ConfirmError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::UnknownToken => StatusCode::UNAUTHORIZED,
+            Self::ExpiredToken => StatusCode::GONE,
+            Self::InvalidTransition(_) => StatusCode::CONFLICT,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Repository { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        log_error(&self, status);
+        match self {
+            Self::RateLimited(e) => e.response(ResponseFormat::Text),
+            Self::ExpiredToken | Self::InvalidTransition(_) => {
+                ResponseFormat::Text.error(status, &self.to_string())
+            }
+            _ => status.into_response(),
+        }
+    }
+}
+
+// Tokens are single use: confirming a subscriber invalidates every token
+// that was issued to them. They also expire after `token_ttl`. Attempts are
+// rate limited per client IP, to make guessing tokens impractical.
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(subscribers, policy, rate_limit, parameters)
+)]
+pub async fn confirm(
+    State(subscribers): State<Arc<dyn SubscriberRepository>>,
+    State(policy): State<PendingSubscriptionPolicy>,
+    rate_limit: ClientRateLimit,
+    Query(parameters): Query<Parameters>,
+) -> Result<StatusCode, ConfirmError> {
+    rate_limit
+        .check_ip()
+        .await
+        .map_err(ConfirmError::RateLimited)?;
+    let (subscriber_id, issued_at) = subscribers
+        .find_confirmation_token(&parameters.subscription_token)
+        .await
+        .map_err(|source| ConfirmError::Repository {
+            context: "Failed to retrieve the subscriber id associated with the provided token.",
+            source,
+        })?
+        .ok_or(ConfirmError::UnknownToken)?;
+    let age = (Utc::now() - issued_at).to_std().unwrap_or_default();
+    if age > policy.token_ttl {
+        return Err(ConfirmError::ExpiredToken);
+    }
+    let outcome =
+        subscribers
+            .confirm(subscriber_id)
+            .await
+            .map_err(|source| ConfirmError::Repository {
+                context: "Failed to update the subscriber status to `confirmed`.",
+                source,
+            })?;
+    match outcome {
+        ConfirmOutcome::Confirmed => Ok(StatusCode::OK),
+        ConfirmOutcome::UnknownSubscriber => Err(ConfirmError::UnknownToken),
+        ConfirmOutcome::Rejected(e) => Err(ConfirmError::InvalidTransition(e)),
+    }
+}
@@ -0,0 +1,340 @@
+use crate::cleanup_worker::PendingSubscriptionPolicy;
+use crate::domain::{EmailPolicy, SubscriberEmail};
+use crate::email_client::EmailClient;
+use crate::error::{error_chain_fmt, log_error};
+use crate::extract::{FieldError, FormOrJson, ResponseFormat};
+use crate::rate_limit::{ClientRateLimit, RateLimited};
+use crate::routes::{email_error, generate_subscription_token};
+use crate::signed_links::{LinkPurpose, LinkSigner};
+use crate::startup::ApplicationBaseUrl;
+use crate::subscriber_repository::{EmailChangeOutcome, SubscriberRepository};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(thiserror::Error)]
+pub enum EmailChangeError {
+    #[error("The link is invalid.")]
+    InvalidLink(ResponseFormat),
+    #[error("The new email address is invalid.")]
+    Validation(ResponseFormat, Vec<FieldError>),
+    #[error("Too many email change requests.")]
+    RateLimited(ResponseFormat, #[source] RateLimited),
+    #[error("Failed to send a verification email.")]
+    SendEmail(#[source] reqwest::Error),
+    #[error("{context}")]
+    Repository {
+        context: &'static str,
+        source: anyhow::Error,
+    },
+}
+
+impl std::fmt::Debug for EmailChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for EmailChangeError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::InvalidLink(_) => StatusCode::UNAUTHORIZED,
+            Self::Validation(..) => StatusCode::BAD_REQUEST,
+            Self::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            Self::SendEmail(_) | Self::Repository { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        log_error(&self, status);
+        match self {
+            Self::InvalidLink(format) => format.error(status, &self.to_string()),
+            Self::Validation(format, errors) => format.field_errors(&errors),
+            Self::RateLimited(format, e) => e.response(format),
+            _ => status.into_response(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangeForm {
+    /// The signed token from the subscriber's manage link.
+    token: String,
+    new_email: String,
+}
+
+// Only confirmed subscribers can move their subscription. Nothing changes
+// until the new address is verified through the link we send to it.
+//
+// The response is the same whether or not the new address already belongs
+// to another subscriber, so the endpoint cannot be used to find out who is
+// subscribed.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "Requesting a subscriber email change",
+    skip(
+        subscribers,
+        email_client,
+        base_url,
+        email_policy,
+        link_signer,
+        rate_limit,
+        format,
+        form
+    ),
+    fields(new_email = %form.new_email, subscriber_id = tracing::field::Empty)
+)]
+pub async fn request_email_change(
+    State(subscribers): State<Arc<dyn SubscriberRepository>>,
+    State(email_client): State<Arc<EmailClient>>,
+    State(base_url): State<ApplicationBaseUrl>,
+    State(email_policy): State<Arc<EmailPolicy>>,
+    State(link_signer): State<LinkSigner>,
+    rate_limit: ClientRateLimit,
+    format: ResponseFormat,
+    FormOrJson(form): FormOrJson<EmailChangeForm>,
+) -> Result<StatusCode, EmailChangeError> {
+    rate_limit
+        .check_ip()
+        .await
+        .map_err(|e| EmailChangeError::RateLimited(format, e))?;
+    let subscriber_id = link_signer
+        .verify(&form.token, LinkPurpose::ManageSubscription)
+        .ok_or(EmailChangeError::InvalidLink(format))?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+    let new_email = parse_new_email(&email_policy, form.new_email)
+        .await
+        .map_err(|error| EmailChangeError::Validation(format, vec![error]))?;
+    rate_limit
+        .check_email(new_email.normalized())
+        .await
+        .map_err(|e| EmailChangeError::RateLimited(format, e))?;
+
+    let subscriber = subscribers
+        .find_by_id(subscriber_id)
+        .await
+        .map_err(|source| EmailChangeError::Repository {
+            context: "Failed to look up the subscriber.",
+            source,
+        })?;
+    if !subscriber.map(|s| s.can_change_email()).unwrap_or(false) {
+        return Err(EmailChangeError::InvalidLink(format));
+    }
+    let address_taken = subscribers
+        .is_email_taken(subscriber_id, &new_email)
+        .await
+        .map_err(|source| EmailChangeError::Repository {
+            context: "Failed to check whether the new address is subscribed.",
+            source,
+        })?;
+    if address_taken {
+        tracing::info!("Ignoring a change to an address that is already subscribed");
+        return Ok(StatusCode::OK);
+    }
+
+    let change_token = generate_subscription_token();
+    subscribers
+        .insert_email_change(subscriber_id, &new_email, &change_token)
+        .await
+        .map_err(|source| EmailChangeError::Repository {
+            context: "Failed to store the email change request.",
+            source,
+        })?;
+    send_verification_email(&email_client, new_email, &base_url.0, &change_token)
+        .await
+        .map_err(EmailChangeError::SendEmail)?;
+    Ok(StatusCode::OK)
+}
+
+async fn parse_new_email(
+    email_policy: &EmailPolicy,
+    new_email: String,
+) -> Result<SubscriberEmail, FieldError> {
+    let email = email_policy
+        .parse(new_email)
+        .map_err(|e| email_error("new_email", e))?;
+    email_policy
+        .verify_deliverable(&email)
+        .await
+        .map_err(|e| email_error("new_email", e))?;
+    Ok(email)
+}
+
+#[tracing::instrument(
+    name = "Send an email change verification email",
+    skip(email_client, new_email, base_url, change_token)
+)]
+async fn send_verification_email(
+    email_client: &EmailClient,
+    new_email: SubscriberEmail,
+    base_url: &str,
+    change_token: &str,
+) -> Result<(), reqwest::Error> {
+    let verification_link = format!(
+        "{}/subscriptions/email_change/confirm?change_token={}",
+        base_url, change_token
+    );
+    let plain_body = format!(
+        "You asked to move your newsletter subscription to this address.\n\
+        Visit {} to confirm the change.",
+        verification_link
+    );
+    let html_body = format!(
+        "You asked to move your newsletter subscription to this address.<br />\
+        Click <a href=\"{}\">here</a> to confirm the change.",
+        verification_link
+    );
+    email_client
+        .send_email(
+            new_email,
+            "Confirm your new email address",
+            &html_body,
+            &plain_body,
+        )
+        .await
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmEmailChangeParameters {
+    change_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmEmailChangeError {
+    #[error("There is no email change associated with the provided token.")]
+    UnknownToken,
+    #[error("The verification link has expired. Please request the change again.")]
+    ExpiredToken,
+    #[error("The new email address is already subscribed.")]
+    AddressTaken,
+    #[error("Too many confirmation attempts.")]
+    RateLimited(#[source] RateLimited),
+    #[error("{context}")]
+    Repository {
+        context: &'static str,
+        source: anyhow::Error,
+    },
+}
+
+impl std::fmt::Debug for ConfirmEmailChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for ConfirmEmailChangeError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::UnknownToken => StatusCode::UNAUTHORIZED,
+            Self::ExpiredToken => StatusCode::GONE,
+            Self::AddressTaken => StatusCode::CONFLICT,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Repository { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        log_error(&self, status);
+        match self {
+            Self::RateLimited(e) => e.response(ResponseFormat::Text),
+            Self::ExpiredToken | Self::AddressTaken => {
+                ResponseFormat::Text.error(status, &self.to_string())
+            }
+            _ => status.into_response(),
+        }
+    }
+}
+
+// The subscriber keeps their id, status, custom fields and subscription
+// date: only the address changes. Verification links expire like
+// confirmation links do, and confirming one invalidates every other pending
+// change for the subscriber.
+//
+// The old address is told about the change, so that a subscriber whose
+// manage link leaked finds out. Failing to tell them does not undo it.
+#[tracing::instrument(
+    name = "Confirm a subscriber email change",
+    skip(subscribers, email_client, policy, rate_limit, parameters)
+)]
+pub async fn confirm_email_change(
+    State(subscribers): State<Arc<dyn SubscriberRepository>>,
+    State(email_client): State<Arc<EmailClient>>,
+    State(policy): State<PendingSubscriptionPolicy>,
+    rate_limit: ClientRateLimit,
+    Query(parameters): Query<ConfirmEmailChangeParameters>,
+) -> Result<StatusCode, ConfirmEmailChangeError> {
+    rate_limit
+        .check_ip()
+        .await
+        .map_err(ConfirmEmailChangeError::RateLimited)?;
+    let request = subscribers
+        .find_email_change(&parameters.change_token)
+        .await
+        .map_err(|source| ConfirmEmailChangeError::Repository {
+            context: "Failed to retrieve the email change request.",
+            source,
+        })?
+        .ok_or(ConfirmEmailChangeError::UnknownToken)?;
+    let age = (Utc::now() - request.requested_at)
+        .to_std()
+        .unwrap_or_default();
+    if age > policy.token_ttl {
+        return Err(ConfirmEmailChangeError::ExpiredToken);
+    }
+
+    let outcome = subscribers.change_email(&request).await.map_err(|source| {
+        ConfirmEmailChangeError::Repository {
+            context: "Failed to update the subscriber's email address.",
+            source,
+        }
+    })?;
+    let old_email = match outcome {
+        EmailChangeOutcome::Changed { old_email } => old_email,
+        EmailChangeOutcome::NotAllowed => return Err(ConfirmEmailChangeError::UnknownToken),
+        EmailChangeOutcome::AddressTaken => return Err(ConfirmEmailChangeError::AddressTaken),
+    };
+
+    match SubscriberEmail::parse(old_email) {
+        Ok(old_email) => {
+            if let Err(e) = send_change_notice(&email_client, old_email, &request.new_email).await {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to notify the old address of an email change"
+                );
+            }
+        }
+        Err(e) => {
+            tracing::warn!(error.message = %e, "Skipping a change notice to an invalid address")
+        }
+    }
+    Ok(StatusCode::OK)
+}
+
+#[tracing::instrument(
+    name = "Notify the old address of an email change",
+    skip(email_client, old_email, new_email)
+)]
+async fn send_change_notice(
+    email_client: &EmailClient,
+    old_email: SubscriberEmail,
+    new_email: &str,
+) -> Result<(), reqwest::Error> {
+    let plain_body = format!(
+        "Your newsletter subscription has moved from this address to {}.\n\
+        If you did not ask for this, please get in touch.",
+        new_email
+    );
+    let html_body = format!(
+        "Your newsletter subscription has moved from this address to {}.<br />\
+        If you did not ask for this, please get in touch.",
+        new_email
+    );
+    email_client
+        .send_email(
+            old_email,
+            "Your subscription email address has changed",
+            &html_body,
+            &plain_body,
+        )
+        .await
+}
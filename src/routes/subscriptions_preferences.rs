@@ -0,0 +1,311 @@
+use crate::domain::{SendingFrequency, SubscriberName};
+use crate::error::{error_chain_fmt, log_error};
+use crate::extract::{FieldError, FormOrJson, ResponseFormat};
+use crate::rate_limit::{ClientRateLimit, RateLimited};
+use crate::routes::name_error;
+use crate::signed_links::{LinkPurpose, LinkSigner};
+use crate::subscriber_repository::{Preferences, PreferencesUpdate, SubscriberRepository};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// HTML forms cannot submit arrays: each ticked list checkbox arrives as its
+/// own field, named after the list with this prefix.
+const LIST_CHECKBOX_PREFIX: &str = "list:";
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("The link is invalid.")]
+    InvalidLink(ResponseFormat),
+    #[error("The submitted preferences are invalid.")]
+    Validation(ResponseFormat, Vec<FieldError>),
+    #[error("Too many preference updates.")]
+    RateLimited(ResponseFormat, #[source] RateLimited),
+    #[error("{context}")]
+    Repository {
+        context: &'static str,
+        source: anyhow::Error,
+    },
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl IntoResponse for PreferencesError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::InvalidLink(_) => StatusCode::UNAUTHORIZED,
+            Self::Validation(..) => StatusCode::BAD_REQUEST,
+            Self::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Repository { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        log_error(&self, status);
+        match self {
+            Self::InvalidLink(format) => format.error(status, &self.to_string()),
+            Self::Validation(format, errors) => format.field_errors(&errors),
+            Self::RateLimited(format, e) => e.response(format),
+            _ => status.into_response(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PreferencesParameters {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PreferencesForm {
+    /// The signed token from the subscriber's preferences link.
+    token: String,
+    name: String,
+    frequency: String,
+    /// The lists to be on, as sent in a JSON body.
+    #[serde(default)]
+    lists: Option<Vec<String>>,
+    /// The list checkboxes of the HTML form.
+    #[serde(flatten)]
+    checkboxes: HashMap<String, String>,
+}
+
+impl PreferencesForm {
+    /// Validates every field against the lists that exist, returning all
+    /// failures rather than the first.
+    fn parse(self, current: &Preferences) -> Result<PreferencesUpdate, Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let name = SubscriberName::parse(self.name)
+            .map_err(|e| errors.push(name_error(e)))
+            .ok();
+        let frequency = SendingFrequency::parse(&self.frequency)
+            .map_err(|message| {
+                errors.push(FieldError {
+                    field: "frequency".into(),
+                    reason: "invalid_value",
+                    message,
+                    position: None,
+                    suggestion: None,
+                })
+            })
+            .ok();
+        let lists: BTreeSet<String> = match self.lists {
+            Some(lists) => lists.into_iter().collect(),
+            None => self
+                .checkboxes
+                .into_keys()
+                .filter_map(|field| field.strip_prefix(LIST_CHECKBOX_PREFIX).map(str::to_string))
+                .collect(),
+        };
+        for unknown in lists
+            .iter()
+            .filter(|list| !current.lists.iter().any(|l| &&l.name == list))
+        {
+            errors.push(FieldError {
+                field: "lists".into(),
+                reason: "unknown_list",
+                message: format!("There is no list called {}.", unknown),
+                position: None,
+                suggestion: None,
+            });
+        }
+
+        match (name, frequency) {
+            (Some(name), Some(frequency)) if errors.is_empty() => Ok(PreferencesUpdate {
+                name,
+                frequency,
+                lists,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+// Reached through the signed link at the bottom of every email we send a
+// subscriber. Browsers get an HTML page; clients asking for JSON get the
+// preferences as JSON.
+#[tracing::instrument(
+    name = "Showing subscriber preferences",
+    skip(subscribers, link_signer, format, parameters)
+)]
+pub async fn get_preferences(
+    State(subscribers): State<Arc<dyn SubscriberRepository>>,
+    State(link_signer): State<LinkSigner>,
+    format: ResponseFormat,
+    Query(parameters): Query<PreferencesParameters>,
+) -> Result<Response, PreferencesError> {
+    let subscriber_id = link_signer
+        .verify(&parameters.token, LinkPurpose::ManageSubscription)
+        .ok_or(PreferencesError::InvalidLink(format))?;
+    let preferences = subscribers
+        .preferences(subscriber_id)
+        .await
+        .map_err(|source| PreferencesError::Repository {
+            context: "Failed to load the subscriber's preferences.",
+            source,
+        })?
+        .ok_or(PreferencesError::InvalidLink(format))?;
+    Ok(render(format, &parameters.token, &preferences, None))
+}
+
+// `FormOrJson` must be the last argument. Submissions replace the name,
+// frequency and list memberships all at once; whatever actually changed is
+// recorded in the subscriber's history.
+#[tracing::instrument(
+    name = "Updating subscriber preferences",
+    skip(subscribers, link_signer, rate_limit, format, form),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn update_preferences(
+    State(subscribers): State<Arc<dyn SubscriberRepository>>,
+    State(link_signer): State<LinkSigner>,
+    rate_limit: ClientRateLimit,
+    format: ResponseFormat,
+    FormOrJson(form): FormOrJson<PreferencesForm>,
+) -> Result<Response, PreferencesError> {
+    rate_limit
+        .check_ip()
+        .await
+        .map_err(|e| PreferencesError::RateLimited(format, e))?;
+    let subscriber_id = link_signer
+        .verify(&form.token, LinkPurpose::ManageSubscription)
+        .ok_or(PreferencesError::InvalidLink(format))?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+    let token = form.token.clone();
+
+    let current = subscribers
+        .preferences(subscriber_id)
+        .await
+        .map_err(|source| PreferencesError::Repository {
+            context: "Failed to load the subscriber's preferences.",
+            source,
+        })?
+        .ok_or(PreferencesError::InvalidLink(format))?;
+    let update = form
+        .parse(&current)
+        .map_err(|errors| PreferencesError::Validation(format, errors))?;
+    let preferences = subscribers
+        .update_preferences(subscriber_id, &update)
+        .await
+        .map_err(|source| PreferencesError::Repository {
+            context: "Failed to save the subscriber's preferences.",
+            source,
+        })?
+        .ok_or(PreferencesError::InvalidLink(format))?;
+    Ok(render(
+        format,
+        &token,
+        &preferences,
+        Some("Your preferences have been saved."),
+    ))
+}
+
+fn render(
+    format: ResponseFormat,
+    token: &str,
+    preferences: &Preferences,
+    notice: Option<&str>,
+) -> Response {
+    match format {
+        ResponseFormat::Json => Json(preferences).into_response(),
+        ResponseFormat::Text => Html(preferences_page(token, preferences, notice)).into_response(),
+    }
+}
+
+fn preferences_page(token: &str, preferences: &Preferences, notice: Option<&str>) -> String {
+    let mut lists = String::new();
+    for list in &preferences.lists {
+        let _ = writeln!(
+            lists,
+            r#"<label><input type="checkbox" name="{}{}"{}> {}</label><br />"#,
+            LIST_CHECKBOX_PREFIX,
+            escape_html(&list.name),
+            if list.subscribed { " checked" } else { "" },
+            escape_html(&list.description),
+        );
+    }
+    let mut frequencies = String::new();
+    for frequency in SendingFrequency::ALL {
+        let _ = writeln!(
+            frequencies,
+            r#"<label><input type="radio" name="frequency" value="{}"{}> {}</label><br />"#,
+            frequency.as_str(),
+            if frequency == preferences.frequency {
+                " checked"
+            } else {
+                ""
+            },
+            frequency.label(),
+        );
+    }
+    let notice = notice
+        .map(|notice| format!("<p><strong>{}</strong></p>\n", escape_html(notice)))
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Your subscription</title>
+</head>
+<body>
+<h1>Your subscription</h1>
+{notice}<p>Preferences for {email}.</p>
+<form action="/subscriptions/preferences" method="post">
+<input type="hidden" name="token" value="{token}">
+<label>Name <input type="text" name="name" value="{name}" required></label>
+<fieldset>
+<legend>Lists</legend>
+{lists}</fieldset>
+<fieldset>
+<legend>How often</legend>
+{frequencies}</fieldset>
+<button type="submit">Save</button>
+</form>
+</body>
+</html>
+"#,
+        notice = notice,
+        email = escape_html(&preferences.email),
+        token = escape_html(token),
+        name = escape_html(&preferences.name),
+        lists = lists,
+        frequencies = frequencies,
+    )
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::subscriptions_preferences::escape_html;
+
+    #[test]
+    fn markup_is_escaped() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+}
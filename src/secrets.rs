@@ -0,0 +1,189 @@
+use secrecy::{ExposeSecret, Secret};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a secret file is checked for changes: checking on every use
+/// would mean a filesystem call for every request.
+pub const SECRET_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reads a secret from a file, such as a Docker or Kubernetes secret,
+/// dropping the trailing newline editors and `echo` tend to add.
+pub fn read_secret_file(path: &Path) -> std::io::Result<Secret<String>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(Secret::new(
+        contents.trim_end_matches(&['\r', '\n'][..]).to_string(),
+    ))
+}
+
+/// A secret that is either fixed or read from a file.
+pub enum SecretSource {
+    Value(Secret<String>),
+    File(SecretFile),
+}
+
+impl SecretSource {
+    pub fn current(&self) -> Secret<String> {
+        match self {
+            Self::Value(secret) => secret.clone(),
+            Self::File(file) => file.current(),
+        }
+    }
+}
+
+impl From<Secret<String>> for SecretSource {
+    fn from(secret: Secret<String>) -> Self {
+        Self::Value(secret)
+    }
+}
+
+/// A secret read from a file, and read again when the file changes so that
+/// it can be rotated without a restart. Changes are picked up within
+/// [`SECRET_FILE_CHECK_INTERVAL`].
+pub struct SecretFile {
+    path: PathBuf,
+    check_interval: Duration,
+    state: RwLock<SecretFileState>,
+}
+
+struct SecretFileState {
+    /// When the file was last checked for changes.
+    checked: Option<Instant>,
+    modified: Option<SystemTime>,
+    secret: Secret<String>,
+}
+
+impl SecretFile {
+    /// `secret` is what the file contained when the configuration was
+    /// loaded. The file is read again on first use, in case it has been
+    /// rotated since.
+    pub fn new(path: PathBuf, secret: Secret<String>) -> Self {
+        Self {
+            path,
+            check_interval: SECRET_FILE_CHECK_INTERVAL,
+            state: RwLock::new(SecretFileState {
+                checked: None,
+                modified: None,
+                secret,
+            }),
+        }
+    }
+
+    #[cfg(test)]
+    fn checked_every(mut self, interval: Duration) -> Self {
+        self.check_interval = interval;
+        self
+    }
+
+    /// If the file cannot be read, the previously read secret stays in use.
+    pub fn current(&self) -> Secret<String> {
+        self.reload_if_modified();
+        self.state.read().unwrap().secret.clone()
+    }
+
+    fn reload_if_modified(&self) {
+        let is_due = |checked: Option<Instant>| match checked {
+            Some(checked) => checked.elapsed() >= self.check_interval,
+            None => true,
+        };
+        if !is_due(self.state.read().unwrap().checked) {
+            return;
+        }
+        {
+            let mut state = self.state.write().unwrap();
+            // Someone else may have checked while we were waiting.
+            if !is_due(state.checked) {
+                return;
+            }
+            state.checked = Some(Instant::now());
+        }
+
+        let modified = match std::fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                tracing::warn!(
+                    error.message = %e,
+                    path = %self.path.display(),
+                    "Failed to read a secret file"
+                );
+                return;
+            }
+        };
+        if self.state.read().unwrap().modified == Some(modified) {
+            return;
+        }
+
+        match read_secret_file(&self.path) {
+            Ok(secret) if !secret.expose_secret().is_empty() => {
+                tracing::info!(path = %self.path.display(), "Reloaded a secret file");
+                let mut state = self.state.write().unwrap();
+                state.modified = Some(modified);
+                state.secret = secret;
+            }
+            // Probably caught halfway through being rewritten.
+            Ok(_) => tracing::warn!(path = %self.path.display(), "Ignoring an empty secret file"),
+            Err(e) => tracing::warn!(
+                error.message = %e,
+                path = %self.path.display(),
+                "Failed to read a secret file"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::{read_secret_file, SecretFile};
+    use secrecy::{ExposeSecret, Secret};
+    use std::time::Duration;
+
+    fn temporary_file(contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("secret-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn trailing_newlines_are_trimmed() {
+        let path = temporary_file("hunter2\r\n");
+
+        let secret = read_secret_file(&path).unwrap();
+
+        assert_eq!(secret.expose_secret(), "hunter2");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_rotated_secret_is_read_again() {
+        let path = temporary_file("new\n");
+
+        let file = SecretFile::new(path.clone(), Secret::new("old".into()));
+
+        assert_eq!(file.current().expose_secret(), "new");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn the_file_is_not_checked_again_right_away() {
+        let path = temporary_file("old\n");
+        let file = SecretFile::new(path.clone(), Secret::new("old".into()));
+        assert_eq!(file.current().expose_secret(), "old");
+
+        std::fs::write(&path, "new\n").unwrap();
+
+        assert_eq!(file.current().expose_secret(), "old");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn the_last_secret_is_kept_if_the_file_disappears() {
+        let path = temporary_file("old\n");
+        let file =
+            SecretFile::new(path.clone(), Secret::new("old".into())).checked_every(Duration::ZERO);
+        assert_eq!(file.current().expose_secret(), "old");
+
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(file.current().expose_secret(), "old");
+    }
+}
@@ -0,0 +1,101 @@
+use tokio::sync::watch;
+
+/// Tells long-running tasks that the application is shutting down.
+///
+/// Clones all observe the same [`ShutdownTrigger`].
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger(sender), Shutdown(receiver))
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        // Nobody is listening any more: there is nothing left to stop.
+        let _ = self.0.send(true);
+    }
+}
+
+impl Shutdown {
+    /// Also true once the trigger has been dropped: nothing could request
+    /// shutdown any more.
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow() || self.0.has_changed().is_err()
+    }
+
+    /// Completes once shutdown has been requested, or the trigger dropped.
+    pub async fn requested(&mut self) {
+        while !*self.0.borrow() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Completes on SIGINT (Ctrl-C) or, on Unix, SIGTERM. A signal that cannot
+/// be listened for is logged and never completes, so the other one still
+/// shuts the application down gracefully.
+pub async fn signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error.message = %e, "Failed to listen for Ctrl-C");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error.message = %e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => tracing::info!("Received SIGINT"),
+        () = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::channel;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn every_clone_sees_the_trigger() {
+        let (trigger, shutdown) = channel();
+        let mut clone = shutdown.clone();
+        assert!(!shutdown.is_requested());
+
+        trigger.trigger();
+
+        tokio::time::timeout(Duration::from_secs(1), clone.requested())
+            .await
+            .expect("Shutdown was not requested.");
+        assert!(shutdown.is_requested());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_trigger_requests_shutdown() {
+        let (trigger, mut shutdown) = channel();
+
+        drop(trigger);
+
+        tokio::time::timeout(Duration::from_secs(1), shutdown.requested())
+            .await
+            .expect("Shutdown was not requested.");
+        assert!(shutdown.is_requested());
+    }
+}
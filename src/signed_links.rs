@@ -0,0 +1,106 @@
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Signs subscriber ids, so that links we email to a subscriber let them
+/// manage their subscription without an account.
+///
+/// A token is only valid for the purpose it was issued for.
+#[derive(Clone)]
+pub struct LinkSigner {
+    secret: Secret<String>,
+}
+
+/// What a signed link lets its holder do.
+#[derive(Debug, Clone, Copy)]
+pub enum LinkPurpose {
+    ManageSubscription,
+}
+
+impl LinkPurpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ManageSubscription => "manage",
+        }
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+impl LinkSigner {
+    pub fn new(secret: Secret<String>) -> Self {
+        Self { secret }
+    }
+
+    pub fn sign(&self, subscriber_id: Uuid, purpose: LinkPurpose) -> String {
+        let signature = self.mac(subscriber_id, purpose).finalize().into_bytes();
+        format!("{}.{}", subscriber_id, hex::encode(signature))
+    }
+
+    /// The link to a subscriber's preference center, included in the emails
+    /// we send them.
+    pub fn preferences_link(&self, base_url: &str, subscriber_id: Uuid) -> String {
+        format!(
+            "{}/subscriptions/preferences?token={}",
+            base_url,
+            self.sign(subscriber_id, LinkPurpose::ManageSubscription)
+        )
+    }
+
+    /// Returns the subscriber id `token` was issued for, if we signed it for
+    /// `purpose`.
+    pub fn verify(&self, token: &str, purpose: LinkPurpose) -> Option<Uuid> {
+        let (subscriber_id, signature) = token.split_once('.')?;
+        let subscriber_id = Uuid::parse_str(subscriber_id).ok()?;
+        let signature = hex::decode(signature).ok()?;
+        self.mac(subscriber_id, purpose)
+            .verify_slice(&signature)
+            .ok()?;
+        Some(subscriber_id)
+    }
+
+    fn mac(&self, subscriber_id: Uuid, purpose: LinkPurpose) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length.");
+        mac.update(format!("{}:{}", purpose.as_str(), subscriber_id).as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signed_links::{LinkPurpose, LinkSigner};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    #[test]
+    fn signed_tokens_are_verified() {
+        let signer = LinkSigner::new(Secret::new("a-secret".into()));
+        let subscriber_id = Uuid::new_v4();
+
+        let token = signer.sign(subscriber_id, LinkPurpose::ManageSubscription);
+
+        assert_eq!(
+            signer.verify(&token, LinkPurpose::ManageSubscription),
+            Some(subscriber_id)
+        );
+    }
+
+    #[test]
+    fn tampered_or_foreign_tokens_are_rejected() {
+        let signer = LinkSigner::new(Secret::new("a-secret".into()));
+        let other = LinkSigner::new(Secret::new("another-secret".into()));
+        let token = signer.sign(Uuid::new_v4(), LinkPurpose::ManageSubscription);
+        let (_, signature) = token.split_once('.').unwrap();
+        let swapped = format!("{}.{}", Uuid::new_v4(), signature);
+
+        for token in [
+            swapped,
+            other.sign(Uuid::new_v4(), LinkPurpose::ManageSubscription),
+            "not-a-token".to_string(),
+        ] {
+            assert_eq!(signer.verify(&token, LinkPurpose::ManageSubscription), None);
+        }
+    }
+}
@@ -1,54 +1,306 @@
 use crate::{
-    configuration::Settings,
+    bot_protection::BotProtection,
+    cleanup_worker::{CleanupWorker, PendingSubscriptionPolicy},
+    configuration::{ConfigurationEntry, ConfigurationError, DatabaseSettings, Settings},
+    database::ConnectionPool,
+    domain::{EmailPolicy, SubscriberEmailError},
     email_client::EmailClient,
-    routes::{health_check, subscribe},
+    error::error_chain_fmt,
+    rate_limit::{RateLimitStore, RateLimiter},
+    reload::{reload_on_hangup, Reloadable, Reloader},
+    routes::{
+        challenge, confirm, confirm_email_change, create_custom_field, delete_custom_field,
+        get_effective_configuration, get_metrics, get_preferences, health_check,
+        list_custom_fields, request_email_change, subscribe, update_preferences,
+    },
+    shutdown::{self, Shutdown, ShutdownTrigger},
+    signed_links::LinkSigner,
+    subscriber_repository::{PostgresSubscriberRepository, SubscriberRepository},
+};
+use axum::{
+    extract::{connect_info::IntoMakeServiceWithConnectInfo, FromRef},
+    routing::{delete, get, post, Router},
 };
-use axum::routing::{get, post, Router};
 use axum_tracing_opentelemetry::opentelemetry_tracing_layer;
-use hyper::Server;
-use sqlx::{postgres::PgPoolOptions, PgPool};
-use std::{future::Future, net::TcpListener, sync::Arc};
-
-pub fn build(configuration: Settings) -> impl Future<Output = hyper::Result<()>> {
-    let connection_pool = PgPoolOptions::new()
-        .acquire_timeout(std::time::Duration::from_secs(2))
-        .connect_lazy_with(configuration.database.with_db());
-
-    let sender_email = configuration
-        .email_client
-        .sender()
-        .expect("Invalid sender email address.");
-    let timeout = configuration.email_client.timeout();
-    let email_client = EmailClient::new(
-        configuration.email_client.base_url,
-        sender_email,
-        configuration.email_client.api_key,
-        timeout,
-    );
-
-    let address = format!(
-        "{}:{}",
-        configuration.application.host, configuration.application.port
-    );
-    //let socket: SocketAddr = address.parse().expect("Unable to parse socket address");
-    let listener = TcpListener::bind(address).expect("Failed to bind port.");
-    run(listener, connection_pool, email_client)
-}
-
-pub fn run(
+use hyper::{server::conn::AddrIncoming, Server};
+use sqlx::PgPool;
+use std::{
+    future::Future,
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+/// The HTTP server, bound to its port but not yet serving requests.
+pub struct Application {
+    port: u16,
+    server: Server<AddrIncoming, IntoMakeServiceWithConnectInfo<Router, SocketAddr>>,
+    state: AppState,
+    cleanup_worker: Option<CleanupWorker>,
+    shutdown_timeout: Duration,
+    trigger: ShutdownTrigger,
+    shutdown: Shutdown,
+    workers: Vec<JoinHandle<()>>,
+    reloader: Option<Reloader>,
+}
+
+#[derive(thiserror::Error)]
+pub enum StartupError {
+    #[error("The configured sender email address is invalid.")]
+    InvalidSenderEmail(#[source] SubscriberEmailError),
+    #[error("Failed to bind to {address}.")]
+    Bind {
+        address: String,
+        source: std::io::Error,
+    },
+    #[error("Failed to start the HTTP server.")]
+    Server(#[source] hyper::Error),
+}
+
+impl std::fmt::Debug for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Application {
+    /// Binds to the configured host and port. Port 0 picks a free port,
+    /// which [`Application::port`] then reports.
+    pub async fn build(configuration: Settings) -> Result<Self, StartupError> {
+        let connection_pool = get_connection_pool(&configuration.database);
+        let address = format!(
+            "{}:{}",
+            configuration.application.host, configuration.application.port
+        );
+        let bind_error = |source| StartupError::Bind {
+            address: address.clone(),
+            source,
+        };
+        let listener = TcpListener::bind(&address).map_err(bind_error)?;
+        let port = listener.local_addr().map_err(bind_error)?.port();
+        let state = AppState::new(&configuration, connection_pool)?;
+        let server = run(listener, state.clone()).map_err(StartupError::Server)?;
+        let (trigger, shutdown) = shutdown::channel();
+        Ok(Self {
+            port,
+            server,
+            state: state.clone(),
+            cleanup_worker: Some(CleanupWorker::new(&configuration, state.clone())),
+            shutdown_timeout: configuration.application.shutdown_timeout(),
+            trigger,
+            shutdown,
+            workers: Vec::new(),
+            reloader: Some(Reloader::new(configuration, state)),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Starts the background job that reminds and purges unconfirmed
+    /// subscribers, sharing the server's services. Calling it again does
+    /// nothing.
+    pub fn spawn_cleanup_worker(&mut self) {
+        if let Some(worker) = self.cleanup_worker.take() {
+            self.workers.push(tokio::spawn(
+                worker.run_until_stopped(self.shutdown.clone()),
+            ));
+        }
+    }
+
+    /// Starts reloading the configuration with `load` on SIGHUP. Only some
+    /// settings can change while the application runs; see [`Reloader`].
+    /// Calling it again does nothing.
+    pub fn spawn_configuration_reloader(
+        &mut self,
+        load: impl Fn() -> Result<Settings, ConfigurationError> + Send + 'static,
+    ) {
+        if let Some(reloader) = self.reloader.take() {
+            self.workers.push(tokio::spawn(reload_on_hangup(
+                reloader,
+                load,
+                self.shutdown.clone(),
+            )));
+        }
+    }
+
+    /// Serves requests until `signal` completes, then shuts down gracefully:
+    /// stops accepting connections, gives in-flight requests up to the
+    /// configured timeout to finish, waits for background workers to finish
+    /// their current job and closes the connection pool.
+    pub async fn run_until_stopped(
+        self,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), hyper::Error> {
+        let mut shutdown = self.shutdown.clone();
+        let server = self
+            .server
+            .with_graceful_shutdown(async move { shutdown.requested().await });
+        tokio::pin!(server);
+        let result = tokio::select! {
+            result = &mut server => result,
+            () = signal => {
+                tracing::info!("Shutting down: no longer accepting connections");
+                self.trigger.trigger();
+                match tokio::time::timeout(self.shutdown_timeout, &mut server).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        tracing::warn!("Gave up waiting for in-flight requests to finish");
+                        Ok(())
+                    }
+                }
+            }
+        };
+
+        // The server may also have stopped on its own, because of an error.
+        self.trigger.trigger();
+        for worker in self.workers {
+            if let Err(e) = worker.await {
+                tracing::error!(error.message = %e, "A background worker panicked");
+            }
+        }
+        self.state.pool.close().await;
+        tracing::info!("Shut down");
+        result
+    }
+}
+
+/// Connections are opened on first use, so a database that is down does not
+/// stop the application from starting.
+pub fn get_connection_pool(configuration: &DatabaseSettings) -> ConnectionPool {
+    ConnectionPool::new(configuration)
+}
+
+/// Everything the request handlers share.
+///
+/// Each field can be extracted on its own with `State<T>`, so a handler only
+/// depends on the services it actually uses.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    /// Replaced when the database password changes; extract it as
+    /// `State<PgPool>`.
+    #[from_ref(skip)]
+    pub pool: ConnectionPool,
+    pub subscribers: Arc<dyn SubscriberRepository>,
+    /// Replaced when the configuration is reloaded; extract its parts as
+    /// `State<Arc<EmailClient>>`, `State<RateLimiter>` and
+    /// `State<Arc<Vec<ConfigurationEntry>>>`.
+    #[from_ref(skip)]
+    pub services: Reloadable<ReloadableServices>,
+    pub base_url: ApplicationBaseUrl,
+    pub email_policy: Arc<EmailPolicy>,
+    pub bot_protection: Arc<BotProtection>,
+    pub pending_policy: PendingSubscriptionPolicy,
+    pub link_signer: LinkSigner,
+}
+
+/// The services built from the settings that can be reloaded. They are
+/// replaced all at once, so a request never sees some from before a reload
+/// and some from after it.
+pub struct ReloadableServices {
+    pub email_client: Arc<EmailClient>,
+    pub rate_limiter: RateLimiter,
+    /// The redacted configuration, for the admin endpoint.
+    pub configuration: Arc<Vec<ConfigurationEntry>>,
+}
+
+impl ReloadableServices {
+    /// Builds the services from `configuration`, keeping the rate limit
+    /// buckets in `store`.
+    pub fn new(
+        configuration: &Settings,
+        store: Arc<dyn RateLimitStore>,
+    ) -> Result<Self, SubscriberEmailError> {
+        Ok(Self {
+            email_client: Arc::new(configuration.email_client.client()?),
+            rate_limiter: configuration.rate_limit.limiter_with(store),
+            configuration: Arc::new(configuration.describe()),
+        })
+    }
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.get()
+    }
+}
+
+impl FromRef<AppState> for Arc<EmailClient> {
+    fn from_ref(state: &AppState) -> Self {
+        state.services.get().email_client.clone()
+    }
+}
+
+impl FromRef<AppState> for RateLimiter {
+    fn from_ref(state: &AppState) -> Self {
+        state.services.get().rate_limiter.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Vec<ConfigurationEntry>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.services.get().configuration.clone()
+    }
+}
+
+impl AppState {
+    /// Sets up every service from `configuration`.
+    pub fn new(configuration: &Settings, pool: ConnectionPool) -> Result<Self, StartupError> {
+        let services =
+            ReloadableServices::new(configuration, configuration.rate_limit.store(pool.clone()))
+                .map_err(StartupError::InvalidSenderEmail)?;
+        Ok(Self {
+            subscribers: Arc::new(PostgresSubscriberRepository::new(pool.clone())),
+            services: Reloadable::new(services),
+            base_url: ApplicationBaseUrl(configuration.application.base_url.clone()),
+            email_policy: Arc::new(configuration.email_address.policy()),
+            bot_protection: Arc::new(configuration.bot_protection.protection(pool.clone())),
+            pending_policy: configuration.pending_subscriptions.policy(),
+            link_signer: configuration.application.link_signer(),
+            pool,
+        })
+    }
+}
+
+/// The public URL the application is reachable at, used to build links in
+/// the emails we send.
+#[derive(Clone)]
+pub struct ApplicationBaseUrl(pub String);
+
+fn run(
     listener: TcpListener,
-    pool: PgPool,
-    email_client: EmailClient,
-) -> impl Future<Output = hyper::Result<()>> {
+    state: AppState,
+) -> Result<Server<AddrIncoming, IntoMakeServiceWithConnectInfo<Router, SocketAddr>>, hyper::Error>
+{
     let app = Router::new()
         //.route("/", get(|| greet(None)))
         //.route("/:name", get(greet))
         .route("/health_check", get(health_check))
         .route("/subscriptions", post(subscribe))
+        .route("/subscriptions/confirm", get(confirm))
+        .route("/subscriptions/challenge", get(challenge))
+        .route(
+            "/subscriptions/preferences",
+            get(get_preferences).post(update_preferences),
+        )
+        .route("/subscriptions/email_change", post(request_email_change))
+        .route(
+            "/subscriptions/email_change/confirm",
+            get(confirm_email_change),
+        )
+        .route(
+            "/admin/lists/:list/custom_fields",
+            get(list_custom_fields).post(create_custom_field),
+        )
+        .route(
+            "/admin/lists/:list/custom_fields/:name",
+            delete(delete_custom_field),
+        )
+        .route("/admin/configuration", get(get_effective_configuration))
+        .route("/admin/metrics", get(get_metrics))
         .layer(opentelemetry_tracing_layer())
-        .with_state(Arc::new(pool))
-        .with_state(Arc::new(email_client));
-    Server::from_tcp(listener)
-        .expect("Failed to connect to socket")
-        .serve(app.into_make_service())
+        .with_state(state);
+    Ok(Server::from_tcp(listener)?.serve(app.into_make_service_with_connect_info::<SocketAddr>()))
 }
@@ -0,0 +1,2093 @@
+use crate::audit::{record_event, SubscriptionEvent};
+use crate::database::ConnectionPool;
+use crate::domain::{
+    CustomFieldDefinition, CustomFieldType, CustomFields, EmailNormalization, InvalidTransition,
+    NewSubscriber, SendingFrequency, Subscriber, SubscriberEmail, SubscriberName, SubscriberStatus,
+};
+use crate::error::is_unique_violation;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Which subscribers [`SubscriberRepository::list`] returns. Filters that
+/// are `None` match everybody.
+#[derive(Debug, Default, Clone)]
+pub struct SubscriberFilter {
+    pub status: Option<SubscriberStatus>,
+    /// The domain of the normalized address: lowercase and IDNA-encoded.
+    pub email_domain: Option<String>,
+    /// Members of this mailing list.
+    pub list: Option<String>,
+    pub custom_field: Option<CustomFieldFilter>,
+    pub limit: Option<usize>,
+}
+
+/// Matches subscribers whose value for `list`'s field `name` is `value`.
+#[derive(Debug, Clone)]
+pub struct CustomFieldFilter {
+    pub list: String,
+    pub name: String,
+    pub value: serde_json::Value,
+}
+
+impl CustomFieldFilter {
+    fn matches(&self, custom_fields: &CustomFields) -> bool {
+        custom_fields.get(&self.list, &self.name) == Some(&self.value)
+    }
+
+    /// The stored custom fields of the matching subscribers contain this.
+    fn as_json(&self) -> serde_json::Value {
+        serde_json::json!({ &self.list: { &self.name: &self.value } })
+    }
+}
+
+/// What [`SubscriberRepository::confirm`] did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfirmOutcome {
+    Confirmed,
+    UnknownSubscriber,
+    /// The subscriber's status does not allow it.
+    Rejected(InvalidTransition),
+}
+
+/// A subscriber's request to move to a new address, waiting for the new
+/// address to be verified.
+#[derive(Debug, Clone)]
+pub struct EmailChangeRequest {
+    pub subscriber_id: Uuid,
+    pub new_email: String,
+    pub new_normalized_email: String,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// What [`SubscriberRepository::change_email`] did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EmailChangeOutcome {
+    /// The subscriber moved away from `old_email`.
+    Changed { old_email: String },
+    /// The subscriber is gone or no longer confirmed.
+    NotAllowed,
+    /// Another subscriber has the new address by now.
+    AddressTaken,
+}
+
+/// What a subscriber can see and change in the preference center.
+#[derive(Debug, Clone, Serialize)]
+pub struct Preferences {
+    pub email: String,
+    pub name: String,
+    pub frequency: SendingFrequency,
+    /// Every list, whether or not the subscriber is on it.
+    pub lists: Vec<ListPreference>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListPreference {
+    pub name: String,
+    pub description: String,
+    pub subscribed: bool,
+}
+
+/// Validated preferences, ready to be saved.
+pub struct PreferencesUpdate {
+    pub name: SubscriberName,
+    pub frequency: SendingFrequency,
+    pub lists: BTreeSet<String>,
+}
+
+/// What an update actually changes, as recorded in the subscriber's
+/// history.
+struct PreferenceChanges {
+    details: serde_json::Value,
+    joined: Vec<String>,
+    left: Vec<String>,
+}
+
+impl PreferencesUpdate {
+    /// Returns `None` if the update changes nothing. Lists that do not exist
+    /// are ignored.
+    fn changes_from(&self, current: &Preferences) -> Option<PreferenceChanges> {
+        let mut details = serde_json::Map::new();
+        if self.name.as_ref() != current.name {
+            details.insert(
+                "name".into(),
+                serde_json::json!({ "from": current.name, "to": self.name.as_ref() }),
+            );
+        }
+        if self.frequency != current.frequency {
+            details.insert(
+                "frequency".into(),
+                serde_json::json!({
+                    "from": current.frequency.as_str(),
+                    "to": self.frequency.as_str(),
+                }),
+            );
+        }
+        let joined: Vec<String> = current
+            .lists
+            .iter()
+            .filter(|l| !l.subscribed && self.lists.contains(&l.name))
+            .map(|l| l.name.clone())
+            .collect();
+        let left: Vec<String> = current
+            .lists
+            .iter()
+            .filter(|l| l.subscribed && !self.lists.contains(&l.name))
+            .map(|l| l.name.clone())
+            .collect();
+        if !joined.is_empty() {
+            details.insert("joined".into(), serde_json::json!(joined));
+        }
+        if !left.is_empty() {
+            details.insert("left".into(), serde_json::json!(left));
+        }
+        if details.is_empty() {
+            return None;
+        }
+        Some(PreferenceChanges {
+            details: serde_json::Value::Object(details),
+            joined,
+            left,
+        })
+    }
+}
+
+/// Where subscribers are stored.
+///
+/// Every change is recorded in the subscriber's history along with it.
+#[async_trait::async_trait]
+pub trait SubscriberRepository: Send + Sync {
+    /// The custom fields of the lists new subscribers join, in display
+    /// order.
+    async fn custom_field_definitions(&self) -> Result<Vec<CustomFieldDefinition>, anyhow::Error>;
+
+    /// Whether there is a mailing list named `list`.
+    async fn list_exists(&self, list: &str) -> Result<bool, anyhow::Error>;
+
+    /// The custom fields of `list`, in display order.
+    async fn custom_field_definitions_of(
+        &self,
+        list: &str,
+    ) -> Result<Vec<CustomFieldDefinition>, anyhow::Error>;
+
+    /// Adds a custom field to its list. Returns `false` if the list already
+    /// has a field with the same name.
+    async fn insert_custom_field(
+        &self,
+        definition: &CustomFieldDefinition,
+    ) -> Result<bool, anyhow::Error>;
+
+    /// Removes a custom field from `list`. Returns `false` if there was no
+    /// such field.
+    async fn delete_custom_field(&self, list: &str, name: &str) -> Result<bool, anyhow::Error>;
+
+    /// Records a subscription request for `new_subscriber`'s address, along
+    /// with the token that confirms it.
+    ///
+    /// Addresses are matched on their normalized form. Returns the
+    /// subscriber if they (still) need to confirm their address, or `None`
+    /// if they are already confirmed or may not sign up again; the token is
+    /// only stored in the former case.
+    async fn insert(
+        &self,
+        new_subscriber: &NewSubscriber,
+        confirmation_token: &str,
+    ) -> Result<Option<Subscriber>, anyhow::Error>;
+
+    async fn find_by_id(&self, subscriber_id: Uuid) -> Result<Option<Subscriber>, anyhow::Error>;
+
+    /// Looks a subscriber up by the normalized form of their address.
+    async fn find_by_email(
+        &self,
+        email: &SubscriberEmail,
+    ) -> Result<Option<Subscriber>, anyhow::Error>;
+
+    /// Saves a status change made through the [`Subscriber`] methods.
+    async fn update_status(&self, subscriber: &Subscriber) -> Result<(), anyhow::Error>;
+
+    /// Subscribers matching `filter`, oldest first.
+    async fn list(&self, filter: &SubscriberFilter) -> Result<Vec<Subscriber>, anyhow::Error>;
+
+    /// The subscriber a confirmation token was issued to, and when.
+    async fn find_confirmation_token(
+        &self,
+        confirmation_token: &str,
+    ) -> Result<Option<(Uuid, DateTime<Utc>)>, anyhow::Error>;
+
+    /// Confirms a subscriber and invalidates every confirmation token that
+    /// was issued to them.
+    async fn confirm(&self, subscriber_id: Uuid) -> Result<ConfirmOutcome, anyhow::Error>;
+
+    /// Whether a subscriber other than `subscriber_id` has `email`.
+    async fn is_email_taken(
+        &self,
+        subscriber_id: Uuid,
+        email: &SubscriberEmail,
+    ) -> Result<bool, anyhow::Error>;
+
+    /// Stores a request to move a subscriber to `new_email`, verified with
+    /// `change_token`.
+    async fn insert_email_change(
+        &self,
+        subscriber_id: Uuid,
+        new_email: &SubscriberEmail,
+        change_token: &str,
+    ) -> Result<(), anyhow::Error>;
+
+    async fn find_email_change(
+        &self,
+        change_token: &str,
+    ) -> Result<Option<EmailChangeRequest>, anyhow::Error>;
+
+    /// Moves a confirmed subscriber to the address they asked for and
+    /// discards their other pending changes.
+    async fn change_email(
+        &self,
+        request: &EmailChangeRequest,
+    ) -> Result<EmailChangeOutcome, anyhow::Error>;
+
+    /// The preferences of a subscriber who can manage them.
+    async fn preferences(&self, subscriber_id: Uuid) -> Result<Option<Preferences>, anyhow::Error>;
+
+    /// Replaces a subscriber's name, frequency and list memberships, and
+    /// returns their preferences as saved, or `None` if they can no longer
+    /// manage them.
+    async fn update_preferences(
+        &self,
+        subscriber_id: Uuid,
+        update: &PreferencesUpdate,
+    ) -> Result<Option<Preferences>, anyhow::Error>;
+
+    /// Picks a pending subscriber who signed up between `purge_cutoff` and
+    /// `remind_cutoff` and has not been reminded yet, records that they were
+    /// reminded and stores `confirmation_token` for them.
+    ///
+    /// Returns `None` once nobody is due. Callers running at the same time
+    /// never get the same subscriber.
+    async fn claim_reminder(
+        &self,
+        remind_cutoff: DateTime<Utc>,
+        purge_cutoff: DateTime<Utc>,
+        confirmation_token: &str,
+    ) -> Result<Option<Subscriber>, anyhow::Error>;
+
+    /// Deletes subscribers who have been pending since before `cutoff`,
+    /// and returns how many.
+    ///
+    /// Their tokens and history go with them: they never consented, and
+    /// their history holds the very address purging them is meant to
+    /// forget.
+    async fn purge_unconfirmed(&self, cutoff: DateTime<Utc>) -> Result<u64, anyhow::Error>;
+
+    /// Deletes the confirmation tokens issued before `cutoff`, and returns
+    /// how many.
+    async fn expire_confirmation_tokens(&self, cutoff: DateTime<Utc>)
+        -> Result<u64, anyhow::Error>;
+
+    /// Deletes the email change requests made before `cutoff`, and returns
+    /// how many.
+    async fn expire_email_changes(&self, cutoff: DateTime<Utc>) -> Result<u64, anyhow::Error>;
+}
+
+/// Keeps subscribers in memory, for tests.
+#[derive(Default)]
+pub struct InMemorySubscriberRepository {
+    state: Mutex<InMemoryState>,
+}
+
+#[derive(Default)]
+struct InMemoryState {
+    /// With their normalized address, oldest first.
+    subscribers: Vec<(Subscriber, String)>,
+    /// Confirmation tokens, with the subscriber they were issued to and
+    /// when.
+    tokens: HashMap<String, (Uuid, DateTime<Utc>)>,
+    /// Pending email changes, by verification token.
+    email_changes: HashMap<String, EmailChangeRequest>,
+    /// Subscribers missing from here get every email as it goes out.
+    frequencies: HashMap<Uuid, SendingFrequency>,
+    /// When each subscriber last asked to join.
+    subscribed_at: HashMap<Uuid, DateTime<Utc>>,
+    /// Subscribers reminded to confirm since they last asked to join.
+    reminded: HashSet<Uuid>,
+    /// Every subscriber's history, oldest first.
+    events: Vec<(Uuid, SubscriptionEvent, serde_json::Value)>,
+    /// The lists new subscribers join, which are the only lists there are.
+    default_lists: Vec<String>,
+    definitions: Vec<CustomFieldDefinition>,
+    /// The lists each subscriber is on.
+    memberships: HashMap<Uuid, Vec<String>>,
+}
+
+impl InMemoryState {
+    fn find_mut(&mut self, subscriber_id: Uuid) -> Option<&mut (Subscriber, String)> {
+        self.subscribers
+            .iter_mut()
+            .find(|(s, _)| s.id() == subscriber_id)
+    }
+
+    fn preferences(&self, subscriber_id: Uuid) -> Option<Preferences> {
+        let (subscriber, _) = self
+            .subscribers
+            .iter()
+            .find(|(s, _)| s.id() == subscriber_id && s.can_manage_preferences())?;
+        let memberships = self.memberships.get(&subscriber_id);
+        let mut lists: Vec<ListPreference> = self
+            .default_lists
+            .iter()
+            .map(|list| ListPreference {
+                name: list.clone(),
+                description: String::new(),
+                subscribed: memberships.map(|m| m.contains(list)).unwrap_or(false),
+            })
+            .collect();
+        lists.sort_by(|a, b| a.name.cmp(&b.name));
+        Some(Preferences {
+            email: subscriber.email().to_string(),
+            name: subscriber.name().to_string(),
+            frequency: self
+                .frequencies
+                .get(&subscriber_id)
+                .copied()
+                .unwrap_or(SendingFrequency::Immediate),
+            lists,
+        })
+    }
+}
+
+impl InMemorySubscriberRepository {
+    /// New subscribers join `default_lists`; `definitions` are the custom
+    /// fields of every list.
+    pub fn with_lists(default_lists: Vec<String>, definitions: Vec<CustomFieldDefinition>) -> Self {
+        Self {
+            state: Mutex::new(InMemoryState {
+                default_lists,
+                definitions,
+                ..InMemoryState::default()
+            }),
+        }
+    }
+
+    /// The subscriber a confirmation token was issued to.
+    pub fn token_owner(&self, confirmation_token: &str) -> Option<Uuid> {
+        let state = self.state.lock().unwrap();
+        state
+            .tokens
+            .get(confirmation_token)
+            .map(|(subscriber_id, _)| *subscriber_id)
+    }
+
+    /// A subscriber's history, oldest first.
+    pub fn events(&self, subscriber_id: Uuid) -> Vec<(SubscriptionEvent, serde_json::Value)> {
+        let state = self.state.lock().unwrap();
+        state
+            .events
+            .iter()
+            .filter(|(id, _, _)| *id == subscriber_id)
+            .map(|(_, event, details)| (*event, details.clone()))
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl SubscriberRepository for InMemorySubscriberRepository {
+    async fn custom_field_definitions(&self) -> Result<Vec<CustomFieldDefinition>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .definitions
+            .iter()
+            .filter(|d| state.default_lists.iter().any(|list| list == d.list()))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_exists(&self, list: &str) -> Result<bool, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.default_lists.iter().any(|name| name == list))
+    }
+
+    async fn custom_field_definitions_of(
+        &self,
+        list: &str,
+    ) -> Result<Vec<CustomFieldDefinition>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .definitions
+            .iter()
+            .filter(|d| d.list() == list)
+            .cloned()
+            .collect())
+    }
+
+    async fn insert_custom_field(
+        &self,
+        definition: &CustomFieldDefinition,
+    ) -> Result<bool, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let exists = state
+            .definitions
+            .iter()
+            .any(|d| d.list() == definition.list() && d.name() == definition.name());
+        if !exists {
+            state.definitions.push(definition.clone());
+        }
+        Ok(!exists)
+    }
+
+    async fn delete_custom_field(&self, list: &str, name: &str) -> Result<bool, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.definitions.len();
+        state
+            .definitions
+            .retain(|d| d.list() != list || d.name() != name);
+        Ok(state.definitions.len() < before)
+    }
+
+    async fn insert(
+        &self,
+        new_subscriber: &NewSubscriber,
+        confirmation_token: &str,
+    ) -> Result<Option<Subscriber>, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let normalized = new_subscriber.email.normalized();
+        let existing = state
+            .subscribers
+            .iter_mut()
+            .find(|(_, email)| email == normalized);
+        let subscriber = match existing {
+            None => {
+                let subscriber = Subscriber::restore(
+                    Uuid::new_v4(),
+                    new_subscriber.email.as_ref().to_string(),
+                    new_subscriber.name.as_ref().to_string(),
+                    SubscriberStatus::PendingConfirmation,
+                    new_subscriber.custom_fields.clone(),
+                );
+                state
+                    .subscribers
+                    .push((subscriber.clone(), normalized.to_string()));
+                state
+                    .memberships
+                    .insert(subscriber.id(), state.default_lists.clone());
+                state.subscribed_at.insert(subscriber.id(), Utc::now());
+                state.events.push((
+                    subscriber.id(),
+                    SubscriptionEvent::Subscribed,
+                    serde_json::json!({ "email": new_subscriber.email.as_ref() }),
+                ));
+                subscriber
+            }
+            Some((existing, _)) => match existing.status() {
+                SubscriberStatus::PendingConfirmation => existing.clone(),
+                SubscriberStatus::Confirmed => return Ok(None),
+                _ => {
+                    if existing.resubscribe().is_err() {
+                        return Ok(None);
+                    }
+                    *existing = Subscriber::restore(
+                        existing.id(),
+                        new_subscriber.email.as_ref().to_string(),
+                        new_subscriber.name.as_ref().to_string(),
+                        existing.status(),
+                        new_subscriber.custom_fields.clone(),
+                    );
+                    state.subscribed_at.insert(existing.id(), Utc::now());
+                    state.reminded.remove(&existing.id());
+                    state.events.push((
+                        existing.id(),
+                        SubscriptionEvent::Subscribed,
+                        serde_json::json!({ "email": new_subscriber.email.as_ref() }),
+                    ));
+                    existing.clone()
+                }
+            },
+        };
+        state.tokens.insert(
+            confirmation_token.to_string(),
+            (subscriber.id(), Utc::now()),
+        );
+        Ok(Some(subscriber))
+    }
+
+    async fn find_by_id(&self, subscriber_id: Uuid) -> Result<Option<Subscriber>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .subscribers
+            .iter()
+            .find(|(s, _)| s.id() == subscriber_id)
+            .map(|(s, _)| s.clone()))
+    }
+
+    async fn find_by_email(
+        &self,
+        email: &SubscriberEmail,
+    ) -> Result<Option<Subscriber>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .subscribers
+            .iter()
+            .find(|(_, normalized)| normalized == email.normalized())
+            .map(|(s, _)| s.clone()))
+    }
+
+    async fn update_status(&self, subscriber: &Subscriber) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some((stored, _)) = state.find_mut(subscriber.id()) {
+            *stored = subscriber.clone();
+            state.events.push((
+                subscriber.id(),
+                SubscriptionEvent::StatusChanged,
+                serde_json::json!({ "status": subscriber.status().as_str() }),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn list(&self, filter: &SubscriberFilter) -> Result<Vec<Subscriber>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .subscribers
+            .iter()
+            .filter(|(s, _)| {
+                filter
+                    .status
+                    .map(|status| s.status() == status)
+                    .unwrap_or(true)
+            })
+            .filter(|(_, normalized)| {
+                filter
+                    .email_domain
+                    .as_deref()
+                    .map(|domain| normalized.rsplit_once('@').map(|(_, d)| d) == Some(domain))
+                    .unwrap_or(true)
+            })
+            .filter(|(s, _)| {
+                filter
+                    .list
+                    .as_ref()
+                    .map(|list| {
+                        state
+                            .memberships
+                            .get(&s.id())
+                            .map(|lists| lists.contains(list))
+                            .unwrap_or(false)
+                    })
+                    .unwrap_or(true)
+            })
+            .filter(|(s, _)| {
+                filter
+                    .custom_field
+                    .as_ref()
+                    .map(|field| field.matches(s.custom_fields()))
+                    .unwrap_or(true)
+            })
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .map(|(s, _)| s.clone())
+            .collect())
+    }
+
+    async fn find_confirmation_token(
+        &self,
+        confirmation_token: &str,
+    ) -> Result<Option<(Uuid, DateTime<Utc>)>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.tokens.get(confirmation_token).copied())
+    }
+
+    async fn confirm(&self, subscriber_id: Uuid) -> Result<ConfirmOutcome, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let (subscriber, _) = match state.find_mut(subscriber_id) {
+            Some(stored) => stored,
+            None => return Ok(ConfirmOutcome::UnknownSubscriber),
+        };
+        if let Err(e) = subscriber.confirm() {
+            return Ok(ConfirmOutcome::Rejected(e));
+        }
+        state.tokens.retain(|_, (owner, _)| *owner != subscriber_id);
+        state.events.push((
+            subscriber_id,
+            SubscriptionEvent::Confirmed,
+            serde_json::json!({}),
+        ));
+        Ok(ConfirmOutcome::Confirmed)
+    }
+
+    async fn is_email_taken(
+        &self,
+        subscriber_id: Uuid,
+        email: &SubscriberEmail,
+    ) -> Result<bool, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .subscribers
+            .iter()
+            .any(|(s, normalized)| s.id() != subscriber_id && normalized == email.normalized()))
+    }
+
+    async fn insert_email_change(
+        &self,
+        subscriber_id: Uuid,
+        new_email: &SubscriberEmail,
+        change_token: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.email_changes.insert(
+            change_token.to_string(),
+            EmailChangeRequest {
+                subscriber_id,
+                new_email: new_email.as_ref().to_string(),
+                new_normalized_email: new_email.normalized().to_string(),
+                requested_at: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn find_email_change(
+        &self,
+        change_token: &str,
+    ) -> Result<Option<EmailChangeRequest>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.email_changes.get(change_token).cloned())
+    }
+
+    async fn change_email(
+        &self,
+        request: &EmailChangeRequest,
+    ) -> Result<EmailChangeOutcome, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let taken = state.subscribers.iter().any(|(s, normalized)| {
+            s.id() != request.subscriber_id && normalized == &request.new_normalized_email
+        });
+        let (subscriber, normalized) = match state.find_mut(request.subscriber_id) {
+            Some((subscriber, normalized)) if subscriber.can_change_email() => {
+                (subscriber, normalized)
+            }
+            _ => return Ok(EmailChangeOutcome::NotAllowed),
+        };
+        if taken {
+            return Ok(EmailChangeOutcome::AddressTaken);
+        }
+        let old_email = subscriber.email().to_string();
+        *subscriber = Subscriber::restore(
+            subscriber.id(),
+            request.new_email.clone(),
+            subscriber.name().to_string(),
+            subscriber.status(),
+            subscriber.custom_fields().clone(),
+        );
+        *normalized = request.new_normalized_email.clone();
+        state.events.push((
+            request.subscriber_id,
+            SubscriptionEvent::EmailChanged,
+            serde_json::json!({ "from": old_email, "to": request.new_email }),
+        ));
+        state
+            .email_changes
+            .retain(|_, pending| pending.subscriber_id != request.subscriber_id);
+        Ok(EmailChangeOutcome::Changed { old_email })
+    }
+
+    async fn preferences(&self, subscriber_id: Uuid) -> Result<Option<Preferences>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.preferences(subscriber_id))
+    }
+
+    async fn update_preferences(
+        &self,
+        subscriber_id: Uuid,
+        update: &PreferencesUpdate,
+    ) -> Result<Option<Preferences>, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let current = match state.preferences(subscriber_id) {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+        if let Some(changes) = update.changes_from(&current) {
+            if let Some((subscriber, _)) = state.find_mut(subscriber_id) {
+                *subscriber = Subscriber::restore(
+                    subscriber.id(),
+                    subscriber.email().to_string(),
+                    update.name.as_ref().to_string(),
+                    subscriber.status(),
+                    subscriber.custom_fields().clone(),
+                );
+            }
+            state.frequencies.insert(subscriber_id, update.frequency);
+            let memberships = state.memberships.entry(subscriber_id).or_default();
+            memberships.retain(|list| !changes.left.contains(list));
+            memberships.extend(changes.joined);
+            state.events.push((
+                subscriber_id,
+                SubscriptionEvent::PreferencesUpdated,
+                changes.details,
+            ));
+        }
+        Ok(state.preferences(subscriber_id))
+    }
+
+    async fn claim_reminder(
+        &self,
+        remind_cutoff: DateTime<Utc>,
+        purge_cutoff: DateTime<Utc>,
+        confirmation_token: &str,
+    ) -> Result<Option<Subscriber>, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let due = state
+            .subscribers
+            .iter()
+            .map(|(s, _)| s)
+            .filter(|s| s.status() == SubscriberStatus::PendingConfirmation)
+            .filter(|s| !state.reminded.contains(&s.id()))
+            .filter_map(|s| Some((s, *state.subscribed_at.get(&s.id())?)))
+            .filter(|(_, at)| *at < remind_cutoff && *at >= purge_cutoff)
+            .min_by_key(|(_, at)| *at)
+            .map(|(s, _)| s.clone());
+        if let Some(subscriber) = &due {
+            state.reminded.insert(subscriber.id());
+            state.tokens.insert(
+                confirmation_token.to_string(),
+                (subscriber.id(), Utc::now()),
+            );
+        }
+        Ok(due)
+    }
+
+    async fn purge_unconfirmed(&self, cutoff: DateTime<Utc>) -> Result<u64, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let purged: HashSet<Uuid> = state
+            .subscribers
+            .iter()
+            .map(|(s, _)| s)
+            .filter(|s| s.status() == SubscriberStatus::PendingConfirmation)
+            .filter(|s| {
+                state
+                    .subscribed_at
+                    .get(&s.id())
+                    .map(|at| *at < cutoff)
+                    .unwrap_or(false)
+            })
+            .map(|s| s.id())
+            .collect();
+        state.subscribers.retain(|(s, _)| !purged.contains(&s.id()));
+        state.tokens.retain(|_, (owner, _)| !purged.contains(owner));
+        state
+            .email_changes
+            .retain(|_, request| !purged.contains(&request.subscriber_id));
+        state.events.retain(|(id, _, _)| !purged.contains(id));
+        for id in &purged {
+            state.frequencies.remove(id);
+            state.subscribed_at.remove(id);
+            state.reminded.remove(id);
+            state.memberships.remove(id);
+        }
+        Ok(purged.len() as u64)
+    }
+
+    async fn expire_confirmation_tokens(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> Result<u64, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.tokens.len();
+        state
+            .tokens
+            .retain(|_, (_, issued_at)| *issued_at >= cutoff);
+        Ok((before - state.tokens.len()) as u64)
+    }
+
+    async fn expire_email_changes(&self, cutoff: DateTime<Utc>) -> Result<u64, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.email_changes.len();
+        state
+            .email_changes
+            .retain(|_, request| request.requested_at >= cutoff);
+        Ok((before - state.email_changes.len()) as u64)
+    }
+}
+
+/// A row of `subscriptions`, as every query that loads a [`Subscriber`]
+/// selects it.
+struct SubscriberRow {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: SubscriberStatus,
+    custom_fields: serde_json::Value,
+}
+
+impl From<SubscriberRow> for Subscriber {
+    fn from(row: SubscriberRow) -> Self {
+        Subscriber::restore(
+            row.id,
+            row.email,
+            row.name,
+            row.status,
+            CustomFields::restore(row.custom_fields),
+        )
+    }
+}
+
+pub struct PostgresSubscriberRepository {
+    pool: ConnectionPool,
+}
+
+impl PostgresSubscriberRepository {
+    pub fn new(pool: ConnectionPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl SubscriberRepository for PostgresSubscriberRepository {
+    #[tracing::instrument(name = "Fetching custom field definitions", skip(self))]
+    async fn custom_field_definitions(&self) -> Result<Vec<CustomFieldDefinition>, anyhow::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT d.list_name, d.name, d.field_type, d.required, d.options
+            FROM custom_field_definitions d
+            JOIN mailing_lists l ON l.name = d.list_name
+            WHERE l.subscribe_by_default
+            ORDER BY d.created_at
+            "#
+        )
+        .fetch_all(&self.pool.get())
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let field_type = CustomFieldType::parse(&row.field_type, row.options)
+                    .map_err(anyhow::Error::msg)?;
+                CustomFieldDefinition::parse(row.list_name, row.name, field_type, row.required)
+                    .map_err(anyhow::Error::msg)
+            })
+            .collect()
+    }
+
+    async fn list_exists(&self, list: &str) -> Result<bool, anyhow::Error> {
+        let exists = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM mailing_lists WHERE name = $1) AS "exists!""#,
+            list
+        )
+        .fetch_one(&self.pool.get())
+        .await?
+        .exists;
+        Ok(exists)
+    }
+
+    #[tracing::instrument(name = "Fetching the custom fields of a list", skip(self))]
+    async fn custom_field_definitions_of(
+        &self,
+        list: &str,
+    ) -> Result<Vec<CustomFieldDefinition>, anyhow::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT list_name, name, field_type, required, options
+            FROM custom_field_definitions
+            WHERE list_name = $1
+            ORDER BY created_at
+            "#,
+            list
+        )
+        .fetch_all(&self.pool.get())
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let field_type = CustomFieldType::parse(&row.field_type, row.options)
+                    .map_err(anyhow::Error::msg)?;
+                CustomFieldDefinition::parse(row.list_name, row.name, field_type, row.required)
+                    .map_err(anyhow::Error::msg)
+            })
+            .collect()
+    }
+
+    #[tracing::instrument(
+        name = "Saving custom field definition in the database",
+        skip(self, definition)
+    )]
+    async fn insert_custom_field(
+        &self,
+        definition: &CustomFieldDefinition,
+    ) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO custom_field_definitions
+                (list_name, name, field_type, required, options, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (list_name, name) DO NOTHING
+            "#,
+            definition.list(),
+            definition.name(),
+            definition.field_type().as_str(),
+            definition.required(),
+            definition.field_type().options(),
+            Utc::now()
+        )
+        .execute(&self.pool.get())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn delete_custom_field(&self, list: &str, name: &str) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM custom_field_definitions WHERE list_name = $1 AND name = $2",
+            list,
+            name
+        )
+        .execute(&self.pool.get())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    #[tracing::instrument(
+        name = "Saving new subscriber details in the database",
+        skip(self, new_subscriber, confirmation_token)
+    )]
+    async fn insert(
+        &self,
+        new_subscriber: &NewSubscriber,
+        confirmation_token: &str,
+    ) -> Result<Option<Subscriber>, anyhow::Error> {
+        let mut transaction = self
+            .pool
+            .get()
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        let subscriber = match upsert_subscriber(&mut transaction, new_subscriber)
+            .await
+            .context("Failed to insert new subscriber in the database.")?
+        {
+            Some(subscriber) => subscriber,
+            None => return Ok(None),
+        };
+        store_token(&mut transaction, subscriber.id(), confirmation_token)
+            .await
+            .context("Failed to store the confirmation token for a new subscriber.")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to store a new subscriber.")?;
+        Ok(Some(subscriber))
+    }
+
+    async fn find_by_id(&self, subscriber_id: Uuid) -> Result<Option<Subscriber>, anyhow::Error> {
+        let subscriber = sqlx::query_as!(
+            SubscriberRow,
+            r#"
+            SELECT id, email, name, status AS "status: SubscriberStatus", custom_fields
+            FROM subscriptions
+            WHERE id = $1
+            "#,
+            subscriber_id
+        )
+        .fetch_optional(&self.pool.get())
+        .await?;
+        Ok(subscriber.map(Subscriber::from))
+    }
+
+    async fn find_by_email(
+        &self,
+        email: &SubscriberEmail,
+    ) -> Result<Option<Subscriber>, anyhow::Error> {
+        let subscriber = sqlx::query_as!(
+            SubscriberRow,
+            r#"
+            SELECT id, email, name, status AS "status: SubscriberStatus", custom_fields
+            FROM subscriptions
+            WHERE normalized_email = $1
+            "#,
+            email.normalized()
+        )
+        .fetch_optional(&self.pool.get())
+        .await?;
+        Ok(subscriber.map(Subscriber::from))
+    }
+
+    #[tracing::instrument(
+        name = "Saving a subscriber status",
+        skip(self, subscriber),
+        fields(subscriber_id = %subscriber.id(), status = %subscriber.status())
+    )]
+    async fn update_status(&self, subscriber: &Subscriber) -> Result<(), anyhow::Error> {
+        let mut transaction = self.pool.get().begin().await?;
+        sqlx::query!(
+            "UPDATE subscriptions SET status = $2 WHERE id = $1",
+            subscriber.id(),
+            subscriber.status() as SubscriberStatus,
+        )
+        .execute(&mut transaction)
+        .await?;
+        record_event(
+            &mut transaction,
+            subscriber.id(),
+            SubscriptionEvent::StatusChanged,
+            serde_json::json!({ "status": subscriber.status().as_str() }),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn list(&self, filter: &SubscriberFilter) -> Result<Vec<Subscriber>, anyhow::Error> {
+        let subscribers = sqlx::query_as!(
+            SubscriberRow,
+            r#"
+            SELECT id, email, name, status AS "status: SubscriberStatus", custom_fields
+            FROM subscriptions
+            WHERE ($1::subscriber_status IS NULL OR status = $1)
+                AND ($2::text IS NULL OR split_part(normalized_email, '@', 2) = $2)
+                AND ($3::text IS NULL OR EXISTS (
+                    SELECT 1 FROM list_memberships m
+                    WHERE m.subscriber_id = id AND m.list_name = $3
+                ))
+                AND ($4::jsonb IS NULL OR custom_fields @> $4)
+            ORDER BY subscribed_at, id
+            LIMIT $5
+            "#,
+            filter.status as Option<SubscriberStatus>,
+            filter.email_domain.as_deref(),
+            filter.list.as_deref(),
+            filter.custom_field.as_ref().map(CustomFieldFilter::as_json),
+            filter.limit.map(|limit| limit as i64),
+        )
+        .fetch_all(&self.pool.get())
+        .await?;
+        Ok(subscribers.into_iter().map(Subscriber::from).collect())
+    }
+
+    #[tracing::instrument(name = "Get subscriber_id from token", skip(self, confirmation_token))]
+    async fn find_confirmation_token(
+        &self,
+        confirmation_token: &str,
+    ) -> Result<Option<(Uuid, DateTime<Utc>)>, anyhow::Error> {
+        let result = sqlx::query!(
+            r#"
+            SELECT subscriber_id, created_at FROM subscription_tokens
+            WHERE subscription_token = $1
+            "#,
+            confirmation_token,
+        )
+        .fetch_optional(&self.pool.get())
+        .await?;
+        Ok(result.map(|r| (r.subscriber_id, r.created_at)))
+    }
+
+    #[tracing::instrument(name = "Mark subscriber as confirmed", skip(self))]
+    async fn confirm(&self, subscriber_id: Uuid) -> Result<ConfirmOutcome, anyhow::Error> {
+        let mut transaction = self
+            .pool
+            .get()
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        let mut subscriber = match fetch_subscriber_for_update(&mut transaction, subscriber_id)
+            .await
+            .context("Failed to load the subscriber.")?
+        {
+            Some(subscriber) => subscriber,
+            None => return Ok(ConfirmOutcome::UnknownSubscriber),
+        };
+        if let Err(e) = subscriber.confirm() {
+            return Ok(ConfirmOutcome::Rejected(e));
+        }
+        sqlx::query!(
+            "UPDATE subscriptions SET status = $2 WHERE id = $1",
+            subscriber.id(),
+            subscriber.status() as SubscriberStatus,
+        )
+        .execute(&mut transaction)
+        .await?;
+        record_event(
+            &mut transaction,
+            subscriber.id(),
+            SubscriptionEvent::Confirmed,
+            serde_json::json!({}),
+        )
+        .await?;
+        sqlx::query!(
+            "DELETE FROM subscription_tokens WHERE subscriber_id = $1",
+            subscriber.id(),
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to confirm a subscriber.")?;
+        Ok(ConfirmOutcome::Confirmed)
+    }
+
+    async fn is_email_taken(
+        &self,
+        subscriber_id: Uuid,
+        email: &SubscriberEmail,
+    ) -> Result<bool, anyhow::Error> {
+        let taken = sqlx::query!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM subscriptions
+                WHERE normalized_email = $2 AND id <> $1
+            ) AS "taken!"
+            "#,
+            subscriber_id,
+            email.normalized(),
+        )
+        .fetch_one(&self.pool.get())
+        .await?
+        .taken;
+        Ok(taken)
+    }
+
+    #[tracing::instrument(
+        name = "Store an email change request",
+        skip(self, new_email, change_token)
+    )]
+    async fn insert_email_change(
+        &self,
+        subscriber_id: Uuid,
+        new_email: &SubscriberEmail,
+        change_token: &str,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO email_change_requests
+                (token, subscriber_id, new_email, new_normalized_email)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            change_token,
+            subscriber_id,
+            new_email.as_ref(),
+            new_email.normalized(),
+        )
+        .execute(&self.pool.get())
+        .await?;
+        Ok(())
+    }
+
+    async fn find_email_change(
+        &self,
+        change_token: &str,
+    ) -> Result<Option<EmailChangeRequest>, anyhow::Error> {
+        let request = sqlx::query!(
+            r#"
+            SELECT subscriber_id, new_email, new_normalized_email, created_at
+            FROM email_change_requests
+            WHERE token = $1
+            "#,
+            change_token,
+        )
+        .fetch_optional(&self.pool.get())
+        .await?;
+        Ok(request.map(|r| EmailChangeRequest {
+            subscriber_id: r.subscriber_id,
+            new_email: r.new_email,
+            new_normalized_email: r.new_normalized_email,
+            requested_at: r.created_at,
+        }))
+    }
+
+    #[tracing::instrument(
+        name = "Change a subscriber's email address",
+        skip(self, request),
+        fields(subscriber_id = %request.subscriber_id)
+    )]
+    async fn change_email(
+        &self,
+        request: &EmailChangeRequest,
+    ) -> Result<EmailChangeOutcome, anyhow::Error> {
+        let mut transaction = self
+            .pool
+            .get()
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        let subscriber =
+            match fetch_subscriber_for_update(&mut transaction, request.subscriber_id).await? {
+                Some(subscriber) if subscriber.can_change_email() => subscriber,
+                _ => return Ok(EmailChangeOutcome::NotAllowed),
+            };
+        let updated = sqlx::query!(
+            "UPDATE subscriptions SET email = $2, normalized_email = $3 WHERE id = $1",
+            request.subscriber_id,
+            request.new_email,
+            request.new_normalized_email,
+        )
+        .execute(&mut transaction)
+        .await;
+        match updated {
+            Err(e) if is_unique_violation(&e) => return Ok(EmailChangeOutcome::AddressTaken),
+            updated => updated.context("Failed to update the subscriber's email address.")?,
+        };
+        record_event(
+            &mut transaction,
+            request.subscriber_id,
+            SubscriptionEvent::EmailChanged,
+            serde_json::json!({ "from": subscriber.email(), "to": request.new_email }),
+        )
+        .await?;
+        sqlx::query!(
+            "DELETE FROM email_change_requests WHERE subscriber_id = $1",
+            request.subscriber_id,
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(EmailChangeOutcome::Changed {
+            old_email: subscriber.email().to_string(),
+        })
+    }
+
+    async fn preferences(&self, subscriber_id: Uuid) -> Result<Option<Preferences>, anyhow::Error> {
+        let mut connection = self
+            .pool
+            .get()
+            .acquire()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        Ok(load_preferences(&mut connection, subscriber_id).await?)
+    }
+
+    #[tracing::instrument(name = "Save subscriber preferences", skip(self, update))]
+    async fn update_preferences(
+        &self,
+        subscriber_id: Uuid,
+        update: &PreferencesUpdate,
+    ) -> Result<Option<Preferences>, anyhow::Error> {
+        let mut transaction = self
+            .pool
+            .get()
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        let current = match load_preferences(&mut transaction, subscriber_id).await? {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+        if let Some(changes) = update.changes_from(&current) {
+            save_preferences(&mut transaction, subscriber_id, update, changes)
+                .await
+                .context("Failed to save the subscriber's preferences.")?;
+        }
+        let saved = load_preferences(&mut transaction, subscriber_id).await?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to save preferences.")?;
+        Ok(saved)
+    }
+
+    #[tracing::instrument(name = "Claim a confirmation reminder", skip(self, confirmation_token))]
+    async fn claim_reminder(
+        &self,
+        remind_cutoff: DateTime<Utc>,
+        purge_cutoff: DateTime<Utc>,
+        confirmation_token: &str,
+    ) -> Result<Option<Subscriber>, anyhow::Error> {
+        let mut transaction = self
+            .pool
+            .get()
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        let subscriber = sqlx::query_as!(
+            SubscriberRow,
+            r#"
+            SELECT id, email, name, status AS "status: SubscriberStatus", custom_fields
+            FROM subscriptions
+            WHERE status = $1
+                AND reminder_sent_at IS NULL
+                AND subscribed_at < $2
+                AND subscribed_at >= $3
+            ORDER BY subscribed_at
+            LIMIT 1
+            FOR UPDATE
+            SKIP LOCKED
+            "#,
+            SubscriberStatus::PendingConfirmation as SubscriberStatus,
+            remind_cutoff,
+            purge_cutoff,
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+        let subscriber = match subscriber {
+            Some(row) => Subscriber::from(row),
+            None => return Ok(None),
+        };
+        sqlx::query!(
+            "UPDATE subscriptions SET reminder_sent_at = now() WHERE id = $1",
+            subscriber.id()
+        )
+        .execute(&mut transaction)
+        .await?;
+        store_token(&mut transaction, subscriber.id(), confirmation_token).await?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to claim a reminder.")?;
+        Ok(Some(subscriber))
+    }
+
+    #[tracing::instrument(name = "Purge unconfirmed subscribers", skip(self))]
+    async fn purge_unconfirmed(&self, cutoff: DateTime<Utc>) -> Result<u64, anyhow::Error> {
+        let mut transaction = self
+            .pool
+            .get()
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        sqlx::query!(
+            r#"
+            DELETE FROM subscription_tokens
+            WHERE subscriber_id IN (
+                SELECT id FROM subscriptions
+                WHERE status = $1 AND subscribed_at < $2
+            )
+            "#,
+            SubscriberStatus::PendingConfirmation as SubscriberStatus,
+            cutoff
+        )
+        .execute(&mut transaction)
+        .await?;
+        // List memberships, email change requests and history go by
+        // `ON DELETE CASCADE`.
+        let purged = sqlx::query!(
+            r#"
+            DELETE FROM subscriptions
+            WHERE status = $1 AND subscribed_at < $2
+            "#,
+            SubscriberStatus::PendingConfirmation as SubscriberStatus,
+            cutoff
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to purge subscribers.")?;
+        Ok(purged)
+    }
+
+    #[tracing::instrument(name = "Expire confirmation tokens", skip(self))]
+    async fn expire_confirmation_tokens(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> Result<u64, anyhow::Error> {
+        let expired = sqlx::query!(
+            "DELETE FROM subscription_tokens WHERE created_at < $1",
+            cutoff
+        )
+        .execute(&self.pool.get())
+        .await?
+        .rows_affected();
+        Ok(expired)
+    }
+
+    #[tracing::instrument(name = "Expire email change requests", skip(self))]
+    async fn expire_email_changes(&self, cutoff: DateTime<Utc>) -> Result<u64, anyhow::Error> {
+        let expired = sqlx::query!(
+            "DELETE FROM email_change_requests WHERE created_at < $1",
+            cutoff
+        )
+        .execute(&self.pool.get())
+        .await?
+        .rows_affected();
+        Ok(expired)
+    }
+}
+
+/// Returns the preferences of a subscriber who can manage them, locking
+/// their row until the current transaction ends.
+#[tracing::instrument(name = "Load subscriber preferences", skip(connection))]
+async fn load_preferences(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Option<Preferences>, sqlx::Error> {
+    let subscriber = match fetch_subscriber_for_update(&mut *connection, subscriber_id).await? {
+        Some(subscriber) if subscriber.can_manage_preferences() => subscriber,
+        _ => return Ok(None),
+    };
+    let frequency = sqlx::query!(
+        "SELECT frequency FROM subscriptions WHERE id = $1",
+        subscriber_id
+    )
+    .fetch_one(&mut *connection)
+    .await?
+    .frequency;
+    let lists = sqlx::query_as!(
+        ListPreference,
+        r#"
+        SELECT l.name, l.description, m.subscriber_id IS NOT NULL AS "subscribed!"
+        FROM mailing_lists l
+        LEFT JOIN list_memberships m
+            ON m.list_name = l.name AND m.subscriber_id = $1
+        ORDER BY l.name
+        "#,
+        subscriber_id
+    )
+    .fetch_all(&mut *connection)
+    .await?;
+    Ok(Some(Preferences {
+        email: subscriber.email().to_string(),
+        name: subscriber.name().to_string(),
+        frequency: SendingFrequency::parse(&frequency)
+            .map_err(|e| sqlx::Error::Decode(e.into()))?,
+        lists,
+    }))
+}
+
+async fn save_preferences(
+    transaction: &mut PgConnection,
+    subscriber_id: Uuid,
+    update: &PreferencesUpdate,
+    changes: PreferenceChanges,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE subscriptions SET name = $2, frequency = $3 WHERE id = $1",
+        subscriber_id,
+        update.name.as_ref(),
+        update.frequency.as_str(),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM list_memberships
+        WHERE subscriber_id = $1 AND list_name = ANY($2)
+        "#,
+        subscriber_id,
+        &changes.left[..],
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (subscriber_id, list_name)
+        SELECT $1::uuid, * FROM UNNEST($2::text[])
+        "#,
+        subscriber_id,
+        &changes.joined[..],
+    )
+    .execute(&mut *transaction)
+    .await?;
+    record_event(
+        transaction,
+        subscriber_id,
+        SubscriptionEvent::PreferencesUpdated,
+        changes.details,
+    )
+    .await
+}
+
+/// Records a subscription request for `new_subscriber`'s email address.
+///
+/// Unsubscribed and bounced addresses go back to pending confirmation with
+/// the newly submitted details.
+///
+/// The subscriber's row stays locked until `transaction` ends, so concurrent
+/// requests for the same address are handled one after the other.
+async fn upsert_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    new_subscriber: &NewSubscriber,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    loop {
+        let inserted = sqlx::query_as!(
+            SubscriberRow,
+            r#"
+            INSERT INTO subscriptions
+                (id, email, normalized_email, name, subscribed_at, custom_fields, status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (normalized_email) DO NOTHING
+            RETURNING id, email, name, status AS "status: SubscriberStatus", custom_fields
+            "#,
+            Uuid::new_v4(),
+            new_subscriber.email.as_ref(),
+            new_subscriber.email.normalized(),
+            new_subscriber.name.as_ref(),
+            Utc::now(),
+            new_subscriber.custom_fields.as_json(),
+            SubscriberStatus::PendingConfirmation as SubscriberStatus,
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if let Some(inserted) = inserted {
+            let inserted = Subscriber::from(inserted);
+            join_default_lists(transaction, inserted.id()).await?;
+            record_event(
+                transaction,
+                inserted.id(),
+                SubscriptionEvent::Subscribed,
+                serde_json::json!({ "email": new_subscriber.email.as_ref() }),
+            )
+            .await?;
+            return Ok(Some(inserted));
+        }
+
+        // The conflicting row may have been deleted since we tried to insert
+        // ours, in which case we try again.
+        let mut existing = match fetch_by_email_for_update(
+            transaction,
+            new_subscriber.email.normalized(),
+        )
+        .await?
+        {
+            Some(existing) => existing,
+            None => continue,
+        };
+
+        return match existing.status() {
+            SubscriberStatus::PendingConfirmation => Ok(Some(existing)),
+            SubscriberStatus::Confirmed => Ok(None),
+            _ => {
+                if let Err(e) = existing.resubscribe() {
+                    tracing::info!(error.message = %e, "Ignoring a subscription request");
+                    return Ok(None);
+                }
+                let resubscribed = sqlx::query_as!(
+                    SubscriberRow,
+                    r#"
+                    UPDATE subscriptions
+                    SET
+                        status = $2,
+                        email = $3,
+                        name = $4,
+                        custom_fields = $5,
+                        subscribed_at = $6,
+                        reminder_sent_at = NULL
+                    WHERE id = $1
+                    RETURNING id, email, name, status AS "status: SubscriberStatus", custom_fields
+                    "#,
+                    existing.id(),
+                    existing.status() as SubscriberStatus,
+                    new_subscriber.email.as_ref(),
+                    new_subscriber.name.as_ref(),
+                    new_subscriber.custom_fields.as_json(),
+                    Utc::now()
+                )
+                .fetch_one(&mut *transaction)
+                .await?;
+                record_event(
+                    transaction,
+                    existing.id(),
+                    SubscriptionEvent::Subscribed,
+                    serde_json::json!({ "email": new_subscriber.email.as_ref() }),
+                )
+                .await?;
+                Ok(Some(Subscriber::from(resubscribed)))
+            }
+        };
+    }
+}
+
+/// Puts a new subscriber on every list that new subscribers join.
+async fn join_default_lists(
+    transaction: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (subscriber_id, list_name)
+        SELECT $1, name FROM mailing_lists WHERE subscribe_by_default
+        "#,
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+async fn fetch_by_email_for_update(
+    connection: &mut PgConnection,
+    normalized_email: &str,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    let subscriber = sqlx::query_as!(
+        SubscriberRow,
+        r#"
+        SELECT id, email, name, status AS "status: SubscriberStatus", custom_fields
+        FROM subscriptions
+        WHERE normalized_email = $1
+        FOR UPDATE
+        "#,
+        normalized_email,
+    )
+    .fetch_optional(connection)
+    .await?;
+    Ok(subscriber.map(Subscriber::from))
+}
+
+/// Loads a subscriber, locking their row until the current transaction
+/// ends.
+pub async fn fetch_subscriber_for_update(
+    connection: &mut PgConnection,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    let subscriber = sqlx::query_as!(
+        SubscriberRow,
+        r#"
+        SELECT id, email, name, status AS "status: SubscriberStatus", custom_fields
+        FROM subscriptions
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(connection)
+    .await?;
+    Ok(subscriber.map(Subscriber::from))
+}
+
+/// Recomputes every stored normalized address with the application's
+/// `rules`, which the migration that introduced them could only approximate,
+/// and merges subscribers whose addresses turn out to be the same.
+///
+/// Of those, the confirmed subscriber is kept, or else the oldest; the
+/// confirmation tokens and history of the others move over to them. Returns
+/// how many subscribers were renormalized or merged away.
+#[tracing::instrument(name = "Normalizing stored email addresses", skip(pool))]
+pub async fn normalize_stored_emails(
+    pool: &PgPool,
+    rules: EmailNormalization,
+) -> Result<u64, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, email, normalized_email
+        FROM subscriptions
+        ORDER BY status = $1 DESC, subscribed_at, id
+        FOR UPDATE
+        "#,
+        SubscriberStatus::Confirmed as SubscriberStatus,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+
+    let mut kept: HashMap<String, Uuid> = HashMap::new();
+    let mut renamed_ids = Vec::new();
+    let mut renamed_to = Vec::new();
+    let mut merged = 0;
+    for row in rows {
+        let normalized = match SubscriberEmail::parse_with(row.email, rules) {
+            Ok(email) => email.normalized().to_string(),
+            // Stored before validation got stricter: left as it is.
+            Err(_) => row.normalized_email.clone(),
+        };
+        match kept.get(&normalized) {
+            Some(&kept_id) => {
+                merge_subscriber(&mut transaction, row.id, kept_id).await?;
+                merged += 1;
+            }
+            None => {
+                kept.insert(normalized.clone(), row.id);
+                if normalized != row.normalized_email {
+                    renamed_ids.push(row.id);
+                    renamed_to.push(normalized);
+                }
+            }
+        }
+    }
+
+    // A subscriber may take the address another one is giving up, and
+    // uniqueness is checked row by row: everybody steps aside first.
+    sqlx::query!(
+        "UPDATE subscriptions SET normalized_email = id::text WHERE id = ANY($1)",
+        &renamed_ids[..],
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions s
+        SET normalized_email = renamed.normalized_email
+        FROM UNNEST($1::uuid[], $2::text[]) AS renamed(id, normalized_email)
+        WHERE s.id = renamed.id
+        "#,
+        &renamed_ids[..],
+        &renamed_to[..],
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(merged + renamed_ids.len() as u64)
+}
+
+/// Folds the subscriber `duplicate_id` into `kept_id` and deletes them.
+async fn merge_subscriber(
+    connection: &mut PgConnection,
+    duplicate_id: Uuid,
+    kept_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE subscription_tokens SET subscriber_id = $2 WHERE subscriber_id = $1",
+        duplicate_id,
+        kept_id
+    )
+    .execute(&mut *connection)
+    .await?;
+    sqlx::query!(
+        "UPDATE subscription_events SET subscriber_id = $2 WHERE subscriber_id = $1",
+        duplicate_id,
+        kept_id
+    )
+    .execute(&mut *connection)
+    .await?;
+    sqlx::query!("DELETE FROM subscriptions WHERE id = $1", duplicate_id)
+        .execute(&mut *connection)
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Store subscription token in the database",
+    skip(subscription_token, transaction)
+)]
+async fn store_token(
+    transaction: &mut PgConnection,
+    subscriber_id: Uuid,
+    subscription_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id)
+        VALUES ($1, $2)
+        "#,
+        subscription_token,
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::SubscriptionEvent;
+    use crate::domain::{
+        CustomFieldDefinition, CustomFieldType, CustomFields, InvalidTransition, NewSubscriber,
+        SendingFrequency, SubscriberEmail, SubscriberName, SubscriberStatus,
+    };
+    use crate::subscriber_repository::{
+        ConfirmOutcome, CustomFieldFilter, EmailChangeOutcome, InMemorySubscriberRepository,
+        PreferencesUpdate, SubscriberFilter, SubscriberRepository,
+    };
+    use claims::{assert_none, assert_ok, assert_some};
+    use serde_json::json;
+    use std::collections::{BTreeSet, HashMap};
+
+    fn new_subscriber(email: &str) -> NewSubscriber {
+        NewSubscriber {
+            email: SubscriberEmail::parse(email.into()).unwrap(),
+            name: SubscriberName::parse("le guin".into()).unwrap(),
+            custom_fields: CustomFields::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn addresses_are_matched_on_their_normalized_form() {
+        let repository = InMemorySubscriberRepository::default();
+        let first = assert_some!(assert_ok!(
+            repository
+                .insert(&new_subscriber("ursula@example.com"), "token-1")
+                .await
+        ));
+        let second = assert_some!(assert_ok!(
+            repository
+                .insert(&new_subscriber("Ursula@Example.com"), "token-2")
+                .await
+        ));
+
+        assert_eq!(first.id(), second.id());
+        assert_eq!(repository.token_owner("token-2"), Some(first.id()));
+        let found = repository
+            .find_by_email(&SubscriberEmail::parse("URSULA@example.com".into()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(found.map(|s| s.id()), Some(first.id()));
+    }
+
+    #[tokio::test]
+    async fn confirmed_subscribers_are_not_inserted_again() {
+        let repository = InMemorySubscriberRepository::default();
+        let mut subscriber = repository
+            .insert(&new_subscriber("ursula@example.com"), "token-1")
+            .await
+            .unwrap()
+            .unwrap();
+        subscriber.confirm().unwrap();
+        repository.update_status(&subscriber).await.unwrap();
+
+        assert_none!(assert_ok!(
+            repository
+                .insert(&new_subscriber("ursula@example.com"), "token-2")
+                .await
+        ));
+        assert_eq!(repository.token_owner("token-2"), None);
+        let stored = repository.find_by_id(subscriber.id()).await.unwrap();
+        assert_eq!(
+            stored.map(|s| s.status()),
+            Some(SubscriberStatus::Confirmed)
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribers_can_be_listed_by_status_and_domain() {
+        let repository = InMemorySubscriberRepository::default();
+        for email in ["a@example.com", "b@example.com", "c@example.org"] {
+            repository
+                .insert(&new_subscriber(email), email)
+                .await
+                .unwrap();
+        }
+        let mut confirmed = repository
+            .find_by_email(&SubscriberEmail::parse("b@example.com".into()).unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        confirmed.confirm().unwrap();
+        repository.update_status(&confirmed).await.unwrap();
+
+        let emails = |subscribers: Vec<crate::domain::Subscriber>| {
+            subscribers
+                .iter()
+                .map(|s| s.email().to_string())
+                .collect::<Vec<_>>()
+        };
+        let pending = repository
+            .list(&SubscriberFilter {
+                status: Some(SubscriberStatus::PendingConfirmation),
+                ..SubscriberFilter::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(emails(pending), vec!["a@example.com", "c@example.org"]);
+        let at_example_com = repository
+            .list(&SubscriberFilter {
+                email_domain: Some("example.com".into()),
+                limit: Some(1),
+                ..SubscriberFilter::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(emails(at_example_com), vec!["a@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn subscribers_can_be_segmented_by_list_and_custom_field() {
+        let company = CustomFieldDefinition::parse(
+            "newsletter".into(),
+            "company".into(),
+            CustomFieldType::Text,
+            false,
+        )
+        .unwrap();
+        let repository =
+            InMemorySubscriberRepository::with_lists(vec!["newsletter".into()], vec![company]);
+        let definitions = repository.custom_field_definitions().await.unwrap();
+        for (email, employer) in [("a@example.com", "Acme"), ("b@example.com", "Initech")] {
+            let raw = HashMap::from([("company".to_string(), employer.to_string())]);
+            let new_subscriber = NewSubscriber {
+                custom_fields: CustomFields::parse(raw, &definitions).unwrap(),
+                ..new_subscriber(email)
+            };
+            repository.insert(&new_subscriber, email).await.unwrap();
+        }
+
+        let at_acme = repository
+            .list(&SubscriberFilter {
+                list: Some("newsletter".into()),
+                custom_field: Some(CustomFieldFilter {
+                    list: "newsletter".into(),
+                    name: "company".into(),
+                    value: json!("Acme"),
+                }),
+                ..SubscriberFilter::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(at_acme.len(), 1);
+        assert_eq!(at_acme[0].email(), "a@example.com");
+        let on_other_list = repository
+            .list(&SubscriberFilter {
+                list: Some("events".into()),
+                ..SubscriberFilter::default()
+            })
+            .await
+            .unwrap();
+        assert!(on_other_list.is_empty());
+    }
+
+    #[tokio::test]
+    async fn confirming_invalidates_every_token_and_is_recorded() {
+        let repository = InMemorySubscriberRepository::default();
+        let subscriber = repository
+            .insert(&new_subscriber("ursula@example.com"), "token-1")
+            .await
+            .unwrap()
+            .unwrap();
+        repository
+            .insert(&new_subscriber("ursula@example.com"), "token-2")
+            .await
+            .unwrap();
+
+        let outcome = repository.confirm(subscriber.id()).await.unwrap();
+        let again = repository.confirm(subscriber.id()).await.unwrap();
+
+        assert_eq!(outcome, ConfirmOutcome::Confirmed);
+        assert_eq!(
+            again,
+            ConfirmOutcome::Rejected(InvalidTransition {
+                from: SubscriberStatus::Confirmed,
+                to: SubscriberStatus::Confirmed,
+            })
+        );
+        assert_none!(repository.find_confirmation_token("token-1").await.unwrap());
+        assert_none!(repository.find_confirmation_token("token-2").await.unwrap());
+        let events: Vec<_> = repository
+            .events(subscriber.id())
+            .into_iter()
+            .map(|(event, _)| event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![SubscriptionEvent::Subscribed, SubscriptionEvent::Confirmed]
+        );
+    }
+
+    #[tokio::test]
+    async fn status_changes_are_recorded() {
+        let repository = InMemorySubscriberRepository::default();
+        let mut subscriber = repository
+            .insert(&new_subscriber("ursula@example.com"), "token-1")
+            .await
+            .unwrap()
+            .unwrap();
+        subscriber.confirm().unwrap();
+        subscriber.unsubscribe().unwrap();
+        repository.update_status(&subscriber).await.unwrap();
+
+        assert_eq!(
+            repository.events(subscriber.id()).last(),
+            Some(&(
+                SubscriptionEvent::StatusChanged,
+                json!({ "status": "unsubscribed" })
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn an_email_change_cannot_take_another_subscribers_address() {
+        let repository = InMemorySubscriberRepository::default();
+        for email in ["a@example.com", "b@example.com"] {
+            let subscriber = repository
+                .insert(&new_subscriber(email), email)
+                .await
+                .unwrap()
+                .unwrap();
+            repository.confirm(subscriber.id()).await.unwrap();
+        }
+        let a = repository
+            .find_by_email(&SubscriberEmail::parse("a@example.com".into()).unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        for (token, new_email) in [("to-b", "B@example.com"), ("to-c", "c@example.com")] {
+            let new_email = SubscriberEmail::parse(new_email.into()).unwrap();
+            repository
+                .insert_email_change(a.id(), &new_email, token)
+                .await
+                .unwrap();
+        }
+
+        let to_b = repository.find_email_change("to-b").await.unwrap().unwrap();
+        let to_c = repository.find_email_change("to-c").await.unwrap().unwrap();
+        assert_eq!(
+            repository.change_email(&to_b).await.unwrap(),
+            EmailChangeOutcome::AddressTaken
+        );
+        assert_eq!(
+            repository.change_email(&to_c).await.unwrap(),
+            EmailChangeOutcome::Changed {
+                old_email: "a@example.com".into()
+            }
+        );
+        assert_none!(repository.find_email_change("to-b").await.unwrap());
+        let moved = repository.find_by_id(a.id()).await.unwrap().unwrap();
+        assert_eq!(moved.email(), "c@example.com");
+    }
+
+    #[tokio::test]
+    async fn only_preference_changes_are_recorded() {
+        let repository =
+            InMemorySubscriberRepository::with_lists(vec!["newsletter".into()], Vec::new());
+        let subscriber = repository
+            .insert(&new_subscriber("ursula@example.com"), "token-1")
+            .await
+            .unwrap()
+            .unwrap();
+        let update = PreferencesUpdate {
+            name: SubscriberName::parse("ursula k. le guin".into()).unwrap(),
+            frequency: SendingFrequency::Weekly,
+            lists: BTreeSet::new(),
+        };
+
+        let saved = repository
+            .update_preferences(subscriber.id(), &update)
+            .await
+            .unwrap()
+            .unwrap();
+        repository
+            .update_preferences(subscriber.id(), &update)
+            .await
+            .unwrap();
+
+        assert_eq!(saved.name, "ursula k. le guin");
+        assert_eq!(saved.frequency, SendingFrequency::Weekly);
+        assert!(!saved.lists[0].subscribed);
+        let updates: Vec<_> = repository
+            .events(subscriber.id())
+            .into_iter()
+            .filter(|(event, _)| *event == SubscriptionEvent::PreferencesUpdated)
+            .collect();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].1["left"], json!(["newsletter"]));
+    }
+
+    #[tokio::test]
+    async fn a_list_has_at_most_one_custom_field_with_a_given_name() {
+        let repository =
+            InMemorySubscriberRepository::with_lists(vec!["newsletter".into()], Vec::new());
+        let company = CustomFieldDefinition::parse(
+            "newsletter".into(),
+            "company".into(),
+            CustomFieldType::Text,
+            false,
+        )
+        .unwrap();
+
+        assert!(repository.insert_custom_field(&company).await.unwrap());
+        assert!(!repository.insert_custom_field(&company).await.unwrap());
+        let names: Vec<String> = repository
+            .custom_field_definitions_of("newsletter")
+            .await
+            .unwrap()
+            .iter()
+            .map(|d| d.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["company"]);
+
+        assert!(repository
+            .delete_custom_field("newsletter", "company")
+            .await
+            .unwrap());
+        assert!(!repository
+            .delete_custom_field("newsletter", "company")
+            .await
+            .unwrap());
+    }
+}
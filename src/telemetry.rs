@@ -1,8 +1,16 @@
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use std::io::Write;
 use tracing::{subscriber::set_global_default, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, reload, EnvFilter, Registry};
 
+static LOG_FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+/// Builds the subscriber, filtering with `RUST_LOG` or, if it is not set,
+/// `env_filter`. The filter of the first subscriber built can later be
+/// changed with [`set_log_filter`].
 pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
@@ -13,6 +21,8 @@ where
 {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    let _ = LOG_FILTER.set(handle);
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
     Registry::default()
         .with(env_filter)
@@ -24,3 +34,37 @@ pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("Failed to set subscriber");
 }
+
+/// Replaces the log filter, unless `RUST_LOG` is set: it takes precedence.
+pub fn set_log_filter(directives: &str) -> Result<(), anyhow::Error> {
+    if std::env::var_os("RUST_LOG").is_some() {
+        tracing::warn!("Keeping the log filter from RUST_LOG");
+        return Ok(());
+    }
+    let filter = EnvFilter::try_new(directives).context("Invalid log filter.")?;
+    match LOG_FILTER.get() {
+        Some(handle) => handle
+            .reload(filter)
+            .context("Failed to replace the log filter."),
+        None => Ok(()),
+    }
+}
+
+/// Exports the spans still queued for the tracer provider and writes out
+/// log lines still buffered for stdout, where the application logs. Call it
+/// right before exiting.
+pub fn flush_telemetry() {
+    opentelemetry::global::shutdown_tracer_provider();
+    let _ = std::io::stdout().flush();
+}
+
+/// Runs a blocking closure on the blocking thread pool, keeping it attached to
+/// the caller's tracing span.
+pub fn spawn_blocking_with_tracing<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+}
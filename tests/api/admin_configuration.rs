@@ -0,0 +1,50 @@
+use crate::helpers::spawn_app;
+use serde_json::Value;
+
+#[tokio::test]
+async fn the_configuration_requires_credentials() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/configuration", &app.host))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_configuration_is_listed_with_secrets_redacted_and_sources() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/configuration", &app.host))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(200, response.status().as_u16());
+    let entries: Vec<Value> = response.json().await.unwrap();
+    let entry = |key: &str| {
+        entries
+            .iter()
+            .find(|entry| entry["key"] == key)
+            .unwrap_or_else(|| panic!("{} is missing", key))
+            .clone()
+    };
+    for secret in [
+        "application.hmac_secret",
+        "bot_protection.hmac_secret",
+        "database.password",
+        "email_client.api_key",
+    ] {
+        assert_eq!(entry(secret)["value"], "[REDACTED]");
+    }
+    let host = entry("application.host");
+    assert_eq!(host["value"], "127.0.0.1");
+    assert!(host["source"].as_str().unwrap().ends_with("local.yaml"));
+    let timeout = entry("email_client.timeout_milliseconds");
+    assert!(timeout["source"].as_str().unwrap().ends_with("base.yaml"));
+}
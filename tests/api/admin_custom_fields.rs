@@ -0,0 +1,191 @@
+use crate::helpers::spawn_app;
+use serde_json::json;
+
+#[tokio::test]
+async fn custom_field_endpoints_reject_requests_without_credentials() {
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!(
+            "{}/admin/lists/newsletter/custom_fields",
+            &app.host
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn custom_field_endpoints_reject_an_invalid_password() {
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!(
+            "{}/admin/lists/newsletter/custom_fields",
+            &app.host
+        ))
+        .basic_auth(&app.test_user.username, Some("not-the-password"))
+        .json(&json!({"name": "company", "field_type": "text"}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn defined_custom_fields_are_listed() {
+    let app = spawn_app().await;
+    let definition = json!({
+        "name": "role",
+        "field_type": "enum",
+        "required": true,
+        "options": ["engineer", "manager"]
+    });
+    app.post_custom_field(&definition).await;
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/lists/newsletter/custom_fields",
+            &app.host
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(200, response.status().as_u16());
+    let fields: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(fields, json!([definition]));
+}
+
+#[tokio::test]
+async fn invalid_custom_field_definitions_are_rejected() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            json!({"name": "Company", "field_type": "text"}),
+            "an invalid name",
+        ),
+        (
+            json!({"name": "email", "field_type": "text"}),
+            "a reserved name",
+        ),
+        (
+            json!({"name": "size", "field_type": "colour"}),
+            "an unknown type",
+        ),
+        (
+            json!({"name": "role", "field_type": "enum"}),
+            "an enum without options",
+        ),
+    ];
+
+    for (definition, description) in test_cases {
+        let response = app.post_custom_field(&definition).await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request for {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn defining_the_same_custom_field_twice_is_a_conflict() {
+    let app = spawn_app().await;
+    let definition = json!({"name": "company", "field_type": "text"});
+
+    assert_eq!(
+        201,
+        app.post_custom_field(&definition).await.status().as_u16()
+    );
+    assert_eq!(
+        409,
+        app.post_custom_field(&definition).await.status().as_u16()
+    );
+}
+
+#[tokio::test]
+async fn deleting_an_unknown_custom_field_returns_404() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .delete(format!(
+            "{}/admin/lists/newsletter/custom_fields/company",
+            &app.host
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn custom_fields_of_an_unknown_list_return_404() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/lists/podcast/custom_fields", &app.host))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&json!({"name": "company", "field_type": "text"}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn custom_fields_belong_to_their_list() {
+    let app = spawn_app().await;
+    sqlx::query!(
+        "INSERT INTO mailing_lists (name, description, subscribe_by_default) \
+         VALUES ('events', 'Meetups near you.', FALSE)"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/admin/lists/events/custom_fields", &app.host))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&json!({"name": "city", "field_type": "text", "required": true}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(201, response.status().as_u16());
+
+    let newsletter_fields: serde_json::Value = client
+        .get(format!(
+            "{}/admin/lists/newsletter/custom_fields",
+            &app.host
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(newsletter_fields, json!([]));
+
+    // New subscribers do not join the list, so they are not asked for its
+    // fields.
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&city=Portland".into())
+        .await;
+    assert_eq!(400, response.status().as_u16());
+}
@@ -0,0 +1,181 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn spawn_protected_app(min_form_age_seconds: u64, proof_of_work_difficulty: u8) -> TestApp {
+    spawn_app_with(|configuration| {
+        configuration.rate_limit.enabled = false;
+        configuration.bot_protection.enabled = true;
+        configuration.bot_protection.min_form_age_seconds = min_form_age_seconds;
+        configuration.bot_protection.proof_of_work_difficulty = proof_of_work_difficulty;
+    })
+    .await
+}
+
+async fn mount_email_server(app: &TestApp, expected_emails: u64) {
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected_emails)
+        .mount(&app.email_server)
+        .await;
+}
+
+fn solve(form_token: &str, difficulty: u32) -> String {
+    (0u64..)
+        .map(|n| n.to_string())
+        .find(|nonce| {
+            let digest = Sha256::digest(format!("{}:{}", form_token, nonce).as_bytes());
+            u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]).leading_zeros()
+                >= difficulty
+        })
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_submission_with_a_valid_form_token_is_accepted() {
+    let app = spawn_protected_app(0, 0).await;
+    mount_email_server(&app, 1).await;
+
+    let challenge = app.get_challenge().await;
+    assert_eq!(challenge["honeypot_field"], "website");
+    assert!(challenge.get("proof_of_work").is_none());
+    let response = app
+        .post_subscriptions_json(&json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "website": "",
+            "form_token": challenge["form_token"],
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn the_default_configuration_accepts_plain_form_submissions() {
+    let app = spawn_app_with(|_| {}).await;
+    mount_email_server(&app, 1).await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn a_form_token_cannot_be_used_twice() {
+    let app = spawn_protected_app(0, 0).await;
+    mount_email_server(&app, 1).await;
+
+    let challenge = app.get_challenge().await;
+    let first = app
+        .post_subscriptions_json(&json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "form_token": challenge["form_token"],
+        }))
+        .await;
+    let replay = app
+        .post_subscriptions_json(&json!({
+            "name": "le guin",
+            "email": "ursula_k_le_guin@gmail.com",
+            "form_token": challenge["form_token"],
+        }))
+        .await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(replay.status().as_u16(), 400);
+    let body: serde_json::Value = replay.json().await.unwrap();
+    assert_eq!(body["error"], "The form has already been submitted.");
+}
+
+#[tokio::test]
+async fn submissions_without_a_valid_form_token_are_rejected() {
+    let app = spawn_protected_app(0, 0).await;
+    mount_email_server(&app, 0).await;
+    let test_cases = vec![
+        (json!(null), "The form token is missing."),
+        (json!("1675000000.00.00"), "The form token is invalid."),
+    ];
+
+    for (form_token, error) in test_cases {
+        let response = app
+            .post_subscriptions_json(&json!({
+                "name": "le guin",
+                "email": "ursula_le_guin@gmail.com",
+                "form_token": form_token,
+            }))
+            .await;
+
+        assert_eq!(response.status().as_u16(), 400);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], error);
+    }
+}
+
+#[tokio::test]
+async fn submissions_made_too_quickly_are_rejected() {
+    let app = spawn_protected_app(60, 0).await;
+    mount_email_server(&app, 0).await;
+
+    let challenge = app.get_challenge().await;
+    let response = app
+        .post_subscriptions_json(&json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "form_token": challenge["form_token"],
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "The form was submitted too quickly.");
+}
+
+#[tokio::test]
+async fn submissions_that_fill_in_the_honeypot_are_silently_ignored() {
+    let app = spawn_protected_app(0, 0).await;
+    mount_email_server(&app, 0).await;
+
+    let challenge = app.get_challenge().await;
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&website=spam.example&form_token={}",
+            challenge["form_token"].as_str().unwrap()
+        ))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn a_proof_of_work_is_required_when_configured() {
+    let app = spawn_protected_app(0, 8).await;
+    mount_email_server(&app, 1).await;
+
+    let challenge = app.get_challenge().await;
+    assert_eq!(challenge["proof_of_work"]["difficulty"], 8);
+    let form_token = challenge["form_token"].as_str().unwrap();
+    let body = json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com",
+        "form_token": form_token,
+    });
+
+    let unsolved = app.post_subscriptions_json(&body).await;
+    let mut solved_body = body.clone();
+    solved_body["pow_nonce"] = json!(solve(form_token, 8));
+    let solved = app.post_subscriptions_json(&solved_body).await;
+
+    assert_eq!(unsolved.status().as_u16(), 400);
+    assert_eq!(solved.status().as_u16(), 200);
+}
@@ -0,0 +1,137 @@
+use crate::helpers::{spawn_app, TestApp};
+use chrono::{Duration, Utc};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod_axum::domain::SubscriberStatus;
+
+async fn create_pending_subscriber(app: &TestApp) {
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+async fn sent_emails(app: &TestApp) -> Vec<wiremock::Request> {
+    app.email_server.received_requests().await.unwrap()
+}
+
+#[tokio::test]
+async fn expired_confirmation_links_are_rejected_with_a_410() {
+    let app = spawn_app().await;
+    create_pending_subscriber(&app).await;
+    let confirmation_link = app.get_confirmation_links(&sent_emails(&app).await[0]).html;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '3 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriberStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriberStatus::PendingConfirmation);
+}
+
+#[tokio::test]
+async fn unconfirmed_subscribers_are_reminded_once_with_a_fresh_link() {
+    let app = spawn_app().await;
+    create_pending_subscriber(&app).await;
+
+    // Nobody is due for a reminder yet.
+    assert_eq!(app.run_cleanup(Utc::now()).await.reminders_sent, 0);
+    let a_day_later = Utc::now() + Duration::hours(25);
+    let report = app.run_cleanup(a_day_later).await;
+    let again = app.run_cleanup(a_day_later).await;
+
+    assert_eq!(report.reminders_sent, 1);
+    assert_eq!(again.reminders_sent, 0);
+    let emails = sent_emails(&app).await;
+    assert_eq!(emails.len(), 2);
+    let reminder_link = app.get_confirmation_links(&emails[1]).html;
+    let response = reqwest::get(reminder_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn subscribers_who_never_confirm_are_purged_after_the_retention_window() {
+    let app = spawn_app().await;
+    create_pending_subscriber(&app).await;
+
+    let report = app.run_cleanup(Utc::now() + Duration::days(8)).await;
+
+    assert_eq!(report.subscribers_purged, 1);
+    // Past the retention window, nobody is reminded any more.
+    assert_eq!(report.reminders_sent, 0);
+    let remaining = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(remaining.is_empty());
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_are_never_purged() {
+    let app = spawn_app().await;
+    create_pending_subscriber(&app).await;
+    let confirmation_link = app.get_confirmation_links(&sent_emails(&app).await[0]).html;
+    reqwest::get(confirmation_link).await.unwrap();
+
+    let report = app.run_cleanup(Utc::now() + Duration::days(30)).await;
+
+    assert_eq!(report.subscribers_purged, 0);
+    assert_eq!(report.reminders_sent, 0);
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriberStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, SubscriberStatus::Confirmed);
+}
+
+#[tokio::test]
+async fn expired_tokens_are_deleted() {
+    let app = spawn_app().await;
+    create_pending_subscriber(&app).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '3 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let report = app.run_cleanup(Utc::now()).await;
+
+    assert_eq!(report.tokens_expired, 1);
+    assert_eq!(report.email_change_requests_expired, 0);
+    let tokens = sqlx::query!("SELECT subscription_token FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(tokens.is_empty());
+}
+
+#[tokio::test]
+async fn cleanup_metrics_are_exposed_to_admins() {
+    let app = spawn_app().await;
+    let url = format!("{}/admin/metrics", app.host);
+
+    let anonymous = reqwest::get(&url).await.unwrap();
+    let admin = reqwest::Client::new()
+        .get(&url)
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(anonymous.status().as_u16(), 401);
+    assert_eq!(admin.status().as_u16(), 200);
+    let body = admin.text().await.unwrap();
+    assert!(body.contains("confirmation_reminders_sent_total"));
+    assert!(body.contains("unconfirmed_subscribers_purged_total"));
+    assert!(body.contains("email_change_requests_expired_total"));
+}
@@ -0,0 +1,37 @@
+use crate::helpers::spawn_app;
+use secrecy::Secret;
+use zero2prod_axum::authentication::create_user;
+
+#[tokio::test]
+async fn a_created_admin_can_call_the_admin_endpoints() {
+    let app = spawn_app().await;
+
+    create_user("ursula", Secret::new("earthsea".into()), &app.db_pool)
+        .await
+        .expect("Failed to create the admin.");
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/lists/newsletter/custom_fields",
+            &app.host
+        ))
+        .basic_auth("ursula", Some("earthsea"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn usernames_must_be_unique() {
+    let app = spawn_app().await;
+    create_user("ursula", Secret::new("earthsea".into()), &app.db_pool)
+        .await
+        .unwrap();
+
+    let error = create_user("ursula", Secret::new("omelas".into()), &app.db_pool)
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.to_string(), "A user named 'ursula' already exists.");
+}
@@ -0,0 +1,76 @@
+use crate::helpers::spawn_app_with;
+use secrecy::Secret;
+use sqlx::{Connection, Executor, PgConnection};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod_axum::configuration::get_configuration;
+use zero2prod_axum::secrets::SECRET_FILE_CHECK_INTERVAL;
+
+#[tokio::test]
+async fn a_rotated_database_password_is_used_without_a_restart() {
+    let role = Uuid::new_v4().to_string();
+    let mut admin = PgConnection::connect_with(
+        &get_configuration()
+            .expect("Failed to read configuration.")
+            .database
+            .without_db(),
+    )
+    .await
+    .expect("Failed to connect to Postgres.");
+    admin
+        .execute(format!(r#"CREATE ROLE "{}" LOGIN CREATEDB PASSWORD 'first'"#, role).as_str())
+        .await
+        .unwrap();
+    // Where the role connects to when no database is given.
+    admin
+        .execute(format!(r#"CREATE DATABASE "{0}" OWNER "{0}""#, role).as_str())
+        .await
+        .unwrap();
+    let password_file = std::env::temp_dir().join(format!("db-password-{}", role));
+    std::fs::write(&password_file, "first").unwrap();
+
+    let username = role.clone();
+    let file = password_file.clone();
+    let app = spawn_app_with(move |configuration| {
+        configuration.database.username = username;
+        configuration.database.password = Secret::new("first".into());
+        configuration.database.password_file = Some(file);
+        configuration.rate_limit.enabled = false;
+    })
+    .await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    admin
+        .execute(format!(r#"ALTER ROLE "{}" PASSWORD 'second'"#, role).as_str())
+        .await
+        .unwrap();
+    std::fs::write(&password_file, "second").unwrap();
+    tokio::time::sleep(SECRET_FILE_CHECK_INTERVAL).await;
+    // Connections opened with the old password have to go.
+    admin
+        .execute(
+            format!(
+                "SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE usename = '{}'",
+                role
+            )
+            .as_str(),
+        )
+        .await
+        .unwrap();
+
+    let response = app
+        .post_subscriptions("name=tolkien&email=jrr_tolkien%40gmail.com".into())
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    std::fs::remove_file(&password_file).unwrap();
+}
@@ -1,10 +1,16 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
-use std::net::TcpListener;
 use uuid::Uuid;
-use zero2prod_axum::configuration::{get_configuration, DatabaseSettings};
+use wiremock::MockServer;
+use zero2prod_axum::cleanup_worker::{cleanup_once, CleanupReport, PendingSubscriptionPolicy};
+use zero2prod_axum::configuration::{get_configuration, DatabaseSettings, Settings};
 use zero2prod_axum::email_client::EmailClient;
-use zero2prod_axum::startup::run;
+use zero2prod_axum::signed_links::LinkSigner;
+use zero2prod_axum::startup::Application;
+use zero2prod_axum::subscriber_repository::PostgresSubscriberRepository;
 use zero2prod_axum::telemetry::{get_subscriber, init_subscriber};
 
 static TRACING: Lazy<()> = Lazy::new(|| {
@@ -22,35 +28,192 @@ static TRACING: Lazy<()> = Lazy::new(|| {
 
 pub struct TestApp {
     pub host: String,
+    pub port: u16,
     pub db_pool: PgPool,
+    pub email_server: MockServer,
+    pub test_user: TestUser,
+    pub email_client: EmailClient,
+    pub pending_policy: PendingSubscriptionPolicy,
+    pub link_signer: LinkSigner,
+}
+
+/// Confirmation links embedded in the request to the email API.
+pub struct ConfirmationLinks {
+    pub html: reqwest::Url,
+    pub plain_text: reqwest::Url,
+}
+
+impl TestApp {
+    pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.host))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_subscriptions_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.host))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Extracts the confirmation links from a request sent to the email API.
+    pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+
+        let get_link = |s: &str| {
+            let start = s.find("http").expect("No link found in the email body.");
+            let link: String = s[start..]
+                .chars()
+                .take_while(|c| !c.is_whitespace() && *c != '"')
+                .collect();
+            let mut confirmation_link = reqwest::Url::parse(&link).unwrap();
+            // Let's make sure we don't call random APIs on the web
+            assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
+            confirmation_link.set_port(Some(self.port)).unwrap();
+            confirmation_link
+        };
+
+        let html = get_link(body["message"]["html"].as_str().unwrap());
+        let plain_text = get_link(body["message"]["text"].as_str().unwrap());
+        ConfirmationLinks { html, plain_text }
+    }
+
+    pub async fn post_email_change(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/email_change", &self.host))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_challenge(&self) -> serde_json::Value {
+        reqwest::get(format!("{}/subscriptions/challenge", &self.host))
+            .await
+            .expect("Failed to execute request.")
+            .json()
+            .await
+            .unwrap()
+    }
+
+    /// Runs a pass of the background cleanup job, as if it were `now`.
+    pub async fn run_cleanup(&self, now: DateTime<Utc>) -> CleanupReport {
+        cleanup_once(
+            &PostgresSubscriberRepository::new(self.db_pool.clone().into()),
+            &self.email_client,
+            &self.host,
+            &self.link_signer,
+            &self.pending_policy,
+            now,
+        )
+        .await
+        .expect("Failed to run the cleanup job.")
+    }
+
+    pub async fn post_custom_field(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/admin/lists/newsletter/custom_fields",
+                &self.host
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+}
+
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    async fn store(&self, pool: &PgPool) {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        let password_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(15000, 2, 1, None).unwrap(),
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash,
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
 }
 
 pub async fn spawn_app() -> TestApp {
+    // Tests that are not about rate limiting should not run into it.
+    spawn_app_with(|configuration| {
+        configuration.rate_limit.enabled = false;
+    })
+    .await
+}
+
+/// Like [`spawn_app`], with `configure` applied to the settings first.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
-    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind port.");
-    let port = listener.local_addr().unwrap().port();
-    let host = format!("http://127.0.0.1:{}", port);
+    let email_server = MockServer::start().await;
 
     let mut configuration = get_configuration().expect("Failed to read configuration.");
     configuration.database.database_name = Uuid::new_v4().to_string();
+    configuration.email_client.base_url = email_server.uri();
+    // Use a random OS port.
+    configuration.application.port = 0;
+    configure(&mut configuration);
 
     let db_pool = configure_database(&configuration.database).await;
 
-    let sender_email = configuration
-        .email_client
-        .sender()
-        .expect("Invalid sender email address.");
-    let email_client = EmailClient::new(
-        configuration.email_client.base_url.clone(),
-        sender_email,
-        configuration.email_client.api_key.clone(),
-        configuration.email_client.timeout(),
-    );
-
-    let server = run(listener, db_pool.clone(), email_client);
-    tokio::spawn(server);
-    TestApp { host, db_pool }
+    let email_client = configuration.email_client.client().unwrap();
+    let pending_policy = configuration.pending_subscriptions.policy();
+    let link_signer = configuration.application.link_signer();
+
+    let application = Application::build(configuration)
+        .await
+        .expect("Failed to build application.");
+    let port = application.port();
+    let host = format!("http://127.0.0.1:{}", port);
+    tokio::spawn(application.run_until_stopped(std::future::pending()));
+
+    let test_user = TestUser::generate();
+    test_user.store(&db_pool).await;
+    TestApp {
+        host,
+        port,
+        db_pool,
+        email_server,
+        test_user,
+        email_client,
+        pending_policy,
+        link_signer,
+    }
 }
 
 pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
@@ -1,3 +1,14 @@
+mod admin_configuration;
+mod admin_custom_fields;
+mod bot_protection;
+mod cleanup_worker;
+mod create_admin;
+mod database_password;
 mod health_check;
 mod helpers;
+mod rate_limit;
+mod shutdown;
 mod subscriptions;
+mod subscriptions_confirm;
+mod subscriptions_email_change;
+mod subscriptions_preferences;
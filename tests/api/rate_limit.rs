@@ -0,0 +1,128 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod_axum::configuration::{RateLimitStorage, Settings};
+use zero2prod_axum::rate_limit::{PostgresStore, RateLimitStore};
+
+fn limit_ips_to_two_requests(storage: RateLimitStorage) -> impl FnOnce(&mut Settings) {
+    move |configuration| {
+        configuration.rate_limit.enabled = true;
+        configuration.rate_limit.storage = storage;
+        configuration.rate_limit.per_ip.capacity = 2;
+        configuration.rate_limit.per_ip.refill_interval_seconds = 60;
+    }
+}
+
+async fn assert_third_subscription_is_rate_limited(app: &TestApp) {
+    // Requests count towards the limit even if they fail validation.
+    let body = serde_json::json!({"name": "", "email": "ursula_le_guin@gmail.com"});
+    for _ in 0..2 {
+        let response = app.post_subscriptions_json(&body).await;
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    let response = app.post_subscriptions_json(&body).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after));
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "Too many requests. Please try again later.");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_429_once_an_ip_is_over_its_limit() {
+    let app = spawn_app_with(limit_ips_to_two_requests(RateLimitStorage::Memory)).await;
+    assert_third_subscription_is_rate_limited(&app).await;
+}
+
+#[tokio::test]
+async fn ip_limits_can_be_stored_in_postgres() {
+    let app = spawn_app_with(limit_ips_to_two_requests(RateLimitStorage::Postgres)).await;
+
+    assert_third_subscription_is_rate_limited(&app).await;
+
+    let buckets = sqlx::query!("SELECT key FROM rate_limit_buckets")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(buckets.len(), 1);
+    assert_eq!(buckets[0].key, "ip:127.0.0.1");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_429_once_an_email_is_over_its_limit() {
+    let app = spawn_app_with(|configuration| {
+        configuration.rate_limit.enabled = true;
+        configuration.rate_limit.per_email.capacity = 1;
+    })
+    .await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let first = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    // The limit applies to the normalized address.
+    let second = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40GMAIL.com".into())
+        .await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+    assert!(second.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn confirmations_are_rate_limited_per_ip() {
+    let app = spawn_app_with(limit_ips_to_two_requests(RateLimitStorage::Memory)).await;
+    let url = format!(
+        "{}/subscriptions/confirm?subscription_token=not-a-real-token",
+        app.host
+    );
+
+    for _ in 0..2 {
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+    }
+    let response = reqwest::get(&url).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn idle_buckets_are_evicted_from_postgres() {
+    let app = spawn_app().await;
+    sqlx::query!(
+        r#"
+        INSERT INTO rate_limit_buckets (key, tokens, updated_at)
+        VALUES ('ip:10.0.0.1', 0, now() - interval '2 hours'), ('ip:10.0.0.2', 0, now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let evicted = PostgresStore::new(app.db_pool.clone().into())
+        .evict_idle(Duration::from_secs(3600))
+        .await
+        .unwrap();
+
+    assert_eq!(evicted, 1);
+    let buckets = sqlx::query!("SELECT key FROM rate_limit_buckets")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(buckets.len(), 1);
+    assert_eq!(buckets[0].key, "ip:10.0.0.2");
+}
@@ -0,0 +1,31 @@
+use std::time::Duration;
+use zero2prod_axum::configuration::get_configuration;
+use zero2prod_axum::startup::Application;
+
+#[tokio::test]
+async fn the_server_stops_accepting_connections_after_a_shutdown_signal() {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    let application = Application::build(configuration)
+        .await
+        .expect("Failed to build application.");
+    let health_check = format!("http://127.0.0.1:{}/health_check", application.port());
+    let (signal, received) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(application.run_until_stopped(async move {
+        let _ = received.await;
+    }));
+    assert!(reqwest::get(&health_check)
+        .await
+        .unwrap()
+        .status()
+        .is_success());
+
+    signal.send(()).unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("The server did not shut down.")
+        .unwrap()
+        .unwrap();
+    assert!(reqwest::get(&health_check).await.is_err());
+}
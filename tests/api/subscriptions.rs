@@ -1,9 +1,20 @@
 use crate::helpers::spawn_app;
+use serde_json::json;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod_axum::domain::{EmailNormalization, SubscriberStatus};
+use zero2prod_axum::subscriber_repository::normalize_stored_emails;
 
 #[tokio::test]
 async fn subscribe_returns_200_for_valid_form_data() {
     let app = spawn_app().await;
     let client = reqwest::Client::new();
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
 
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
     let response = client
@@ -80,3 +91,582 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_empty() {
         );
     }
 }
+
+#[tokio::test]
+async fn subscribe_persists_custom_fields_as_typed_values() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    for definition in [
+        json!({"name": "company", "field_type": "text"}),
+        json!({"name": "employees", "field_type": "number"}),
+        json!({"name": "role", "field_type": "enum", "options": ["engineer", "manager"]}),
+    ] {
+        assert_eq!(
+            201,
+            app.post_custom_field(&definition).await.status().as_u16()
+        );
+    }
+
+    let body =
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&company=Acme&employees=12&role=manager";
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT custom_fields FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(
+        saved.custom_fields,
+        json!({"newsletter": {"company": "Acme", "employees": 12, "role": "manager"}})
+    );
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_when_custom_fields_are_invalid() {
+    let app = spawn_app().await;
+    for definition in [
+        json!({"name": "company", "field_type": "text", "required": true}),
+        json!({"name": "employees", "field_type": "number"}),
+    ] {
+        assert_eq!(
+            201,
+            app.post_custom_field(&definition).await.status().as_u16()
+        );
+    }
+    let test_cases = vec![
+        (
+            "name=Ursula&email=ursula_le_guin%40gmail.com",
+            "missing a required field",
+        ),
+        (
+            "name=Ursula&email=ursula_le_guin%40gmail.com&company=Acme&employees=many",
+            "a number field that is not a number",
+        ),
+        (
+            "name=Ursula&email=ursula_le_guin%40gmail.com&company=Acme&colour=blue",
+            "an undefined field",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_subscriptions(body.into()).await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_returns_200_for_valid_json_data() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_custom_field(&json!({"name": "employees", "field_type": "number"}))
+        .await;
+
+    let response = app
+        .post_subscriptions_json(&json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+            "employees": 12
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, name, custom_fields FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(
+        saved.custom_fields,
+        json!({"newsletter": {"employees": 12}})
+    );
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_json_400_when_json_data_is_invalid() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (json!({"name": "le guin"}), "missing the email"),
+        (
+            json!({"email": "ursula_le_guin@gmail.com"}),
+            "missing the name",
+        ),
+        (
+            json!({"name": "", "email": "ursula_le_guin@gmail.com"}),
+            "an empty name",
+        ),
+        (json!([]), "not an object"),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_subscriptions_json(&body).await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload was {}.",
+            description
+        );
+        assert_eq!("application/json", response.headers()["Content-Type"]);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["error"].is_string());
+    }
+}
+
+#[tokio::test]
+async fn subscribe_reports_missing_and_mistyped_json_fields() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (json!({"name": "le guin"}), "email", "missing"),
+        (
+            json!({"name": 12, "email": "ursula_le_guin@gmail.com"}),
+            "name",
+            "invalid_value",
+        ),
+    ];
+
+    for (body, field, reason) in test_cases {
+        let response = app.post_subscriptions_json(&body).await;
+
+        assert_eq!(400, response.status().as_u16());
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["fields"],
+            json!([{
+                "field": field,
+                "reason": reason,
+                "message": body["fields"][0]["message"],
+            }])
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_plain_text_400_for_invalid_form_data() {
+    let app = spawn_app().await;
+
+    let response = app.post_subscriptions("name=le%20guin".into()).await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_415_for_unsupported_content_types() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.host))
+        .header("Content-Type", "text/plain")
+        .body("ursula_le_guin@gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(415, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_lists_every_invalid_field_in_a_json_error() {
+    let app = spawn_app().await;
+    app.post_custom_field(&json!({"name": "employees", "field_type": "number"}))
+        .await;
+
+    let response = app
+        .post_subscriptions_json(&json!({
+            "name": "Ursula (Le Guin)",
+            "email": "definitely-not-an-email",
+            "employees": "many"
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["fields"],
+        json!([
+            {
+                "field": "name",
+                "reason": "forbidden_character",
+                "message": "The name must not contain `(` (found at position 7).",
+                "position": 7
+            },
+            {
+                "field": "email",
+                "reason": "malformed_email",
+                "message": "definitely-not-an-email is not a valid email address."
+            },
+            {
+                "field": "employees",
+                "reason": "invalid_value",
+                "message": "The value is not a valid number."
+            }
+        ])
+    );
+}
+
+#[tokio::test]
+async fn subscribe_reports_why_a_field_is_invalid() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            json!({"name": " ", "email": "ursula@gmail.com"}),
+            "name",
+            "empty",
+        ),
+        (
+            json!({"name": "a".repeat(257), "email": "ursula@gmail.com"}),
+            "name",
+            "too_long",
+        ),
+        (json!({"name": "Ursula", "email": ""}), "email", "empty"),
+        (
+            json!({"name": "Ursula", "email": "ursula@mailinator.com"}),
+            "email",
+            "disposable_domain",
+        ),
+        (
+            json!({"name": "Ursula", "email": "noreply@example.com"}),
+            "email",
+            "role_account",
+        ),
+    ];
+
+    for (body, field, reason) in test_cases {
+        let response = app.post_subscriptions_json(&body).await;
+
+        assert_eq!(400, response.status().as_u16());
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["fields"][0]["field"], field);
+        assert_eq!(body["fields"][0]["reason"], reason);
+    }
+}
+
+#[tokio::test]
+async fn subscribe_fails_without_leaking_details_if_there_is_a_fatal_database_error() {
+    let app = spawn_app().await;
+    sqlx::query!("ALTER TABLE subscriptions DROP COLUMN name;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(500, response.status().as_u16());
+    assert_eq!("", response.text().await.unwrap());
+}
+
+#[tokio::test]
+async fn subscribe_persists_the_new_subscriber_as_pending_confirmation() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriberStatus" FROM subscriptions"#,)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, SubscriberStatus::PendingConfirmation);
+}
+
+#[tokio::test]
+async fn subscribe_sends_a_confirmation_email_with_a_link() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    assert_eq!(confirmation_links.html, confirmation_links.plain_text);
+}
+
+#[tokio::test]
+async fn subscribe_fails_if_the_confirmation_email_cannot_be_sent() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(500, response.status().as_u16());
+    assert_eq!("", response.text().await.unwrap());
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_the_confirmation_email() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let first = app.post_subscriptions(body.into()).await;
+    let second = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    let requests = app.email_server.received_requests().await.unwrap();
+    let first_link = app.get_confirmation_links(&requests[0]).plain_text;
+    let second_link = app.get_confirmation_links(&requests[1]).plain_text;
+    assert_ne!(first_link, second_link);
+    // Both links are valid until the subscriber confirms
+    let response = reqwest::get(first_link).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_succeeds_without_sending_an_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request).plain_text;
+    reqwest::get(confirmation_link).await.unwrap();
+
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!(r#"SELECT status AS "status: SubscriberStatus" FROM subscriptions"#,)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, SubscriberStatus::Confirmed);
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_can_subscribe_again() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_subscriptions("name=Ursula&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved =
+        sqlx::query!(r#"SELECT name, status AS "status: SubscriberStatus" FROM subscriptions"#,)
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.status, SubscriberStatus::PendingConfirmation);
+    assert_eq!(2, app.email_server.received_requests().await.unwrap().len());
+}
+
+#[tokio::test]
+async fn subscribers_who_complained_are_not_subscribed_again() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    sqlx::query!("UPDATE subscriptions SET status = 'complained'",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_subscriptions("name=Ursula&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Same response as any other request, but no email is sent.
+    assert_eq!(200, response.status().as_u16());
+    let saved =
+        sqlx::query!(r#"SELECT name, status AS "status: SubscriberStatus" FROM subscriptions"#,)
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.status, SubscriberStatus::Complained);
+    assert_eq!(1, app.email_server.received_requests().await.unwrap().len());
+}
+
+#[tokio::test]
+async fn concurrent_duplicate_subscriptions_all_succeed() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let responses =
+        futures::future::join_all((0..5).map(|_| app.post_subscriptions(body.into()))).await;
+
+    for response in responses {
+        assert_eq!(200, response.status().as_u16());
+    }
+    let saved = sqlx::query!("SELECT id FROM subscriptions",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(1, saved.len());
+}
+
+#[tokio::test]
+async fn addresses_differing_only_by_case_are_the_same_subscriber() {
+    let app = spawn_app().await;
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=Alice&email=Alice%40Example.com".into())
+        .await;
+    let response = app
+        .post_subscriptions("name=Alice&email=alice%40example.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, normalized_email FROM subscriptions",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(1, saved.len());
+    assert_eq!(saved[0].email, "Alice@Example.com");
+    assert_eq!(saved[0].normalized_email, "alice@example.com");
+}
+
+#[tokio::test]
+async fn stored_addresses_are_renormalized_and_duplicates_merged() {
+    let app = spawn_app().await;
+    let rows = [
+        // Backfilled without IDNA encoding by the migration.
+        (
+            "ursula@Bücher.example",
+            "ursula@bücher.example",
+            SubscriberStatus::PendingConfirmation,
+            "2023-01-01T00:00:00Z",
+        ),
+        (
+            "URSULA@bücher.example",
+            "stale",
+            SubscriberStatus::Confirmed,
+            "2023-01-02T00:00:00Z",
+        ),
+        // Holds the address the confirmed subscriber normalizes to.
+        (
+            "other@bücher.example",
+            "ursula@xn--bcher-kva.example",
+            SubscriberStatus::PendingConfirmation,
+            "2023-01-03T00:00:00Z",
+        ),
+    ];
+    let mut ids = Vec::new();
+    for (email, normalized, status, subscribed_at) in rows {
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            r#"
+            INSERT INTO subscriptions (id, email, normalized_email, name, subscribed_at, status)
+            VALUES ($1, $2, $3, 'le guin', $4::text::timestamptz, $5)
+            "#,
+            id,
+            email,
+            normalized,
+            subscribed_at,
+            status as SubscriberStatus,
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+        ids.push(id);
+    }
+    sqlx::query!(
+        "INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ('token', $1)",
+        ids[0]
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let changed = normalize_stored_emails(&app.db_pool, EmailNormalization::default())
+        .await
+        .unwrap();
+
+    assert_eq!(changed, 3);
+    let saved =
+        sqlx::query!("SELECT id, normalized_email FROM subscriptions ORDER BY subscribed_at")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap();
+    let saved: Vec<_> = saved
+        .into_iter()
+        .map(|r| (r.id, r.normalized_email))
+        .collect();
+    assert_eq!(
+        saved,
+        vec![
+            (ids[1], "ursula@xn--bcher-kva.example".to_string()),
+            (ids[2], "other@xn--bcher-kva.example".to_string()),
+        ]
+    );
+    let token_owner = sqlx::query!("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .subscriber_id;
+    assert_eq!(token_owner, ids[1]);
+}
@@ -0,0 +1,251 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod_axum::domain::SubscriberStatus;
+use zero2prod_axum::signed_links::LinkPurpose;
+
+/// Subscribes and confirms `email`, returning the subscriber's manage token.
+async fn create_confirmed_subscriber(app: &TestApp, email: &str) -> String {
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({ "name": "le guin", "email": email }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_link = app
+        .get_confirmation_links(requests.last().unwrap())
+        .plain_text;
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let subscriber_id = subscriber_id(app, email).await;
+    app.link_signer
+        .sign(subscriber_id, LinkPurpose::ManageSubscription)
+}
+
+async fn subscriber_id(app: &TestApp, email: &str) -> Uuid {
+    sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.")
+        .id
+}
+
+async fn mount_email_api(app: &TestApp) {
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+/// The recipients of the emails sent so far, in order.
+async fn email_recipients(app: &TestApp) -> Vec<String> {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            body["message"]["to"][0]["email"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn changing_email_moves_the_subscription_after_verification() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    let token = create_confirmed_subscriber(&app, "ursula@gmail.com").await;
+    let subscriber_id = subscriber_id(&app, "ursula@gmail.com").await;
+
+    let response = app
+        .post_email_change(&serde_json::json!({
+            "token": token,
+            "new_email": "ursula@example.com",
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Nothing changes until the new address is verified.
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@gmail.com");
+    let requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_recipients(&app).await[1], "ursula@example.com");
+    let verification_link = app.get_confirmation_links(&requests[1]).html;
+
+    let response = reqwest::get(verification_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!(r#"SELECT id, email, normalized_email, status AS "status: SubscriberStatus" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.id, subscriber_id);
+    assert_eq!(saved.email, "ursula@example.com");
+    assert_eq!(saved.normalized_email, "ursula@example.com");
+    assert_eq!(saved.status, SubscriberStatus::Confirmed);
+    // The old address is told about the change.
+    assert_eq!(
+        email_recipients(&app).await,
+        vec!["ursula@gmail.com", "ursula@example.com", "ursula@gmail.com"]
+    );
+}
+
+#[tokio::test]
+async fn verification_links_can_only_be_used_once() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    let token = create_confirmed_subscriber(&app, "ursula@gmail.com").await;
+    app.post_email_change(&serde_json::json!({
+        "token": token,
+        "new_email": "ursula@example.com",
+    }))
+    .await;
+    let requests = app.email_server.received_requests().await.unwrap();
+    let verification_link = app.get_confirmation_links(&requests[1]).plain_text;
+    reqwest::get(verification_link.clone()).await.unwrap();
+
+    let response = reqwest::get(verification_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn email_change_requests_with_an_invalid_link_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    let token = create_confirmed_subscriber(&app, "ursula@gmail.com").await;
+    let forged = format!("{}.{}", Uuid::new_v4(), token.split_once('.').unwrap().1);
+
+    for token in [forged.as_str(), "garbage"] {
+        let response = app
+            .post_email_change(&serde_json::json!({
+                "token": token,
+                "new_email": "ursula@example.com",
+            }))
+            .await;
+
+        assert_eq!(response.status().as_u16(), 401);
+    }
+    assert_eq!(email_recipients(&app).await.len(), 1);
+}
+
+#[tokio::test]
+async fn pending_subscribers_cannot_change_their_email() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    let token = app.link_signer.sign(
+        subscriber_id(&app, "ursula@gmail.com").await,
+        LinkPurpose::ManageSubscription,
+    );
+
+    let response = app
+        .post_email_change(&serde_json::json!({
+            "token": token,
+            "new_email": "ursula@example.com",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_invalid_new_email_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    let token = create_confirmed_subscriber(&app, "ursula@gmail.com").await;
+
+    let response = app
+        .post_email_change(&serde_json::json!({
+            "token": token,
+            "new_email": "not-an-email",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["fields"][0]["field"], "new_email");
+    assert_eq!(body["fields"][0]["reason"], "malformed_email");
+}
+
+#[tokio::test]
+async fn changing_to_an_address_that_is_already_subscribed_sends_nothing() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    let token = create_confirmed_subscriber(&app, "ursula@gmail.com").await;
+    create_confirmed_subscriber(&app, "ursula@example.com").await;
+
+    let response = app
+        .post_email_change(&serde_json::json!({
+            "token": token,
+            "new_email": "Ursula@Example.com",
+        }))
+        .await;
+
+    // Same response as a successful request, so subscribers cannot be
+    // enumerated.
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(email_recipients(&app).await.len(), 2);
+}
+
+#[tokio::test]
+async fn verification_fails_if_the_address_was_taken_in_the_meantime() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    let token = create_confirmed_subscriber(&app, "ursula@gmail.com").await;
+    app.post_email_change(&serde_json::json!({
+        "token": token,
+        "new_email": "ursula@example.com",
+    }))
+    .await;
+    let requests = app.email_server.received_requests().await.unwrap();
+    let verification_link = app.get_confirmation_links(&requests[1]).plain_text;
+    create_confirmed_subscriber(&app, "ursula@example.com").await;
+
+    let response = reqwest::get(verification_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    let id = subscriber_id(&app, "ursula@gmail.com").await;
+    assert_ne!(id, subscriber_id(&app, "ursula@example.com").await);
+}
+
+#[tokio::test]
+async fn expired_verification_links_are_rejected_with_a_410() {
+    let app = spawn_app().await;
+    mount_email_api(&app).await;
+    let token = create_confirmed_subscriber(&app, "ursula@gmail.com").await;
+    app.post_email_change(&serde_json::json!({
+        "token": token,
+        "new_email": "ursula@example.com",
+    }))
+    .await;
+    let requests = app.email_server.received_requests().await.unwrap();
+    let verification_link = app.get_confirmation_links(&requests[1]).plain_text;
+    // Links are valid for two days.
+    sqlx::query!("UPDATE email_change_requests SET created_at = now() - interval '3 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(verification_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@gmail.com");
+}
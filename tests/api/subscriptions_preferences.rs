@@ -0,0 +1,272 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// Subscribes, then returns the preferences link from the confirmation
+/// email.
+async fn subscribe(app: &TestApp) -> reqwest::Url {
+    Mock::given(path("/api/1.0/messages/send"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let text = body["message"]["text"].as_str().unwrap();
+    // The preferences link comes after the confirmation link.
+    let start = text.rfind("http").unwrap();
+    let mut link = reqwest::Url::parse(text[start..].trim()).unwrap();
+    assert_eq!(link.path(), "/subscriptions/preferences");
+    link.set_port(Some(app.port)).unwrap();
+    link
+}
+
+fn token(link: &reqwest::Url) -> String {
+    link.query_pairs()
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+async fn get_preferences_json(link: &reqwest::Url) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(link.clone())
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn post_preferences_json(app: &TestApp, body: &serde_json::Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions/preferences", app.host))
+        .json(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn add_list(app: &TestApp, name: &str) {
+    sqlx::query!(
+        r#"
+        INSERT INTO mailing_lists (name, description, subscribe_by_default)
+        VALUES ($1, 'Another list.', false)
+        "#,
+        name
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn the_preferences_link_in_the_confirmation_email_shows_the_preferences() {
+    let app = spawn_app().await;
+    let link = subscribe(&app).await;
+    add_list(&app, "events").await;
+
+    let response = get_preferences_json(&link).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "email": "ursula_le_guin@gmail.com",
+            "name": "le guin",
+            "frequency": "immediate",
+            "lists": [
+                { "name": "events", "description": "Another list.", "subscribed": false },
+                { "name": "newsletter", "description": "Our regular newsletter.", "subscribed": true },
+            ],
+        })
+    );
+}
+
+#[tokio::test]
+async fn browsers_get_an_html_page() {
+    let app = spawn_app().await;
+    let link = subscribe(&app).await;
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"name="list:newsletter" checked"#));
+    assert!(page.contains(r#"value="le guin""#));
+}
+
+#[tokio::test]
+async fn preferences_are_updated_and_recorded_in_the_history() {
+    let app = spawn_app().await;
+    let link = subscribe(&app).await;
+    add_list(&app, "events").await;
+
+    let response = post_preferences_json(
+        &app,
+        &serde_json::json!({
+            "token": token(&link),
+            "name": "Ursula K. Le Guin",
+            "frequency": "weekly",
+            "lists": ["events"],
+        }),
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["name"], "Ursula K. Le Guin");
+    assert_eq!(body["frequency"], "weekly");
+    assert_eq!(body["lists"][0]["subscribed"], true);
+    assert_eq!(body["lists"][1]["subscribed"], false);
+    let event =
+        sqlx::query!("SELECT details FROM subscription_events WHERE kind = 'preferences_updated'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        event.details,
+        serde_json::json!({
+            "name": { "from": "le guin", "to": "Ursula K. Le Guin" },
+            "frequency": { "from": "immediate", "to": "weekly" },
+            "joined": ["events"],
+            "left": ["newsletter"],
+        })
+    );
+}
+
+#[tokio::test]
+async fn the_html_form_can_leave_every_list() {
+    let app = spawn_app().await;
+    let link = subscribe(&app).await;
+
+    // Unticked checkboxes are not submitted at all.
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/preferences", app.host))
+        .form(&[
+            ("token", token(&link).as_str()),
+            ("name", "le guin"),
+            ("frequency", "monthly"),
+        ])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Your preferences have been saved."));
+    let body: serde_json::Value = get_preferences_json(&link).await.json().await.unwrap();
+    assert_eq!(body["frequency"], "monthly");
+    assert_eq!(body["lists"][0]["subscribed"], false);
+}
+
+#[tokio::test]
+async fn unchanged_preferences_are_not_recorded() {
+    let app = spawn_app().await;
+    let link = subscribe(&app).await;
+
+    let response = post_preferences_json(
+        &app,
+        &serde_json::json!({
+            "token": token(&link),
+            "name": "le guin",
+            "frequency": "immediate",
+            "lists": ["newsletter"],
+        }),
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let events = sqlx::query!(
+        "SELECT count(*) AS \"count!\" FROM subscription_events WHERE kind = 'preferences_updated'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(events.count, 0);
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected_with_every_error() {
+    let app = spawn_app().await;
+    let link = subscribe(&app).await;
+
+    let response = post_preferences_json(
+        &app,
+        &serde_json::json!({
+            "token": token(&link),
+            "name": "",
+            "frequency": "daily",
+            "lists": ["newsletter", "no-such-list"],
+        }),
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let reasons: Vec<_> = body["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| (f["field"].as_str().unwrap(), f["reason"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            ("name", "empty"),
+            ("frequency", "invalid_value"),
+            ("lists", "unknown_list"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn preferences_with_an_invalid_link_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+    let link = subscribe(&app).await;
+    let mut forged = link.clone();
+    forged.set_query(Some("token=garbage"));
+
+    let response = get_preferences_json(&forged).await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = post_preferences_json(
+        &app,
+        &serde_json::json!({
+            "token": "garbage",
+            "name": "le guin",
+            "frequency": "weekly",
+        }),
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn subscribing_and_confirming_are_recorded_in_the_history() {
+    let app = spawn_app().await;
+    subscribe(&app).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request).html;
+
+    reqwest::get(confirmation_link).await.unwrap();
+
+    let kinds: Vec<String> = sqlx::query!("SELECT kind FROM subscription_events ORDER BY id")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.kind)
+        .collect();
+    assert_eq!(kinds, vec!["subscribed", "confirmed"]);
+}